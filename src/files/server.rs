@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::IpAddr, path::Path, time::Duration};
+
+/// The definition for the top-level server configuration file.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+#[non_exhaustive]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ServerConfig {
+	/// Whether the server is in maintenance mode.
+	/// While it is, every request is answered with a 503 without touching the filesystem.
+	pub maintenance: bool,
+	/// Whether requests for a non-canonical path (like `/a//b/../c`) are redirected to the canonical one.
+	/// Otherwise they are quietly served as if the canonical path had been requested.
+	pub redirect_to_canonical: bool,
+	/// The status to redirect to the canonical path with, defaulting to 301. Directories' `redirect_trailing_slash` uses it too.
+	/// 301 and 302 let clients retry a `POST` as a `GET`, while 307 and 308 make them repeat the same method and body.
+	/// 301 and 308 are permanent, so clients may cache them, while 302 and 307 are temporary.
+	pub canonical_redirect_status: Option<u16>,
+	/// Whether requests through a symlink to a directory (like `latest` linking to `v2`) are redirected to the linked path.
+	/// Symlinks are otherwise followed silently. Links leading outside the root they're in are always followed silently,
+	/// since there's no path to redirect to. The redirect uses `canonical_redirect_status`.
+	pub redirect_symlinked_dirs: bool,
+	/// A hashmap from URL path prefixes (like `"/static/"`) to the directories they are served from, if any.
+	/// Relative directories are relative to the workdir.
+	/// Aliased requests resolve entirely within their directory, so handlers in the workdir don't apply to them.
+	pub aliases: Option<HashMap<String, String>>,
+	/// Whether responses carry a `Server-Timing` header breaking down where the server spent its time.
+	/// This exposes how long gatekeepers and CGIs take, so it's meant for debugging.
+	pub server_timing: bool,
+	/// A hashmap from file extensions (like `"png"` or `"tar.gz"`) to the `Cache-Control` sent with static files that have them, if any.
+	/// When several match, the longest extension wins. CGI binaries set their own.
+	pub cache_control: Option<HashMap<String, String>>,
+	/// The status for a CGI binary killed by a signal (like a crash, or running out of memory), defaulting to 500.
+	pub signal_status: Option<u16>,
+	/// A hashmap from statuses (like `"404"`) to the files used as the body of error responses with them, if any.
+	/// Relative files are relative to the workdir. They only replace wwebs' own bodies, so a CGI's error page is kept.
+	/// Gemini sends failures without a body, so they don't apply there. A directory's own `errors` take precedence.
+	pub error_pages: Option<HashMap<String, String>>,
+	/// The body sent when a response can't be sent as-is, like when a CGI set an invalid header.
+	/// Defaults to `Whoopsie`.
+	pub fallback_body: Option<String>,
+	/// How long, in seconds, running CGI binaries get to finish when the server shuts down, defaulting to 30.
+	/// Any still running afterwards are killed.
+	#[serde(default, with = "super::wwebs::seconds")]
+	pub shutdown_grace: Option<Duration>,
+	/// The addresses of reverse proxies in front of wwebs, whose `X-Forwarded-For` headers are believed, if any.
+	/// Requests from anywhere else have the header ignored when working out the client's address.
+	pub trusted_proxies: Option<Vec<IpAddr>>,
+	/// Whether requests through a directory whose `.wwebs.toml` can't be read or parsed are answered with a 500.
+	/// Otherwise the file is left out, as if it weren't there. Either way, the error is logged.
+	pub strict_config: bool,
+	/// Global limits on what requests may cost the server.
+	pub limits: ServerLimits,
+}
+
+/// Global limits, as opposed to anything configured per directory.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct ServerLimits {
+	/// The most headers a request may have before it's refused with a 431.
+	pub max_headers: usize,
+	/// The most CGI binaries (including handlers) that may run at once, if limited.
+	/// Requests that would need another are answered with a 503.
+	pub max_concurrent_cgi: Option<usize>,
+	/// The most bytes a CGI binary may write to stdout, if limited.
+	/// CGIs that write more are cut off, and the request is answered with a 500 (or a 502, from a `FastCGI` upstream).
+	pub max_response_size: Option<usize>,
+	/// The most connections that may be open at once, across every protocol that counts them, if limited.
+	/// Requests on connections past the limit are answered with a 503, and the connection closed.
+	pub max_connections: Option<usize>,
+	/// The most bytes of request and response bodies that may be held in memory at once, across all requests, if limited.
+	/// Request bodies are reserved for before they're read, going by their length, and as they arrive without one.
+	/// Requests whose body would take the total past the limit are answered with a 503 as soon as it would.
+	/// The output of dynamic content is reserved for as it's read, and answered with a 503 the same way,
+	/// and responses stay reserved for until they're sent.
+	pub max_buffered_bytes: Option<usize>,
+	/// How deeply requests may nest, if limited, going by the `WWebS-Depth` header.
+	/// CGIs get `WWEBS_DEPTH` and should forward it in that header when they make requests back to the server.
+	/// Requests nested deeper than this are answered with a 508, breaking loops like a CGI requesting itself.
+	pub max_depth: Option<usize>,
+	/// The longest URL a request may have, in bytes, before it's refused with a 414. Defaults to 8192.
+	pub max_url_length: usize,
+	/// The most segments a request's path may have, before it's refused with a 414. Defaults to 128.
+	/// Each segment is a directory to look into, so this keeps absurdly deep paths from costing much.
+	pub max_path_segments: usize,
+}
+
+impl Default for ServerLimits {
+	fn default() -> Self {
+		Self {
+			max_headers: 100,
+			max_concurrent_cgi: None,
+			max_response_size: None,
+			max_connections: None,
+			max_buffered_bytes: None,
+			max_depth: None,
+			max_url_length: 8192,
+			max_path_segments: 128,
+		}
+	}
+}
+
+impl ServerConfig {
+	/// Reads and parses a server configuration file.
+	/// # Errors
+	/// Errors if the file can't be read or isn't valid TOML.
+	pub fn load(path: &Path) -> anyhow::Result<ServerConfig> {
+		let config_string = std::fs::read_to_string(path)?;
+		Ok(toml::from_str(&config_string)?)
+	}
+}
@@ -1,16 +1,140 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 /// The definition for the wwebs.toml file.
-#[derive(Serialize, Deserialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
 #[non_exhaustive]
 pub struct WWebS {
 	/// The file resolution configuration, if any.
 	pub resolution: Option<ResolutionInfo>,
 	/// A hashmap of extra environment variables to set, if any.
 	pub env: Option<HashMap<String, String>>,
+	/// Keys of `env` that only the target gets, withheld from gatekeepers, transformers and loggers, if any.
+	/// Use this to keep secrets away from handlers that don't need them. Subdirectories add to the list rather than replacing it.
+	pub target_only_env: Option<Vec<String>>,
+	/// A hashmap from URL path patterns to `Link` header values, if any.
+	/// Static HTML responses whose path matches a pattern get its values as preload hints.
+	/// Patterns may use `*` to match any run of characters, e.g. `"/blog/*.html"`.
+	pub preload: Option<HashMap<String, Vec<String>>>,
+	/// Whether identical concurrent GET and HEAD requests for CGI share a single run, if set.
+	/// Requests with different cookies or `Authorization` never share one, but only enable this for endpoints whose output
+	/// doesn't depend on other headers.
+	pub single_flight: Option<bool>,
+	/// Whether CGI binaries here have their output streamed to the client as it's written, if set.
+	/// Streaming binaries must finish their output commands with a blank line on stderr before writing their body,
+	/// and their exit status isn't used as the response's. Output that's shared or transcoded is never streamed.
+	pub streaming: Option<bool>,
+	/// How long, in seconds, a CGI binary gets to read its request body, if limited.
+	/// Once it runs out, its stdin is closed and it keeps running with whatever it read.
+	#[serde(default, with = "seconds")]
+	pub stdin_timeout: Option<Duration>,
+	/// How long, in seconds, a CGI binary may run before it's killed and the request answered with a 504, if limited.
+	#[serde(default, with = "seconds")]
+	pub timeout: Option<Duration>,
+	/// The charset that CGI binaries here emit text in, if it isn't UTF-8 (e.g. `"latin1"`).
+	/// Their `text/*` responses are converted to UTF-8, and their `Content-Type` updated to match.
+	pub transcode_from: Option<String>,
+	/// Rules that serve a different index to, or redirect, particular user agents (like crawlers), if any.
+	/// They only apply when this directory itself is requested, and the first matching rule wins.
+	pub user_agent_rules: Option<Vec<UserAgentRule>>,
+	/// The fingerprints of the client certificates allowed in, if limited.
+	/// Fingerprints are the base64 of the certificate's SHA-512 digest, as passed to CGI in `HEADER_USERCERT`.
+	/// Requests without a certificate get a 401 (Gemini's 60), and ones with any other get a 403 (Gemini's 61).
+	pub allowed_client_certs: Option<Vec<String>>,
+	/// A hashmap from file extensions (like `"php"`) to a fixed response for any request for such a file, if any.
+	/// These are answered without looking for the file, so they suit migrations, like answering old `.php` links with a 410.
+	pub extension_status: Option<HashMap<String, ExtensionStatus>>,
+	/// Whether wwebs' own error responses here are sent as `application/problem+json` (RFC 7807), if set.
+	/// Clients whose `Accept` header prefers HTML still get the usual body, and a CGI's error body is never replaced.
+	pub problem_json: Option<bool>,
+	/// A hashmap from file extensions (like `"gmi"`) to the `Content-Type` of static files that have them, if any.
+	/// These take precedence over the types wwebs guesses, and the longest matching extension wins.
+	pub mime: Option<HashMap<String, String>>,
+	/// Whether static files here are served as downloads, with a `Content-Disposition: attachment` header, if set.
+	pub download: Option<bool>,
+	/// File extensions (like `"zip"`) that are served as downloads even when `download` isn't set, if any.
+	pub download_extensions: Option<Vec<String>>,
+	/// Caching of the responses of targets here, if enabled.
+	pub cache: Option<CacheInfo>,
+	/// Compression of responses from here for HTTP clients that accept it, if configured.
+	pub compression: Option<CompressionInfo>,
+	/// The address of a `FastCGI` upstream that runs the dynamic content here (including handlers), if any.
+	/// Either `unix:` followed by a socket path, or a `host:port`. Content still has to be executable to count as dynamic,
+	/// but it's handed to the upstream instead of being spawned. Output from `FastCGI` is never streamed.
+	pub fastcgi: Option<String>,
+	/// Whether CGI binaries here handle WebSocket connections, if set.
+	/// Upgrade requests for them start the binary and relay the connection over its stdin and stdout, a line per message,
+	/// while other requests run it as usual. Response transformers don't apply to WebSocket connections, and `timeout` limits how long one stays open.
+	pub websocket: Option<bool>,
+	/// A file to append a line to for every request answered from here, in the Combined Log Format, if any.
+	/// Relative paths are relative to the directory wwebs was started in, not this one.
+	pub access_log: Option<PathBuf>,
+	/// A hashmap from statuses (like `"404"`) to pages sent as the body of wwebs' own error responses with that status, if any.
+	/// Pages are relative to the directory whose `.wwebs.toml` names them, and apply to its subdirectories too, which can replace them.
+	/// Like the server's `error_pages` (which are used when no directory has one), a CGI's error body is kept.
+	pub errors: Option<HashMap<String, PathBuf>>,
+	/// The methods (like `"GET"` and `"POST"`) requests answered here, by a file, a listing or the upstream, may use, if limited.
+	/// Other methods get a 405 with an `Allow` header, and `OPTIONS` is answered with the `Allow` header instead of reaching the file.
+	/// `HEAD` is allowed along with `GET`.
+	pub methods: Option<Vec<String>>,
+	/// A hashmap from file extensions (like `"py"`) to the interpreter (like `"/usr/bin/python3"`) files with them run through, if any.
+	/// Such files are dynamic even without the `o+x` bit, and run as the interpreter with the file's path as its first argument.
+	/// Files that are executable themselves still run on their own.
+	pub interpreters: Option<HashMap<String, String>>,
+	/// The URL of an upstream HTTP server (like `"http://127.0.0.1:9000/api"`) that requests here are forwarded to, if any.
+	/// Files here aren't looked for: the rest of the request's path after this directory is added to the upstream's, and the upstream's answer is relayed.
+	/// Only `http://` upstreams are supported, and `timeout` limits how long one gets to answer.
+	pub proxy_pass: Option<String>,
 }
 
+impl WWebS {
+	/// The configuration handlers run with, which leaves out the target's own variables.
+	pub(crate) fn for_handlers(&self) -> WWebS {
+		let mut config = self.clone();
+		if let (Some(env), Some(target_only)) = (&mut config.env, &self.target_only_env) {
+			env.retain(|key, _| !target_only.contains(key));
+		}
+		config
+	}
+}
+
+/// A fixed response for requests with a particular extension.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct ExtensionStatus {
+	/// The status to answer with.
+	pub status: u16,
+	/// The body to answer with, if any.
+	pub body: Option<String>,
+}
+
+/// Merges a directory's configuration (`rhs`) over its parent's (`self`), with one rule for every field: the child wins.
+/// Settings the child sets replace the parent's, maps (like `env`) are merged key by key with the child's values
+/// replacing the parent's, and lists that restrict (like `target_only_env`) add up. Anything the child leaves out is inherited.
+///
+/// ```
+/// use std::time::Duration;
+/// use wwebs::files::wwebs::WWebS;
+///
+/// let parent: WWebS = toml::from_str(r#"
+///     timeout = 5
+///     target_only_env = ["API_KEY"]
+///     env = { API_KEY = "secret", SITE = "parent" }
+/// "#).unwrap();
+/// let child: WWebS = toml::from_str(r#"
+///     target_only_env = ["DB_PASSWORD"]
+///     env = { SITE = "child" }
+/// "#).unwrap();
+/// let merged = parent.clone() & child.clone();
+/// assert_eq!(merged.timeout, Some(Duration::from_secs(5)));
+/// let env = merged.env.unwrap();
+/// assert_eq!(env["API_KEY"], "secret");
+/// assert_eq!(env["SITE"], "child");
+/// assert_eq!(merged.target_only_env.unwrap(), ["API_KEY", "DB_PASSWORD"]);
+///
+/// let quick: WWebS = toml::from_str("timeout = 0.5").unwrap();
+/// assert_eq!((parent & quick).timeout, Some(Duration::from_millis(500)));
+/// ```
 impl std::ops::BitAnd for WWebS {
 	type Output = WWebS;
 
@@ -23,19 +147,102 @@ impl std::ops::BitAnd for WWebS {
 			},
 			env: match (self.env, rhs.env) {
 				(Some(v), None) | (None, Some(v)) => Some(v),
-				(Some(a), Some(b)) => Some(a.into_iter().chain(b.into_iter()).collect()),
+				// Collecting keeps the last value for a key, which is the child's.
+				(Some(a), Some(b)) => Some(a.into_iter().chain(b).collect()),
+				(None, None) => None,
+			},
+			target_only_env: match (self.target_only_env, rhs.target_only_env) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a.into_iter().chain(b).collect()),
+				(None, None) => None,
+			},
+			preload: match (self.preload, rhs.preload) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a.into_iter().chain(b).collect()),
+				(None, None) => None,
+			},
+			single_flight: rhs.single_flight.or(self.single_flight),
+			streaming: rhs.streaming.or(self.streaming),
+			stdin_timeout: rhs.stdin_timeout.or(self.stdin_timeout),
+			timeout: rhs.timeout.or(self.timeout),
+			transcode_from: rhs.transcode_from.or(self.transcode_from),
+			user_agent_rules: rhs.user_agent_rules.or(self.user_agent_rules),
+			allowed_client_certs: rhs.allowed_client_certs.or(self.allowed_client_certs),
+			extension_status: match (self.extension_status, rhs.extension_status) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a.into_iter().chain(b).collect()),
+				(None, None) => None,
+			},
+			problem_json: rhs.problem_json.or(self.problem_json),
+			mime: match (self.mime, rhs.mime) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a.into_iter().chain(b).collect()),
 				(None, None) => None,
 			},
+			download: rhs.download.or(self.download),
+			download_extensions: rhs.download_extensions.or(self.download_extensions),
+			cache: match (self.cache, rhs.cache) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a & b),
+				(None, None) => None,
+			},
+			compression: match (self.compression, rhs.compression) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a & b),
+				(None, None) => None,
+			},
+			fastcgi: rhs.fastcgi.or(self.fastcgi),
+			websocket: rhs.websocket.or(self.websocket),
+			access_log: rhs.access_log.or(self.access_log),
+			errors: match (self.errors, rhs.errors) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a.into_iter().chain(b).collect()),
+				(None, None) => None,
+			},
+			methods: rhs.methods.or(self.methods),
+			interpreters: match (self.interpreters, rhs.interpreters) {
+				(Some(v), None) | (None, Some(v)) => Some(v),
+				(Some(a), Some(b)) => Some(a.into_iter().chain(b).collect()),
+				(None, None) => None,
+			},
+			proxy_pass: rhs.proxy_pass.or(self.proxy_pass),
 		}
 	}
 }
 
+/// Alternate handling for requests from matching user agents.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct UserAgentRule {
+	/// A regex matched against the `User-Agent` header.
+	pub pattern: String,
+	/// The index file to serve instead of the usual one, if any.
+	pub index: Option<String>,
+	/// Where to redirect matching user agents to, if anywhere. This takes precedence over `index`.
+	pub redirect: Option<String>,
+	/// The status to redirect with, defaulting to 302.
+	/// See `ServerConfig::canonical_redirect_status` for how the redirect statuses differ.
+	pub redirect_status: Option<u16>,
+}
+
 /// Configuration for path resolution.
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[non_exhaustive]
 pub struct ResolutionInfo {
-	/// Sets the name of the "index" file.
-	pub index: Option<String>,
+	/// The index file of directories, or candidates for it, if set. See `Index`.
+	pub index: Option<Index>,
+	/// Whether the default index depends on the protocol, if set.
+	/// When it does and no `index` is set, Gemini requests get `index.gmi` while everything else gets `index.html`.
+	pub protocol_index: Option<bool>,
+	/// Whether directories without an index file get a generated listing of their files instead of a 404, if set.
+	/// Listings are HTML, or gemtext over Gemini, and leave out dotfiles and anything that isn't readable by others.
+	pub autoindex: Option<bool>,
+	/// Whether requests for directories without a trailing slash (like `/blog`) are redirected to it (`/blog/`), if set.
+	/// The redirect uses the server's `canonical_redirect_status`, a 301 by default.
+	/// Otherwise the index is served in place, where the page's relative links resolve against the parent directory.
+	pub redirect_trailing_slash: Option<bool>,
+	/// What answers a name here that's both a file and the directory of an alias, if set. See `Prefer`.
+	pub prefer: Option<Prefer>,
 }
 
 impl std::ops::BitAnd for ResolutionInfo {
@@ -43,10 +250,163 @@ impl std::ops::BitAnd for ResolutionInfo {
 
 	fn bitand(self, rhs: Self) -> Self::Output {
 		Self {
-			index: match (self.index, rhs.index) {
-				(None, None) => None,
-				(_, Some(v)) | (Some(v), None) => Some(v),
-			},
+			index: rhs.index.or(self.index),
+			protocol_index: rhs.protocol_index.or(self.protocol_index),
+			autoindex: rhs.autoindex.or(self.autoindex),
+			redirect_trailing_slash: rhs.redirect_trailing_slash.or(self.redirect_trailing_slash),
+			prefer: rhs.prefer.or(self.prefer),
+		}
+	}
+}
+
+/// Which of a file and a directory answers a name that's both, like `docs` when the workdir has a file by that name
+/// and the server aliases `/docs/` to a directory. Within a single directory, a name is only ever one or the other.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Prefer {
+	/// The file answers requests for the name itself, like `/docs`, while `/docs/` and anything under it still go to the alias.
+	File,
+	/// The alias' directory answers, which is the default.
+	Directory,
+}
+
+/// The index file of a directory: a single name, names tried in order, or either of those by protocol.
+/// The first name that exists and is readable by others is the index.
+///
+/// ```
+/// use wwebs::files::wwebs::WWebS;
+///
+/// let config: WWebS = toml::from_str(r#"
+///     [resolution]
+///     index = ["index.html", "index.htm"]
+/// "#).unwrap();
+/// let index = config.resolution.unwrap().index.unwrap();
+/// assert_eq!(index.candidates("Http"), ["index.html", "index.htm"]);
+///
+/// let config: WWebS = toml::from_str(r#"
+///     [resolution.index]
+///     Gemini = ["index.gmi", "index.html"]
+///     default = "index.html"
+/// "#).unwrap();
+/// let index = config.resolution.unwrap().index.unwrap();
+/// assert_eq!(index.candidates("Gemini"), ["index.gmi", "index.html"]);
+/// assert_eq!(index.candidates("Http"), ["index.html"]);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Index {
+	/// A single name, like `index = "home.html"`.
+	Name(String),
+	/// Names tried in order, like `index = ["index.html", "index.htm"]`.
+	Candidates(Vec<String>),
+	/// A name or names by protocol (like `Gemini` or `Http`), with `default` for protocols that aren't listed.
+	ByProtocol(HashMap<String, Index>),
+}
+
+impl Index {
+	/// The names to try for a request over `proto`, in order.
+	/// Empty if the index is by protocol, and neither `proto` nor `default` is listed.
+	#[must_use]
+	pub fn candidates(&self, proto: &str) -> Vec<String> {
+		match self {
+			Index::Name(name) => vec![name.clone()],
+			Index::Candidates(names) => names.clone(),
+			Index::ByProtocol(by_protocol) => by_protocol
+				.iter()
+				.find(|(name, _)| name.eq_ignore_ascii_case(proto))
+				.or_else(|| by_protocol.get_key_value("default"))
+				.map(|(_, index)| index.candidates(proto))
+				.unwrap_or_default(),
+		}
+	}
+}
+
+/// Configuration for caching responses.
+/// Only the target's response is reused, so gatekeepers and transformers still run for every request.
+/// Only `GET` and `HEAD` requests are cached. `Range` requests are answered from a cached whole response, but never cached themselves.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct CacheInfo {
+	/// How long, in seconds, static files are cached for, if they are.
+	/// CGI binaries are only cached when they ask to be, with the `cache` output command.
+	#[serde(default, with = "seconds")]
+	pub ttl: Option<Duration>,
+	/// The most responses the cache may hold, defaulting to 1000.
+	/// Caching a response evicts the least recently used ones until there's room for it.
+	pub max_entries: Option<usize>,
+	/// Request headers (like `"Accept-Language"`) whose values are cached separately, if any.
+	/// Responses are otherwise shared by every request for the same URL with the same cookies and `Authorization`.
+	pub vary: Option<Vec<String>>,
+}
+
+impl std::ops::BitAnd for CacheInfo {
+	type Output = CacheInfo;
+
+	fn bitand(self, rhs: Self) -> Self::Output {
+		Self {
+			ttl: rhs.ttl.or(self.ttl),
+			max_entries: rhs.max_entries.or(self.max_entries),
+			vary: rhs.vary.or(self.vary),
 		}
 	}
 }
+
+/// Configuration for compressing responses, with gzip or deflate depending on the client's `Accept-Encoding`.
+/// Streamed bodies, and responses that already have a `Content-Encoding`, are sent as they are.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[non_exhaustive]
+pub struct CompressionInfo {
+	/// Whether responses are compressed, if set.
+	pub enabled: Option<bool>,
+	/// Prefixes of the `Content-Type`s that are compressed, defaulting to text and the common text-based formats.
+	/// Types that are already compressed, like images and archives, gain nothing and shouldn't be listed.
+	pub types: Option<Vec<String>>,
+	/// The smallest body, in bytes, that's compressed, defaulting to 1024.
+	pub min_size: Option<usize>,
+}
+
+impl CompressionInfo {
+	/// The `Content-Type` prefixes compressed when `types` isn't set.
+	pub const DEFAULT_TYPES: &'static [&'static str] = &[
+		"text/",
+		"application/json",
+		"application/javascript",
+		"application/xml",
+		"image/svg+xml",
+	];
+}
+
+impl std::ops::BitAnd for CompressionInfo {
+	type Output = CompressionInfo;
+
+	fn bitand(self, rhs: Self) -> Self::Output {
+		Self {
+			enabled: rhs.enabled.or(self.enabled),
+			types: rhs.types.or(self.types),
+			min_size: rhs.min_size.or(self.min_size),
+		}
+	}
+}
+
+/// (De)serializes an optional duration as a number of seconds, like `timeout = 2.5`.
+pub(crate) mod seconds {
+	use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+	use std::time::Duration;
+
+	// serde's `with` always hands over a reference to the field itself.
+	#[allow(clippy::ref_option)]
+	pub fn serialize<S: Serializer>(
+		duration: &Option<Duration>,
+		serializer: S,
+	) -> Result<S::Ok, S::Error> {
+		duration.map(|d| d.as_secs_f64()).serialize(serializer)
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(
+		deserializer: D,
+	) -> Result<Option<Duration>, D::Error> {
+		Option::<f64>::deserialize(deserializer)?
+			.map(|secs| Duration::try_from_secs_f64(secs).map_err(D::Error::custom))
+			.transpose()
+	}
+}
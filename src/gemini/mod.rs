@@ -1,20 +1,15 @@
 //! This module implements Gemini protocol support for wwebs.
 
-use std::{collections::HashMap, io::Read};
+use std::{collections::HashMap, net::SocketAddr, os::unix::fs::PermissionsExt, path::Path};
 
 use crate::{
 	files::wwebs::WWebS,
 	server::Server,
-	structures::{Request, Response},
+	structures::{Cancellation, Request, Response, TlsInfo},
 	traits::Protocol,
 };
 use async_trait::async_trait;
 use openssl::hash::MessageDigest;
-use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt},
-	net::TcpListener,
-};
-use tokio_native_tls::{native_tls::Identity, TlsAcceptor};
 use url::Url;
 use windmark::{context::RouteContext, response::Response as WMResponse};
 
@@ -30,6 +25,7 @@ impl Protocol for Gemini {
 	type Config = GConfig;
 
 	async fn run(self, config: Self::Config, server: Server) -> anyhow::Result<()> {
+		check_private_key(Path::new(&config.private), server.workdir())?;
 		let handler = {
 			let server = server.clone();
 			move |ctx: RouteContext| {
@@ -39,62 +35,99 @@ impl Protocol for Gemini {
 					url,
 					user_cert: ctx
 						.certificate
+						.as_ref()
 						.and_then(|cert| cert.digest(MessageDigest::sha512()).ok())
 						.map(base64::encode),
+					verb: None,
+					body: None,
+					remote_addr: ctx.tcp.peer_addr().ok(),
+					local_addr: ctx.tcp.local_addr().ok(),
 				};
 				let mut req: Request = req.into();
-				let response = server.exec(&mut req, 0, &mut WWebS::default());
-
-				let response = GResponse {
-					body: response.body.clone(),
-					status: match response.status {
-						200 => 20,
-						500 => 50,
-						404 => 40,
-						v => v.try_into().unwrap_or(50),
-					},
-					meta: response
-						.headers
-						.get("X-GeminiMeta")
-						.cloned()
-						.unwrap_or_else(|| "text/gemini".to_owned()),
-				};
-				let meta = response.meta;
-				let status = response.status;
-				let mut response = WMResponse::new(response.status, unsafe {
-					String::from_utf8_unchecked(response.body)
+				// windmark doesn't say what it negotiated, only what certificate the client sent.
+				let mut tls = TlsInfo::default();
+				if let Some(cert) = &ctx.certificate {
+					tls.set_client_cert(cert);
+				}
+				req.tls = Some(tls);
+				let response: GResponse = server.exec(&mut req, 0, &mut WWebS::default()).into();
+
+				if response.status != 20 {
+					return WMResponse::new(response.status, response.meta);
+				}
+				let mut params = response.meta.split(';').map(str::trim);
+				let mime = params.next().unwrap_or_default().to_string();
+				// Only text is guaranteed to be UTF-8, anything else goes through windmark's binary path.
+				if !mime.to_ascii_lowercase().starts_with("text/") {
+					return WMResponse::binary_success(response.body, response.meta);
+				}
+				let body = String::from_utf8(response.body).unwrap_or_else(|e| {
+					tracing::error!(
+						"Replacing invalid UTF-8 in the {mime} response for {}",
+						ctx.url.path()
+					);
+					String::from_utf8_lossy(e.as_bytes()).to_string()
 				});
-				if status == 20 {
-					response.with_mime(meta);
+				let mut wm_response = WMResponse::new(response.status, body);
+				// windmark writes the charset and language itself, so they're handed over separately.
+				let mut rest = vec![mime.as_str()];
+				for param in params {
+					match param.split_once('=') {
+						Some(("charset", charset)) => {
+							wm_response.with_character_set(charset);
+						}
+						Some(("lang", lang)) => {
+							wm_response.with_language(lang);
+						}
+						_ => rest.push(param),
+					}
 				}
-				response
+				wm_response.with_mime(rest.join("; "));
+				wm_response
 			}
 		};
-		windmark::router::Router::new()
+		// windmark binds as soon as it starts running, so this is as close as we can get.
+		server.listener_bound();
+		let mut router = windmark::router::Router::new();
+		router
 			.set_private_key_file(config.private)
 			.set_certificate_file(config.public)
 			.mount("/*path", handler.clone())
 			.mount("/", handler)
-			.set_error_handler(|_error| WMResponse::temporary_failure("Whoopsie"))
-			.run()
-			.await
-			.expect("Gemini run failed");
+			.set_error_handler(|_error| WMResponse::temporary_failure("Whoopsie"));
+		// windmark can't be asked to stop, but dropping it stops the listener while its connections carry on.
+		tokio::select! {
+			ran = router.run() => ran.expect("Gemini run failed"),
+			() = server.shutting_down() => {}
+		}
 		Ok(())
 	}
 }
 
 /// The Gemini request structure.
+///
+/// Input arrives as the URL's query, which content finds decoded in `QUERY_INPUT`.
+/// Content asks for it by answering with `status 600` (or `601` for sensitive input) and the prompt as its body.
 pub struct GRequest {
 	/// The URL of the request.
 	pub url: Url,
 	/// The user's certificate fingerprint, if they provided one.
 	pub user_cert: Option<String>,
+	/// The verb of the request, for Gemini-family protocols that do more than fetch.
+	/// Defaults to `GET`.
+	pub verb: Option<String>,
+	/// The body of the request, for Gemini-family protocols that can upload (like Titan).
+	pub body: Option<Vec<u8>>,
+	/// The address of the client's end of the connection, if known.
+	pub remote_addr: Option<SocketAddr>,
+	/// The address of the server's end of the connection, if known.
+	pub local_addr: Option<SocketAddr>,
 }
 
 /// The Gemini response structure.
 pub struct GResponse {
 	/// The status code for the Gemini response.
-	/// These status codes don't directly map to HTTP status codes, you should read the source code implementing `From<Response> for GResponse` to understand exactly how they're converted.
+	/// These status codes don't directly map to HTTP status codes, see `GResponse::status_for` for exactly how they're converted.
 	pub status: i32,
 	/// The metadata for the response.
 	/// This is usually a MIME type.
@@ -124,7 +157,7 @@ impl From<GRequest> for Request {
 	fn from(req: GRequest) -> Self {
 		Request {
 			proto: "Gemini",
-			verb: "GET".to_string(),
+			verb: req.verb.unwrap_or_else(|| "GET".to_string()),
 			url: {
 				if req.url.has_authority() {
 					req.url.clone()
@@ -145,26 +178,56 @@ impl From<GRequest> for Request {
 				}
 				h
 			},
-			body: vec![],
+			body: req.body.unwrap_or_default(),
+			cancellation: Cancellation::default(),
+			remote_addr: req.remote_addr,
+			local_addr: req.local_addr,
+			version: None,
+			tls: None,
 		}
 	}
 }
 
-impl From<Response> for GResponse {
-	fn from(mut res: Response) -> Self {
-		let status = match res.status {
+impl GResponse {
+	/// Maps a wwebs status (which is HTTP-like) to a Gemini status.
+	///
+	/// | wwebs status | Gemini status |
+	/// | --- | --- |
+	/// | 0 (unset) | 20 success |
+	/// | 1 to 61 | relayed as-is, since they're assumed to be intentional Gemini statuses |
+	/// | 200 to 299 | 20 success |
+	/// | 301, 308 | 31 permanent redirect |
+	/// | 302, 303, 307 and any other 3xx | 30 temporary redirect |
+	/// | 503 | 41 server unavailable |
+	/// | 500 | 42 CGI error |
+	/// | 502 | 43 proxy error |
+	/// | 429 | 44 slow down |
+	/// | any other 5xx | 40 temporary failure |
+	/// | 404 | 51 not found |
+	/// | 410 | 52 gone |
+	/// | 400, 414 | 59 bad request |
+	/// | 401 | 60 client certificate required |
+	/// | 403 | 61 certificate not authorized |
+	/// | 600 | 10 input |
+	/// | 601 | 11 sensitive input |
+	/// | anything else | 42 CGI error |
+	///
+	/// Successful responses use their `Content-Type` as the meta line, redirects their `Location`,
+	/// and everything else their body.
+	#[must_use]
+	pub fn status_for(status: u16) -> i32 {
+		match status {
 			0 => 20,
 
 			// It is assumed that valid gemini status codes are intentional and should be relayed
-			#[allow(clippy::cast_possible_truncation)]
-			n if n < 62 => n as u8,
+			n if n < 62 => n.into(),
 
 			// All "OK" responses become 20.
-			_ if res.is_ok() => 20,
+			200..=299 => 20,
 
 			// Map redirect responses...
-			301 => 31,
-			302 => 30,
+			301 | 308 => 31,
+			302 | 303 | 307 => 30,
 			// Catch-all for 300 responses, might cause issues
 			n if (300..400).contains(&n) => 30,
 
@@ -177,7 +240,7 @@ impl From<Response> for GResponse {
 
 			404 => 51,
 			410 => 52,
-			400 => 59,
+			400 | 414 => 59,
 
 			401 => 60,
 			403 => 61,
@@ -188,33 +251,195 @@ impl From<Response> for GResponse {
 			// Sensitive information is requested.
 			601 => 11,
 			_ => 42,
-		};
+		}
+	}
+}
+
+impl From<Response> for GResponse {
+	fn from(mut res: Response) -> Self {
+		// windmark only takes whole bodies.
+		if let Err(e) = res.buffer_stream() {
+			tracing::error!("Failed to read a streamed body: {e}");
+		}
+		let status = GResponse::status_for(res.status);
+		// Raw Gemini success statuses are successes too.
+		let success = (20..30).contains(&status);
 		GResponse {
-			status: status as i32,
-			meta: {
-				if res.is_ok() {
-					if let Some(gemini_meta) = res.headers.remove("GEMINI_META") {
-						gemini_meta
-					} else if let Some(mime) = res.headers.get("Content-Type") {
-						mime.to_string()
-					} else {
-						"application/octet-stream".to_string()
-					}
-				} else {
-					match status {
-						n if (30..40).contains(&n) => res
-							.headers
-							.get("Location")
-							.map_or_else(|| "/".to_string(), std::clone::Clone::clone),
-						n if (40..62).contains(&n) || (0..10).contains(&n) => {
-							String::from_utf8(res.body.clone())
-								.unwrap_or_else(|_| "???".to_string())
-						}
-						_ => "Body wasn't a string when it should have been".to_string(),
-					}
-				}
+			status,
+			meta: match status {
+				_ if success => success_meta(&res.headers),
+				30..=39 => res
+					.headers
+					.get("Location")
+					.map_or_else(|| "/".to_string(), std::clone::Clone::clone),
+				// Input prompts and failures explain themselves in the meta line.
+				_ => String::from_utf8(res.body.clone()).unwrap_or_else(|_| "???".to_string()),
 			},
-			body: if res.is_ok() { res.body } else { vec![] },
+			body: if success { res.body } else { vec![] },
+		}
+	}
+}
+
+/// Refuses a private key that anyone but its owner has access to, like OpenSSH does,
+/// and warns about one inside the directory being served, where a bad permission could leak it.
+fn check_private_key(path: &Path, workdir: &Path) -> anyhow::Result<()> {
+	let mode = std::fs::metadata(path)
+		.map_err(|e| anyhow::anyhow!("Can't read the Gemini private key {}: {e}", path.display()))?
+		.permissions()
+		.mode();
+	if mode & 0o077 != 0 {
+		anyhow::bail!(
+			"The Gemini private key {} is accessible by others (mode {:o}), run `chmod 600` on it",
+			path.display(),
+			mode & 0o777
+		);
+	}
+	if let (Ok(path), Ok(workdir)) = (path.canonicalize(), workdir.canonicalize()) {
+		if path.starts_with(&workdir) {
+			tracing::error!(
+				"The Gemini private key {} is inside the web directory, keep it somewhere else!!!",
+				path.display()
+			);
+		}
+	}
+	Ok(())
+}
+
+/// Builds the meta line of a successful response.
+/// An explicit `GEMINI_META` (or `X-GeminiMeta`) header is used as-is.
+/// Otherwise it's the `Content-Type`, defaulting to `text/gemini`, with a `lang` parameter from
+/// `Content-Language` for `text/gemini` bodies that don't already declare one.
+fn success_meta(headers: &HashMap<String, String>) -> String {
+	let header = |name: &str| {
+		headers
+			.iter()
+			.find(|(k, _)| k.eq_ignore_ascii_case(name))
+			.map(|(_, v)| v.trim().to_string())
+	};
+	if let Some(meta) = header("GEMINI_META").or_else(|| header("X-GeminiMeta")) {
+		return meta;
+	}
+	let mut meta = header("Content-Type").unwrap_or_else(|| "text/gemini".to_string());
+	let mut params = meta.split(';').map(str::trim);
+	let is_gemtext = params
+		.next()
+		.is_some_and(|mime| mime.eq_ignore_ascii_case("text/gemini"));
+	let has_lang = params.any(|param| param.starts_with("lang="));
+	if let (true, false, Some(lang)) = (is_gemtext, has_lang, header("Content-Language")) {
+		meta = format!("{meta}; lang={lang}");
+	}
+	meta
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{fs, os::unix::fs::PermissionsExt};
+
+	use super::{check_private_key, GResponse, Response};
+
+	#[test]
+	fn statuses_map_to_gemini() {
+		let table = [
+			(0, 20),
+			(1, 1),
+			(10, 10),
+			(59, 59),
+			(61, 61),
+			(62, 42),
+			(200, 20),
+			(204, 20),
+			(301, 31),
+			(308, 31),
+			(302, 30),
+			(303, 30),
+			(307, 30),
+			(399, 30),
+			(400, 59),
+			(401, 60),
+			(403, 61),
+			(404, 51),
+			(410, 52),
+			(414, 59),
+			(418, 42),
+			(429, 44),
+			(500, 42),
+			(502, 43),
+			(503, 41),
+			(504, 40),
+			(600, 10),
+			(601, 11),
+			(700, 42),
+		];
+		for (status, gemini) in table {
+			assert_eq!(GResponse::status_for(status), gemini, "for {status}");
+		}
+	}
+
+	#[test]
+	fn the_meta_follows_the_status() {
+		let convert = |status: u16, header: Option<(&str, &str)>, body: &str| {
+			let mut response = Response::default().with_status(status).with_body(body);
+			if let Some((name, value)) = header {
+				response.append_header(name, value);
+			}
+			let response = GResponse::from(response);
+			(response.status, response.meta, response.body)
+		};
+
+		assert_eq!(
+			convert(0, None, "# Hi"),
+			(20, "text/gemini".to_string(), b"# Hi".to_vec())
+		);
+		assert_eq!(
+			convert(200, Some(("Content-Type", "text/plain")), "hi"),
+			(20, "text/plain".to_string(), b"hi".to_vec())
+		);
+		assert_eq!(
+			convert(301, Some(("Location", "/moved")), "Moved"),
+			(31, "/moved".to_string(), vec![])
+		);
+		assert_eq!(convert(302, None, ""), (30, "/".to_string(), vec![]));
+		// Input prompts and failures explain themselves in the meta line, without a body.
+		assert_eq!(
+			convert(600, None, "Your name?"),
+			(10, "Your name?".to_string(), vec![])
+		);
+		assert_eq!(
+			convert(404, None, "Not found"),
+			(51, "Not found".to_string(), vec![])
+		);
+		assert_eq!(
+			convert(500, None, "Broke"),
+			(42, "Broke".to_string(), vec![])
+		);
+		assert_eq!(
+			convert(59, None, "Bad request"),
+			(59, "Bad request".to_string(), vec![])
+		);
+		// Raw Gemini successes are successes too.
+		assert_eq!(
+			convert(21, Some(("Content-Type", "text/plain")), "raw"),
+			(21, "text/plain".to_string(), b"raw".to_vec())
+		);
+	}
+
+	#[test]
+	fn private_keys_must_only_be_readable_by_their_owner() {
+		let dir = std::env::temp_dir().join(format!("wwebs-key-{}", std::process::id()));
+		let keys = dir.join("keys");
+		fs::create_dir_all(&keys).unwrap();
+		let key = keys.join("key.pem");
+		fs::write(&key, "not really a key").unwrap();
+		let workdir = dir.join("site");
+
+		for mode in [0o640, 0o604, 0o644] {
+			fs::set_permissions(&key, fs::Permissions::from_mode(mode)).unwrap();
+			let error = check_private_key(&key, &workdir).unwrap_err().to_string();
+			assert!(error.contains("chmod 600"), "{mode:o}: {error}");
 		}
+		fs::set_permissions(&key, fs::Permissions::from_mode(0o600)).unwrap();
+		assert!(check_private_key(&key, &workdir).is_ok());
+		assert!(check_private_key(&keys.join("missing.pem"), &workdir).is_err());
+		fs::remove_dir_all(&dir).unwrap();
 	}
 }
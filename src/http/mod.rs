@@ -1,18 +1,50 @@
-use std::{convert::Infallible, net::SocketAddr};
+use std::{
+	collections::HashMap,
+	convert::Infallible,
+	future::Future,
+	io::{BufRead, BufReader, Read, Write},
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	path::PathBuf,
+	pin::Pin,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	task::{Context, Poll},
+	time::Duration,
+};
 
 use crate::{
 	files::wwebs::WWebS,
-	server::Server as WWebSServer,
-	structures::{Request as WWebSRequest, Response as WWebSResponse},
+	server::{BodyReservation, Server as WWebSServer},
+	structures::{
+		Cancellation, Request as WWebSRequest, Response as WWebSResponse, TlsInfo, UpgradeSink,
+	},
 	traits::Protocol,
 };
-use cookie::Cookie;
+use futures_util::{SinkExt, StreamExt};
 use hyper::server::conn::AddrStream;
 use hyper::{
-	body::Bytes,
-	service::{make_service_fn, service_fn},
+	body::{Bytes, HttpBody},
+	service::{make_service_fn, service_fn, Service},
+};
+use hyper::{
+	header::{
+		HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, SEC_WEBSOCKET_KEY, UPGRADE,
+	},
+	upgrade::OnUpgrade,
+	Body, Request, Response, Server, StatusCode,
+};
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use tokio::{
+	net::{TcpListener, TcpStream},
+	task::JoinSet,
+};
+use tokio_openssl::SslStream;
+use tokio_tungstenite::{
+	tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+	WebSocketStream,
 };
-use hyper::{Body, Request, Response, Server};
 use url::Url;
 
 /// The marker struct for the HTTP protocol implementation.
@@ -22,17 +54,101 @@ pub struct Http;
 /// The configuration struct for the HTTP protocol implementation.
 #[allow(clippy::module_name_repetitions)]
 pub struct HttpConfig {
-	/// The ipv4 address on which to listen.
-	pub ip: [u8; 4],
+	/// The address on which to listen, which may be IPv4 or IPv6.
+	pub ip: IpAddr,
 	/// The TCP port on which to listen.
 	pub port: u16,
+	/// The methods advertised in the `Allow` header of a server-wide `OPTIONS *` request.
+	pub allowed_methods: Vec<String>,
+	/// The `Strict-Transport-Security` policy to send, if any.
+	/// It is only ever sent over secure connections.
+	pub hsts: Option<Hsts>,
+	/// The `Alt-Svc` header value to send on every response, if any.
+	pub alt_svc: Option<String>,
+	/// How many requests a connection may make before it's closed, if limited.
+	/// Pipelined requests past the limit are dropped along with the connection, and the client has to retry them.
+	pub max_requests_per_connection: Option<usize>,
+	/// The most a connection may buffer while reading a request, in bytes, if not hyper's default.
+	/// hyper needs at least 8192 bytes, so anything smaller is raised to that.
+	pub read_buffer_size: Option<usize>,
+	/// Whether a request's CGI binaries are killed when its client disconnects before the response is sent.
+	/// Leave this off if scripts can't be safely stopped partway through, like ones that write files.
+	pub cancel_on_disconnect: bool,
+	/// The largest request body accepted, in bytes, if limited. Larger bodies are refused with a 413.
+	pub max_body_size: Option<usize>,
+	/// The certificate to serve HTTPS with, if any. Without one, plain HTTP is served.
+	pub tls: Option<TlsConfig>,
+}
+
+/// The certificate and key to serve HTTPS with.
+pub struct TlsConfig {
+	/// The PEM certificate chain.
+	pub public: PathBuf,
+	/// The PEM PKCS #8 private key.
+	pub private: PathBuf,
+	/// Whether to ask clients for a certificate. It isn't required or verified,
+	/// it's only passed on to content, like over Gemini.
+	pub client_certs: bool,
+}
+
+impl TlsConfig {
+	/// Loads the certificate and key into an acceptor for connections.
+	/// # Errors
+	/// Errors if either file can't be read, or they aren't a valid certificate and key.
+	pub fn acceptor(&self) -> anyhow::Result<SslAcceptor> {
+		let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+		acceptor.set_certificate_chain_file(&self.public)?;
+		acceptor.set_private_key_file(&self.private, SslFiletype::PEM)?;
+		acceptor.check_private_key()?;
+		if self.client_certs {
+			acceptor.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+		}
+		Ok(acceptor.build())
+	}
+}
+
+/// A `Strict-Transport-Security` policy.
+#[derive(Clone)]
+pub struct Hsts {
+	/// How long, in seconds, browsers should only use HTTPS for this host.
+	pub max_age: u64,
+	/// Whether the policy also applies to subdomains.
+	pub include_subdomains: bool,
+	/// Whether the host consents to being preloaded into browsers.
+	pub preload: bool,
+}
+
+impl Hsts {
+	/// Renders the policy as a header value.
+	#[must_use]
+	pub fn header_value(&self) -> String {
+		let mut value = format!("max-age={}", self.max_age);
+		if self.include_subdomains {
+			value += "; includeSubDomains";
+		}
+		if self.preload {
+			value += "; preload";
+		}
+		value
+	}
 }
 
 impl Default for HttpConfig {
 	fn default() -> Self {
 		Self {
-			ip: [127, 0, 0, 1],
+			ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
 			port: 8000,
+			allowed_methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"]
+				.into_iter()
+				.map(str::to_string)
+				.collect(),
+			hsts: None,
+			alt_svc: None,
+			max_requests_per_connection: None,
+			read_buffer_size: None,
+			cancel_on_disconnect: false,
+			max_body_size: None,
+			tls: None,
 		}
 	}
 }
@@ -47,78 +163,584 @@ impl Protocol for Http {
 
 	/// Starts the protocol.
 	async fn run(self, config: Self::Config, server: WWebSServer) -> anyhow::Result<()> {
-		let addr = SocketAddr::from((config.ip, config.port));
+		let addr = SocketAddr::new(config.ip, config.port);
+		let config = Arc::new(config);
+
+		if let Some(tls) = &config.tls {
+			return Self::serve_tls(addr, tls.acceptor()?, config, server).await;
+		}
 
 		let make_svc = make_service_fn({
-			|_conn: &AddrStream| {
-				let server = server.clone();
-				async move { Ok::<_, Infallible>(service_fn(move |r| Self::handle(server.clone(), r))) }
+			|conn: &AddrStream| {
+				let service = Self::service(
+					server.clone(),
+					config.clone(),
+					None,
+					conn.remote_addr(),
+					conn.local_addr(),
+				);
+				async move { Ok::<_, Infallible>(service) }
 			}
 		});
 
-		let server = Server::bind(&addr).serve(make_svc);
-		server.await?;
+		let mut builder = Server::try_bind(&addr)?;
+		if let Some(size) = config.read_buffer_size {
+			builder = builder.http1_max_buf_size(size.max(8192));
+		}
+		server.listener_bound();
+		// hyper stops accepting right away, and waits for the requests it's answering.
+		let shutdown = {
+			let server = server.clone();
+			async move { server.shutting_down().await }
+		};
+		builder
+			.serve(make_svc)
+			.with_graceful_shutdown(shutdown)
+			.await?;
 		Ok(())
 	}
 }
 
 impl Http {
-	async fn handle(server: WWebSServer, r: Request<Body>) -> Result<Response<Body>, Infallible> {
+	/// Accepts connections and serves HTTPS on them, each in a task of its own.
+	async fn serve_tls(
+		addr: SocketAddr,
+		acceptor: SslAcceptor,
+		config: Arc<HttpConfig>,
+		server: WWebSServer,
+	) -> anyhow::Result<()> {
+		let listener = TcpListener::bind(addr).await?;
+		server.listener_bound();
+		let mut connections = JoinSet::new();
+		loop {
+			let accepted = tokio::select! {
+				accepted = listener.accept() => accepted,
+				// Finished connections are reaped as they go, so the set doesn't grow forever.
+				Some(_) = connections.join_next() => continue,
+				() = server.shutting_down() => break,
+			};
+			let (stream, remote_addr) = match accepted {
+				Ok(accepted) => accepted,
+				Err(e) => {
+					tracing::error!("Failed to accept an HTTPS connection: {e}");
+					continue;
+				}
+			};
+			let local_addr = stream.local_addr().unwrap_or(addr);
+			let acceptor = acceptor.clone();
+			let server = server.clone();
+			let config = config.clone();
+			let mut http = hyper::server::conn::Http::new();
+			if let Some(size) = config.read_buffer_size {
+				http.max_buf_size(size.max(8192));
+			}
+			connections.spawn(async move {
+				let stream = match Self::accept_tls(&acceptor, stream).await {
+					Ok(stream) => stream,
+					Err(e) => {
+						tracing::warn!("TLS handshake failed: {e}");
+						return;
+					}
+				};
+				// What was negotiated is only known once the handshake is done, so that's when the service is built.
+				let tls = TlsInfo::from_ssl(stream.ssl());
+				let service =
+					Self::service(server.clone(), config, Some(tls), remote_addr, local_addr);
+				let connection = http.serve_connection(stream, service);
+				tokio::pin!(connection);
+				// Shutting down lets the request in progress finish, then closes the connection.
+				let result = tokio::select! {
+					result = connection.as_mut() => result,
+					() = server.shutting_down() => {
+						connection.as_mut().graceful_shutdown();
+						connection.await
+					}
+				};
+				if let Err(e) = result {
+					tracing::error!("Error serving an HTTPS connection: {e}");
+				}
+			});
+		}
+		while connections.join_next().await.is_some() {}
+		Ok(())
+	}
+
+	/// Runs the TLS handshake on a connection.
+	async fn accept_tls(
+		acceptor: &SslAcceptor,
+		stream: TcpStream,
+	) -> anyhow::Result<SslStream<TcpStream>> {
+		let mut stream = SslStream::new(Ssl::new(acceptor.context())?, stream)?;
+		Pin::new(&mut stream).accept().await?;
+		Ok(stream)
+	}
+
+	/// Builds the service for a single connection.
+	fn service(
+		server: WWebSServer,
+		config: Arc<HttpConfig>,
+		tls: Option<TlsInfo>,
+		remote_addr: SocketAddr,
+		local_addr: SocketAddr,
+	) -> impl Service<
+		Request<Body>,
+		Response = Response<Body>,
+		Error = Infallible,
+		Future = impl Future<Output = Result<Response<Body>, Infallible>> + Send,
+	> + Send {
+		let request_count = Arc::new(AtomicUsize::new(0));
+		// The service lives as long as the connection, so it holds the connection's guard.
+		let connection = server.open_connection();
+		service_fn(move |r| {
+			let server = server.clone();
+			let config = config.clone();
+			let request_count = request_count.clone();
+			let tls = tls.clone();
+			let admitted = connection.is_some();
+			async move {
+				if !admitted {
+					return Ok(Response::builder()
+						.status(503)
+						.header(CONNECTION, "close")
+						.body(Body::empty())
+						.unwrap());
+				}
+				let addrs = (remote_addr, local_addr);
+				Self::handle(server, config, request_count, tls, addrs, r).await
+			}
+		})
+	}
+
+	/// Answers a request, closing the connection afterwards if it has made too many.
+	async fn handle(
+		server: WWebSServer,
+		config: Arc<HttpConfig>,
+		request_count: Arc<AtomicUsize>,
+		tls: Option<TlsInfo>,
+		addrs: (SocketAddr, SocketAddr),
+		r: Request<Body>,
+	) -> Result<Response<Body>, Infallible> {
+		let mut response = Self::respond(server, config.clone(), tls, addrs, r).await?;
+		let request_count = request_count.fetch_add(1, Ordering::SeqCst) + 1;
+		// An upgraded connection isn't HTTP anymore, so there's nothing left to close.
+		if config
+			.max_requests_per_connection
+			.is_some_and(|max| request_count >= max)
+			&& response.status() != StatusCode::SWITCHING_PROTOCOLS
+		{
+			response
+				.headers_mut()
+				.insert(CONNECTION, HeaderValue::from_static("close"));
+		}
+		Ok(response)
+	}
+
+	async fn respond(
+		server: WWebSServer,
+		config: Arc<HttpConfig>,
+		tls: Option<TlsInfo>,
+		(remote_addr, local_addr): (SocketAddr, SocketAddr),
+		mut r: Request<Body>,
+	) -> Result<Response<Body>, Infallible> {
+		// `OPTIONS *` asks about the server as a whole, so it never touches the filesystem.
+		if r.method() == hyper::Method::OPTIONS && r.uri() == "*" {
+			return Ok(Response::builder()
+				.status(204)
+				.header("Allow", config.allowed_methods.join(", "))
+				.body(Body::empty())
+				.unwrap());
+		}
+		if r.headers().len() > server.config().limits.max_headers {
+			return Ok(Response::builder().status(431).body(Body::empty()).unwrap());
+		}
+		let Some(mut reservation) = server.reserve_body(0) else {
+			return Ok(Response::builder().status(503).body(Body::empty()).unwrap());
+		};
+		let body = match Self::read_body(&config, r.body_mut(), &mut reservation).await {
+			Ok(body) => body,
+			Err(status) => {
+				return Ok(Response::builder()
+					.status(status)
+					.body(Body::empty())
+					.unwrap())
+			}
+		};
+		// The connection can only be taken over once the response is sent, so it's set aside in case a handler wants it.
+		let websocket = r
+			.headers()
+			.get(SEC_WEBSOCKET_KEY)
+			.map(|key| derive_accept_key(key.as_bytes()))
+			.map(|accept| (accept, hyper::upgrade::on(&mut r)));
 		let mut request = WWebSRequest {
 			proto: "Http",
 			verb: r.method().to_string(),
 			url: {
 				let http_uri = r.uri();
-				let mut url = Url::parse("http://localhost/").unwrap();
-				url.set_path(&http_uri.to_string());
+				let mut url = Url::parse(if tls.is_some() {
+					"https://localhost/"
+				} else {
+					"http://localhost/"
+				})
+				.unwrap();
+				url.set_path(http_uri.path());
+				url.set_query(http_uri.query());
 				url
 			},
-			headers: {
-				r.headers()
-					.iter()
-					.filter(|(k, _)| *k != "Cookie")
-					.map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
-					.chain(
-						r.headers()
-							.iter()
-							.filter(|(k, _)| *k == "Cookie")
-							.filter_map(|(_, v)| v.to_str().ok())
-							.flat_map(|s| {
-								s.split(';')
-									.map(str::trim)
-									.flat_map(Cookie::parse)
-									.map(|cookie| {
-										(
-											format!("Cookie_{}", cookie.name()),
-											cookie.value().to_string(),
-										)
-									})
-									.collect::<Vec<_>>()
-									.into_iter()
-							}),
-					)
-					.map(|(name, value)| (name.replace('-', "_"), value))
-					.collect()
-			},
-			body: hyper::body::to_bytes(r.into_body()).await.unwrap().to_vec(),
+			headers: Self::request_headers(r.headers()),
+			body,
+			cancellation: Cancellation::default(),
+			remote_addr: Some(remote_addr),
+			local_addr: Some(local_addr),
+			version: Some(format!("{:?}", r.version())),
+			tls,
+		};
+		// hyper drops this future if the client disconnects, which is how abandoned requests are noticed.
+		let mut guard = CancelOnDrop(
+			config
+				.cancel_on_disconnect
+				.then(|| request.cancellation.clone()),
+		);
+		// Resolving blocks, so it gets a thread of its own rather than stalling other connections.
+		let (request, mut response) = {
+			let server = server.clone();
+			tokio::task::spawn_blocking(move || {
+				// HEAD responses never send their body, so there's no need to hold on to it.
+				let response = if request.verb == "HEAD" {
+					server.head(&mut request)
+				} else {
+					server.exec(&mut request, 0, &mut WWebS::default())
+				};
+				(request, response)
+			})
+			.await
+		}
+		.unwrap_or_else(|e| {
+			tracing::error!("Resolving a request panicked: {e}");
+			(
+				WWebSRequest::default(),
+				WWebSResponse::internal_server_error(),
+			)
+		});
+		guard.0 = None;
+		if let Some(input) = response.upgrade.take() {
+			return Ok(Self::accept_websocket(
+				websocket,
+				&input,
+				response,
+				request.cancellation,
+			));
+		}
+		// The response is held until it's sent, so later requests should know about it.
+		reservation.grow(response.body.len());
+		Self::add_site_headers(&config, &mut response, request.tls.is_some());
+		match Self::to_hyper(response, reservation) {
+			Ok(hyper_res) => Ok(hyper_res),
+			Err(reason) => {
+				tracing::error!("Sending a 500 for {}, {reason}", request.url.path());
+				let body = server
+					.config()
+					.fallback_body
+					.clone()
+					.unwrap_or_else(|| "Whoopsie".to_string());
+				Ok(Response::builder().status(500).body(body.into()).unwrap())
+			}
+		}
+	}
+
+	/// Switches a connection over to a WebSocket, which is relayed to a handler's input and output once hyper lets go of it.
+	/// Answers with a 400 instead if the request wasn't a WebSocket handshake, which drops (and so stops) the handler.
+	fn accept_websocket(
+		websocket: Option<(String, OnUpgrade)>,
+		input: &UpgradeSink,
+		response: WWebSResponse,
+		cancellation: Cancellation,
+	) -> Response<Body> {
+		let output = response.stream.and_then(|stream| stream.take());
+		let (Some((accept, on_upgrade)), Some(input), Some(output)) =
+			(websocket, input.take(), output)
+		else {
+			return Response::builder().status(400).body(Body::empty()).unwrap();
 		};
-		let response = server.exec(&mut request, 0, &mut WWebS::default());
-		let mut hyper_res = Response::builder().status(response.status);
-		for (k, v) in response.headers {
-			hyper_res = hyper_res.header(k, v);
-		}
-		let bytes = Bytes::copy_from_slice(&response.body);
-		let body = Body::from(bytes);
-		let hyper_res = hyper_res.body(body);
-		if let Ok(hyper_res) = hyper_res {
-			Ok(hyper_res)
-		} else {
-			let (mut sender, body) = Body::channel();
-			sender
-				.send_data(Bytes::from("Whoopsie"))
+		tokio::spawn(Self::relay_websocket(
+			on_upgrade,
+			input,
+			output,
+			cancellation,
+		));
+		Response::builder()
+			.status(StatusCode::SWITCHING_PROTOCOLS)
+			.header(UPGRADE, "websocket")
+			.header(CONNECTION, "Upgrade")
+			.header("Sec-WebSocket-Accept", accept)
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	/// Relays a WebSocket to and from a handler, a line per message, until either side closes it.
+	/// The handler's stdin is closed when the client goes away, and it's killed if it hasn't exited a few seconds later.
+	async fn relay_websocket(
+		on_upgrade: OnUpgrade,
+		mut input: Box<dyn Write + Send>,
+		output: Box<dyn Read + Send>,
+		cancellation: Cancellation,
+	) {
+		let upgraded = match on_upgrade.await {
+			Ok(upgraded) => upgraded,
+			Err(e) => {
+				tracing::error!("Failed to upgrade a connection to a WebSocket: {e}");
+				cancellation.cancel();
+				return;
+			}
+		};
+		let (mut to_client, mut from_client) =
+			WebSocketStream::from_raw_socket(upgraded, Role::Server, None)
 				.await
-				.expect("Failed to send");
-			Ok(Response::builder().status(500).body(body).unwrap())
+				.split();
+		// The handler's pipes block, so each of them is worked on a thread of its own.
+		let (line_sender, mut lines) = tokio::sync::mpsc::channel::<String>(16);
+		let reader = tokio::task::spawn_blocking(move || {
+			for line in BufReader::new(output).lines().map_while(Result::ok) {
+				if line_sender.blocking_send(line).is_err() {
+					return;
+				}
+			}
+		});
+		let (message_sender, messages) = std::sync::mpsc::channel::<Vec<u8>>();
+		tokio::task::spawn_blocking(move || {
+			for mut message in messages {
+				message.push(b'\n');
+				if input
+					.write_all(&message)
+					.and_then(|()| input.flush())
+					.is_err()
+				{
+					return;
+				}
+			}
+		});
+		loop {
+			tokio::select! {
+				line = lines.recv() => {
+					let Some(line) = line else {
+						let _ = to_client.send(Message::Close(None)).await;
+						break;
+					};
+					if to_client.send(Message::Text(line)).await.is_err() {
+						break;
+					}
+				}
+				message = from_client.next() => {
+					let message = match message {
+						Some(Ok(Message::Text(text))) => text.into_bytes(),
+						Some(Ok(Message::Binary(data))) => data,
+						// Pings are answered by tungstenite itself.
+						Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+						// Closing sends tungstenite's reply to the client's close.
+						Some(Ok(Message::Close(_))) => {
+							let _ = to_client.close().await;
+							break;
+						}
+						Some(Err(_)) | None => break,
+					};
+					if message_sender.send(message).is_err() {
+						break;
+					}
+				}
+			}
+		}
+		drop(message_sender);
+		if tokio::time::timeout(Duration::from_secs(5), reader)
+			.await
+			.is_err()
+		{
+			cancellation.cancel();
+		}
+	}
+
+	/// Reads a request's body, as long as it fits in `max_body_size`, reserving memory for it as it goes.
+	/// Errors with a 413 for bodies that don't fit, a 503 once the server can't hold any more of it,
+	/// or a 400 for ones that break off partway.
+	async fn read_body(
+		config: &HttpConfig,
+		body: &mut Body,
+		reservation: &mut BodyReservation,
+	) -> Result<Vec<u8>, u16> {
+		let max = config.max_body_size.unwrap_or(usize::MAX);
+		// Refuse bodies that say they're too big before reading any of them.
+		let expected = usize::try_from(body.size_hint().lower()).unwrap_or(usize::MAX);
+		if expected > max {
+			return Err(413);
+		}
+		if !reservation.try_grow(expected) {
+			return Err(503);
+		}
+		let mut reserved = expected;
+		let mut buf = vec![];
+		while let Some(chunk) = body.data().await {
+			let chunk = chunk.map_err(|e| {
+				tracing::error!("Failed to read a request body: {e}");
+				400_u16
+			})?;
+			let len = buf.len() + chunk.len();
+			if len > max {
+				return Err(413);
+			}
+			// Bodies without a `Content-Length` are reserved for a chunk at a time.
+			if len > reserved {
+				if !reservation.try_grow(len - reserved) {
+					return Err(503);
+				}
+				reserved = len;
+			}
+			buf.extend_from_slice(&chunk);
+		}
+		Ok(buf)
+	}
+
+	/// Converts hyper's headers to wwebs', with each cookie as a header of its own.
+	/// Names are kept as they were sent (lowercased by hyper), so underscores aren't confused with dashes.
+	/// Headers sent more than once are joined with commas into one, as HTTP allows for everything but cookies.
+	fn request_headers(headers: &HeaderMap) -> HashMap<String, String> {
+		let mut joined: HashMap<String, String> = HashMap::new();
+		for (k, v) in headers.iter().filter(|(k, _)| *k != "Cookie") {
+			let value = v.to_str().unwrap_or("");
+			joined
+				.entry(k.to_string())
+				.and_modify(|values| {
+					values.push_str(", ");
+					values.push_str(value);
+				})
+				.or_insert_with(|| value.to_string());
+		}
+		let cookies = headers
+			.get_all("Cookie")
+			.iter()
+			.filter_map(|v| v.to_str().ok())
+			.flat_map(WWebSRequest::cookie_headers);
+		joined.extend(cookies);
+		joined
+	}
+
+	/// Converts a response to hyper's, explaining which part of it was invalid if that fails.
+	/// The body keeps `reservation` until it has all been handed to hyper.
+	fn to_hyper(
+		response: WWebSResponse,
+		reservation: BodyReservation,
+	) -> Result<Response<Body>, String> {
+		let status = StatusCode::from_u16(response.status)
+			.map_err(|_| format!("{} is not a valid status", response.status))?;
+		let mut hyper_res = Response::builder().status(status);
+		let reader = response.stream.and_then(|stream| stream.take());
+		// hyper can't tell how long a body handed over in pieces is, so it's told.
+		let pieces = reader.is_none() && !response.body.is_empty();
+		if pieces {
+			hyper_res = hyper_res.header(CONTENT_LENGTH, response.body.len());
+		}
+		for (k, values) in response.headers {
+			if pieces && k.eq_ignore_ascii_case("Content-Length") {
+				continue;
+			}
+			let name = HeaderName::from_bytes(k.as_bytes())
+				.map_err(|_| format!("{k:?} is not a valid header name"))?;
+			// Several values are separated by newlines, and each gets a line of its own.
+			for v in values.split('\n') {
+				let value = HeaderValue::from_str(v)
+					.map_err(|_| format!("{v:?} is not a valid value for the {k} header"))?;
+				hyper_res = hyper_res.header(&name, value);
+			}
+		}
+		let body = match reader {
+			Some(reader) => Self::stream_body(response.body, reader, reservation),
+			None if pieces => Body::wrap_stream(ReservedBody {
+				rest: Bytes::from(response.body),
+				reservation: Some(reservation),
+			}),
+			None => Body::empty(),
+		};
+		hyper_res.body(body).map_err(|e| e.to_string())
+	}
+
+	/// Sends `start`, then the rest of a streamed body as it's read, keeping `reservation` until it's done.
+	/// The reader blocks, so it's read on a thread of its own. It's dropped once the client goes away, which stops it.
+	fn stream_body(
+		start: Vec<u8>,
+		mut reader: Box<dyn Read + Send>,
+		reservation: BodyReservation,
+	) -> Body {
+		let (mut sender, body) = Body::channel();
+		let runtime = tokio::runtime::Handle::current();
+		tokio::task::spawn_blocking(move || {
+			let _reservation = reservation;
+			let mut chunk = start;
+			let mut buf = vec![0; 8192];
+			loop {
+				if !chunk.is_empty() && runtime.block_on(sender.send_data(chunk.into())).is_err() {
+					return;
+				}
+				chunk = match reader.read(&mut buf) {
+					Ok(0) => return,
+					Ok(n) => buf[..n].to_vec(),
+					Err(e) => {
+						tracing::error!("Failed to stream a response: {e}");
+						sender.abort();
+						return;
+					}
+				};
+			}
+		});
+		body
+	}
+
+	/// Adds the site-wide headers from the config, unless the response already set them.
+	fn add_site_headers(config: &HttpConfig, response: &mut WWebSResponse, secure: bool) {
+		let mut add = |name: &str, value: String| {
+			if !response
+				.headers
+				.keys()
+				.any(|k| k.eq_ignore_ascii_case(name))
+			{
+				response.headers.insert(name.to_string(), value);
+			}
+		};
+		if let (Some(hsts), true) = (&config.hsts, secure) {
+			add("Strict-Transport-Security", hsts.header_value());
+		}
+		if let Some(alt_svc) = &config.alt_svc {
+			add("Alt-Svc", alt_svc.clone());
+		}
+	}
+}
+
+/// The most of a body held in memory that's handed to hyper at once.
+const PIECE_SIZE: usize = 64 * 1024;
+
+/// A body held in memory, handed to hyper a piece at a time, which only waits for more once it has sent most of what it has.
+/// The memory reserved for the body is given back once hyper asks for more after the last piece, or drops it when the client goes away.
+struct ReservedBody {
+	rest: Bytes,
+	reservation: Option<BodyReservation>,
+}
+
+impl futures_util::Stream for ReservedBody {
+	type Item = Result<Bytes, Infallible>;
+
+	fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+		if self.rest.is_empty() {
+			self.reservation = None;
+			return Poll::Ready(None);
+		}
+		let len = self.rest.len().min(PIECE_SIZE);
+		Poll::Ready(Some(Ok(self.rest.split_to(len))))
+	}
+}
+
+/// Cancels a request if dropped while still holding it.
+struct CancelOnDrop(Option<Cancellation>);
+
+impl Drop for CancelOnDrop {
+	fn drop(&mut self) {
+		if let Some(cancellation) = self.0.take() {
+			cancellation.cancel();
 		}
 	}
 }
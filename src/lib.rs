@@ -6,6 +6,8 @@
 
 /// Definitions for the configuration file syntax used by wwebs
 pub mod files {
+	/// Structures used in the top-level server configuration file.
+	pub mod server;
 	/// Structures used in wwebs.toml.
 	pub mod wwebs;
 }
@@ -32,3 +34,7 @@ pub mod http;
 
 #[cfg(feature = "gemini")]
 pub mod gemini;
+
+#[cfg(feature = "scgi")]
+/// The module implementing SCGI support in wwebs.
+pub mod scgi;
@@ -1,60 +1,252 @@
+#[cfg(feature = "http")]
+use std::net::{Ipv4Addr, SocketAddr};
+use std::{io::IsTerminal, path::PathBuf};
+
 use structopt::StructOpt;
-use wwebs::{
-	gemini::{GConfig, Gemini},
-	http::{Http, HttpConfig},
-	server::Server,
-	traits::Protocol,
+use tokio::{
+	signal::unix::{signal, SignalKind},
+	task::JoinHandle,
 };
+use tracing_subscriber::EnvFilter;
+#[cfg(feature = "gemini")]
+use wwebs::gemini::{GConfig, Gemini};
+#[cfg(feature = "http")]
+use wwebs::http::{Http, HttpConfig, TlsConfig};
+#[cfg(feature = "scgi")]
+use wwebs::scgi::{Scgi, ScgiConfig};
+#[cfg(any(feature = "http", feature = "gemini", feature = "scgi"))]
+use wwebs::traits::Protocol;
+use wwebs::{files::server::ServerConfig, server::Server, structures::Request};
 
 #[derive(structopt::StructOpt)]
 struct Opts {
 	/// The port to listen on for HTTP.
+	#[cfg(feature = "http")]
 	#[structopt(short, long)]
 	pub http_port: Option<u16>,
+	/// The full address to listen on for HTTP, like `0.0.0.0:80` or `[::1]:8080`.
+	/// This takes precedence over `--http-port`, which listens on every IPv4 address.
+	#[cfg(feature = "http")]
+	#[structopt(long)]
+	pub http_addr: Option<SocketAddr>,
+	/// The location of the HTTPS private key, in PKCS #8 PEM.
+	/// Like the Gemini key, keep it out of the web directory!!!
+	/// HTTP is only served over TLS if *both* options are set.
+	#[cfg(feature = "http")]
+	#[structopt(long)]
+	pub http_priv: Option<PathBuf>,
+	/// The location of the HTTPS certificate chain, in PEM.
+	/// HTTP is only served over TLS if *both* options are set.
+	#[cfg(feature = "http")]
+	#[structopt(long)]
+	pub http_pub: Option<PathBuf>,
+	/// Ask HTTPS clients for a certificate, passed on to content as `SSL_CLIENT_CERT`.
+	/// It isn't required or verified, so content must decide whether to trust it.
+	#[cfg(feature = "http")]
+	#[structopt(long)]
+	pub http_client_certs: bool,
 	/// The location of the Gemini private key.
 	/// Make sure it isn't in the web directory and o+r, otherwise clients will be able to download it!!!
+	/// wwebs refuses to start if anyone but its owner has access to it.
 	/// Gemini will only be enabled if *both* options are set!!!
+	#[cfg(feature = "gemini")]
 	#[structopt(short, long)]
 	pub gem_priv: Option<String>,
 	/// The location of the Gemini public key.
 	/// Gemini will only be enabled if *both* options are set!!!
+	#[cfg(feature = "gemini")]
 	#[structopt(short = "G", long, env = "GEM_PASS")]
 	pub gem_pub: Option<String>,
+	/// The address to listen on for SCGI from a front-end web server,
+	/// either a `host:port` like `127.0.0.1:4000` or `unix:` followed by a socket path.
+	#[cfg(feature = "scgi")]
+	#[structopt(long)]
+	pub scgi_addr: Option<String>,
+	/// The location of the top-level server configuration file.
+	/// Sending wwebs a SIGHUP re-reads it without dropping connections.
+	#[structopt(short, long)]
+	pub config: Option<PathBuf>,
+	#[structopt(subcommand)]
+	pub command: Option<Command>,
+}
+
+#[derive(structopt::StructOpt)]
+enum Command {
+	/// Explains how a path (like `/blog/?page=2`) would be resolved, step by step, without running anything.
+	Explain {
+		/// The path to explain.
+		path: String,
+		/// The method to explain it for.
+		#[structopt(long, default_value = "GET")]
+		method: String,
+	},
 }
 
 #[tokio::main]
 async fn main() {
+	// `RUST_LOG` picks what's logged (like `RUST_LOG=debug`, or `RUST_LOG=warn` for less), and it's everything from `info` up otherwise.
+	tracing_subscriber::fmt()
+		.with_env_filter(
+			EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+		)
+		.with_writer(std::io::stderr)
+		.with_ansi(std::io::stderr().is_terminal())
+		.init();
 	let workdir = std::env::current_dir().unwrap();
-	let server = Server::new(workdir);
-
 	let opt = Opts::from_args();
 
-	let http_fut = opt.http_port.map(|port| {
-		tokio::task::spawn(Http.run(
-			HttpConfig {
-				ip: [0, 0, 0, 0],
-				port,
-			},
-			server.clone(),
-		))
-	});
-
-	let gem_fut = if let (Some(private), Some(public)) = (opt.gem_priv.clone(), opt.gem_pub.clone())
+	let server_config = opt
+		.config
+		.as_ref()
+		.map_or_else(ServerConfig::default, |path| {
+			ServerConfig::load(path).expect("Failed to load the server configuration")
+		});
+	let server = Server::with_config(workdir, server_config);
+
+	if let Some(Command::Explain { path, method }) = opt.command {
+		let mut request = Request::default();
+		request.verb = method;
+		request.url = request.url.join(&path).expect("Invalid path");
+		for step in server.explain(&mut request) {
+			println!("{step}");
+		}
+		return;
+	}
+
+	if let Some(path) = opt.config.clone() {
+		let server = server.clone();
+		let mut hangups = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+		tokio::task::spawn(async move {
+			while hangups.recv().await.is_some() {
+				match server.reload_config(&path) {
+					Ok(()) => tracing::info!("Reloaded {}", path.display()),
+					Err(e) => tracing::warn!(
+						"Keeping the old configuration, {} is invalid: {e}",
+						path.display()
+					),
+				}
+			}
+		});
+	}
+
 	{
-		Some(tokio::task::spawn(
-			Gemini.run(GConfig { private, public }, server),
-		))
-	} else {
-		None
-	};
-	if let Some(fut) = gem_fut {
-		fut.await.unwrap().expect("Gemini failed");
+		let server = server.clone();
+		tokio::task::spawn(async move {
+			shutdown_signal().await;
+			tracing::info!("Shutting down, send another SIGINT or SIGTERM to stop right away");
+			systemd::notify_stopping();
+			server.shut_down();
+			// Requests still waiting on CGI binaries after the grace period are answered once those are killed.
+			tokio::task::spawn({
+				let server = server.clone();
+				async move { server.finish_cgi().await }
+			});
+			shutdown_signal().await;
+			tracing::info!("Stopping without waiting for requests to finish");
+			std::process::exit(1);
+		});
+	}
+
+	// Without any protocol features, nothing is ever pushed.
+	#[allow(unused_mut)]
+	let mut protocols: Vec<(&str, JoinHandle<anyhow::Result<()>>)> = vec![];
+
+	#[cfg(feature = "http")]
+	if let Some(addr) = opt.http_addr.or_else(|| {
+		opt.http_port
+			.map(|port| SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+	}) {
+		protocols.push((
+			"HTTP",
+			tokio::task::spawn(Http.run(
+				HttpConfig {
+					ip: addr.ip(),
+					port: addr.port(),
+					tls: opt.http_priv.clone().zip(opt.http_pub.clone()).map(
+						|(private, public)| TlsConfig {
+							public,
+							private,
+							client_certs: opt.http_client_certs,
+						},
+					),
+					..Default::default()
+				},
+				server.clone(),
+			)),
+		));
+	}
+
+	#[cfg(feature = "gemini")]
+	if let (Some(private), Some(public)) = (opt.gem_priv.clone(), opt.gem_pub.clone()) {
+		protocols.push((
+			"Gemini",
+			tokio::task::spawn(Gemini.run(GConfig { private, public }, server.clone())),
+		));
 	}
-	if let Some(fut) = http_fut {
-		fut.await.unwrap().expect("HTTP failed");
+
+	#[cfg(feature = "scgi")]
+	if let Some(address) = opt.scgi_addr.clone() {
+		protocols.push((
+			"SCGI",
+			tokio::task::spawn(Scgi.run(
+				ScgiConfig {
+					address,
+					..Default::default()
+				},
+				server.clone(),
+			)),
+		));
 	}
 
-	if let (None, None, None) = (opt.http_port, opt.gem_priv, opt.gem_pub) {
+	if protocols.is_empty() {
 		eprintln!("You need to pass an http port or a Gemini certificate and password for wwebs to do anything");
 	}
+	{
+		let server = server.clone();
+		let count = protocols.len();
+		tokio::task::spawn(async move {
+			server.listeners_bound(count).await;
+			systemd::notify_ready();
+		});
+	}
+	for (name, fut) in protocols {
+		let res = fut.await.unwrap();
+		if let Err(e) = res {
+			systemd::notify_stopping();
+			panic!("{name} failed: {e}");
+		}
+	}
+	systemd::notify_stopping();
+}
+
+/// Waits for a SIGINT (like from Ctrl-C) or SIGTERM, either of which starts shutting down.
+async fn shutdown_signal() {
+	let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+	tokio::select! {
+		interrupted = tokio::signal::ctrl_c() => interrupted.expect("Failed to listen for SIGINT"),
+		_ = terminate.recv() => {}
+	}
+}
+
+/// Lifecycle notifications for systemd, which do nothing unless built with the `systemd` feature.
+/// Socket activation would also belong here, but listeners are always bound by wwebs itself for now.
+mod systemd {
+	/// Sends `READY=1`, once every configured listener is bound.
+	pub fn notify_ready() {
+		#[cfg(feature = "systemd")]
+		notify(sd_notify::NotifyState::Ready);
+	}
+
+	/// Sends `STOPPING=1`, when wwebs begins shutting down.
+	pub fn notify_stopping() {
+		#[cfg(feature = "systemd")]
+		notify(sd_notify::NotifyState::Stopping);
+	}
+
+	#[cfg(feature = "systemd")]
+	fn notify(state: sd_notify::NotifyState) {
+		if let Err(e) = sd_notify::notify(false, &[state]) {
+			tracing::error!("Failed to notify systemd: {e}");
+		}
+	}
 }
@@ -0,0 +1,406 @@
+//! This module implements SCGI support for wwebs, for serving behind another web server.
+
+use std::{
+	collections::HashMap,
+	fmt::Write,
+	io::Read,
+	net::{IpAddr, SocketAddr},
+	os::unix::fs::FileTypeExt,
+	sync::Arc,
+};
+
+use crate::{
+	files::wwebs::WWebS,
+	server::{status_title, BodyReservation, Server},
+	structures::{BodyStream, Cancellation, Request, Response, TlsInfo},
+	traits::Protocol,
+};
+use async_trait::async_trait;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::{TcpListener, UnixListener},
+	task::JoinSet,
+};
+use url::Url;
+
+/// The longest block of headers accepted from the front-end, in bytes.
+const MAX_HEADERS_LEN: usize = 1 << 20;
+
+/// The marker struct for SCGI servers.
+pub struct Scgi;
+
+/// The configuration struct used by the SCGI protocol.
+#[allow(clippy::module_name_repetitions)]
+pub struct ScgiConfig {
+	/// The address on which to listen, either `unix:` followed by a socket path or a `host:port`.
+	pub address: String,
+	/// The largest request body accepted, in bytes, if limited.
+	pub max_body_size: Option<usize>,
+}
+
+impl Default for ScgiConfig {
+	fn default() -> Self {
+		Self {
+			address: "127.0.0.1:4000".to_string(),
+			max_body_size: None,
+		}
+	}
+}
+
+/// The SCGI request structure.
+pub struct SRequest {
+	/// The variables the front-end sent, like `REQUEST_METHOD` and `HTTP_HOST`.
+	pub vars: HashMap<String, String>,
+	/// The body of the request, which is `CONTENT_LENGTH` bytes long.
+	pub body: Vec<u8>,
+}
+
+/// The SCGI response structure.
+pub struct SResponse {
+	/// The HTTP status of the response.
+	pub status: u16,
+	/// The headers of the response, with a value per line for headers that are sent more than once.
+	pub headers: Vec<(String, String)>,
+	/// The body of the response, or the start of it if it's streamed.
+	pub body: Vec<u8>,
+	/// The rest of the body, if it's streamed.
+	pub stream: Option<BodyStream>,
+}
+
+#[async_trait]
+impl Protocol for Scgi {
+	type Request = SRequest;
+
+	type Response = SResponse;
+
+	type Config = ScgiConfig;
+
+	async fn run(self, config: Self::Config, server: Server) -> anyhow::Result<()> {
+		let config = Arc::new(config);
+		let mut connections = JoinSet::new();
+		if let Some(path) = config.address.strip_prefix("unix:") {
+			// A socket left behind by a previous run would keep this one from binding.
+			if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+				std::fs::remove_file(path)?;
+			}
+			let listener = UnixListener::bind(path)?;
+			server.listener_bound();
+			loop {
+				tokio::select! {
+					accepted = listener.accept() => match accepted {
+						Ok((stream, _)) => {
+							connections.spawn(Self::serve(stream, config.clone(), server.clone()));
+						}
+						Err(e) => tracing::error!("Failed to accept an SCGI connection: {e}"),
+					},
+					// Finished connections are reaped as they go, so the set doesn't grow forever.
+					Some(_) = connections.join_next() => {}
+					() = server.shutting_down() => break,
+				}
+			}
+		} else {
+			let listener = TcpListener::bind(&config.address).await?;
+			server.listener_bound();
+			loop {
+				tokio::select! {
+					accepted = listener.accept() => match accepted {
+						Ok((stream, _)) => {
+							connections.spawn(Self::serve(stream, config.clone(), server.clone()));
+						}
+						Err(e) => tracing::error!("Failed to accept an SCGI connection: {e}"),
+					},
+					Some(_) = connections.join_next() => {}
+					() = server.shutting_down() => break,
+				}
+			}
+		}
+		// Each connection carries a single request, so waiting for them is waiting for the requests.
+		while connections.join_next().await.is_some() {}
+		Ok(())
+	}
+}
+
+impl Scgi {
+	/// Answers the single request a front-end sends over a connection.
+	async fn serve(
+		mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+		config: Arc<ScgiConfig>,
+		server: Server,
+	) {
+		let Some(_connection) = server.open_connection() else {
+			Self::write_status(stream, 503).await;
+			return;
+		};
+		let (request, mut reservation) =
+			match Self::read_request(&mut stream, &config, &server).await {
+				Ok(read) => read,
+				Err(status) => {
+					Self::write_status(stream, status).await;
+					return;
+				}
+			};
+		// Resolving blocks, so it gets a thread of its own rather than stalling other connections.
+		let response = {
+			let server = server.clone();
+			tokio::task::spawn_blocking(move || {
+				let mut request: Request = request.into();
+				// HEAD responses never send their body, so there's no need to hold on to it.
+				let response = if request.verb == "HEAD" {
+					server.head(&mut request)
+				} else {
+					server.exec(&mut request, 0, &mut WWebS::default())
+				};
+				SResponse::from(response)
+			})
+			.await
+		}
+		.unwrap_or_else(|e| {
+			tracing::error!("Resolving a request panicked: {e}");
+			SResponse::from(Response::internal_server_error())
+		});
+		reservation.grow(response.body.len());
+		if let Err(e) = Self::write_response(stream, response).await {
+			tracing::error!("Failed to send an SCGI response: {e}");
+		}
+	}
+
+	/// Reads a request's netstring of headers and then its body, which is reserved for before it's read.
+	/// Errors with the status to answer with if the request is malformed or too big, or its body doesn't fit in memory.
+	async fn read_request(
+		stream: &mut (impl AsyncRead + Unpin),
+		config: &ScgiConfig,
+		server: &Server,
+	) -> Result<(SRequest, BodyReservation), u16> {
+		let mut len = 0_usize;
+		loop {
+			let byte = stream.read_u8().await.map_err(|_| 400_u16)?;
+			match byte {
+				b':' => break,
+				b'0'..=b'9' => {
+					len = len * 10 + usize::from(byte - b'0');
+					if len > MAX_HEADERS_LEN {
+						return Err(431);
+					}
+				}
+				_ => return Err(400),
+			}
+		}
+		let mut headers = vec![0; len + 1];
+		stream.read_exact(&mut headers).await.map_err(|_| 400_u16)?;
+		if headers.pop() != Some(b',') {
+			return Err(400);
+		}
+		// Names and values alternate, each ending with a NUL.
+		let mut vars = HashMap::new();
+		let mut fields = headers.split(|&b| b == 0);
+		while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+			vars.insert(
+				String::from_utf8_lossy(name).to_string(),
+				String::from_utf8_lossy(value).to_string(),
+			);
+		}
+		let http_headers = vars.keys().filter(|k| k.starts_with("HTTP_")).count();
+		if http_headers > server.config().limits.max_headers {
+			return Err(431);
+		}
+		let content_length = vars
+			.get("CONTENT_LENGTH")
+			.and_then(|len| len.parse::<usize>().ok())
+			.ok_or(400_u16)?;
+		if content_length > config.max_body_size.unwrap_or(usize::MAX) {
+			return Err(413);
+		}
+		let reservation = server.reserve_body(content_length).ok_or(503_u16)?;
+		let mut body = vec![];
+		stream
+			.take(content_length as u64)
+			.read_to_end(&mut body)
+			.await
+			.map_err(|_| 400_u16)?;
+		if body.len() < content_length {
+			return Err(400);
+		}
+		Ok((SRequest { vars, body }, reservation))
+	}
+
+	/// Answers with nothing but a status.
+	async fn write_status(stream: impl AsyncWrite + Unpin + Send + 'static, status: u16) {
+		let response = SResponse {
+			status,
+			headers: vec![],
+			body: vec![],
+			stream: None,
+		};
+		if let Err(e) = Self::write_response(stream, response).await {
+			tracing::error!("Failed to send an SCGI response: {e}");
+		}
+	}
+
+	/// Writes a response as CGI output, which the front-end turns back into HTTP.
+	async fn write_response(
+		mut stream: impl AsyncWrite + Unpin + Send + 'static,
+		response: SResponse,
+	) -> std::io::Result<()> {
+		stream.write_all(&response.head()).await?;
+		stream.write_all(&response.body).await?;
+		let Some(mut reader) = response.stream.and_then(|stream| stream.take()) else {
+			return stream.shutdown().await;
+		};
+		// The stream's reader blocks, so it's copied over on a thread of its own.
+		let runtime = tokio::runtime::Handle::current();
+		tokio::task::spawn_blocking(move || {
+			let mut buf = vec![0; 8192];
+			loop {
+				match reader.read(&mut buf)? {
+					0 => return runtime.block_on(stream.shutdown()),
+					n => runtime.block_on(stream.write_all(&buf[..n]))?,
+				}
+			}
+		})
+		.await?
+	}
+}
+
+impl SResponse {
+	/// Builds the `Status` line and headers, up to and including the blank line that ends them.
+	#[must_use]
+	pub fn head(&self) -> Vec<u8> {
+		let mut head = format!("Status: {} {}\r\n", self.status, reason(self.status));
+		for (name, value) in &self.headers {
+			// A stray carriage return would let a value start a header of its own.
+			if name.contains(['\r', '\n', ':']) || value.contains('\r') {
+				tracing::warn!("Dropping the invalid {name:?} header from an SCGI response");
+				continue;
+			}
+			let _ = write!(head, "{name}: {value}\r\n");
+		}
+		head.push_str("\r\n");
+		head.into_bytes()
+	}
+}
+
+impl From<SRequest> for Request {
+	fn from(req: SRequest) -> Self {
+		let var = |name: &str| req.vars.get(name).filter(|v| !v.is_empty());
+		let secure = var("HTTPS").is_some_and(|v| v.eq_ignore_ascii_case("on") || v == "1");
+		let mut url = Url::parse(if secure {
+			"https://localhost/"
+		} else {
+			"http://localhost/"
+		})
+		.unwrap();
+		match var("REQUEST_URI") {
+			Some(uri) => {
+				let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+				url.set_path(path);
+				url.set_query(Some(query).filter(|q| !q.is_empty()));
+			}
+			None => url.set_path(&format!(
+				"{}{}",
+				var("SCRIPT_NAME").map_or("", String::as_str),
+				var("PATH_INFO").map_or("", String::as_str)
+			)),
+		}
+		if let Some(query) = var("QUERY_STRING") {
+			url.set_query(Some(query));
+		}
+
+		// Headers are named the way the HTTP protocol names them, so handlers can't tell the difference.
+		// CGI variables can't tell dashes from underscores, so they're taken to be dashes, which nearly every header uses.
+		let mut headers: HashMap<String, String> = req
+			.vars
+			.iter()
+			.filter_map(|(k, v)| {
+				let name = match k.strip_prefix("HTTP_") {
+					Some("COOKIE") => return None,
+					Some(name) => name,
+					None if k == "CONTENT_TYPE" || k == "CONTENT_LENGTH" => k,
+					None => return None,
+				};
+				Some((name.to_ascii_lowercase().replace('_', "-"), v.clone()))
+			})
+			.collect();
+		if let Some(cookies) = var("HTTP_COOKIE") {
+			headers.extend(Request::cookie_headers(cookies));
+		}
+
+		let remote_addr = var("REMOTE_ADDR")
+			.and_then(|addr| addr.parse::<IpAddr>().ok())
+			.map(|ip| {
+				let port = var("REMOTE_PORT").and_then(|port| port.parse().ok());
+				SocketAddr::new(ip, port.unwrap_or(0))
+			});
+		// Front-ends don't always send their own address, only the port, which is all CGI needs of it.
+		let local_addr = var("SERVER_PORT")
+			.and_then(|port| port.parse().ok())
+			.map(|port| {
+				let ip = var("SERVER_ADDR").and_then(|addr| addr.parse::<IpAddr>().ok());
+				SocketAddr::new(ip.unwrap_or(IpAddr::from([0, 0, 0, 0])), port)
+			});
+		Request {
+			// The client speaks HTTP, SCGI only carries it the rest of the way.
+			proto: "Http",
+			verb: var("REQUEST_METHOD").map_or_else(|| "GET".to_string(), Clone::clone),
+			url,
+			headers,
+			body: req.body,
+			cancellation: Cancellation::default(),
+			remote_addr,
+			local_addr,
+			version: var("SERVER_PROTOCOL").cloned(),
+			// Front ends pass on what they negotiated in the same variables content gets it in.
+			tls: secure.then(|| TlsInfo {
+				protocol: var("SSL_PROTOCOL").cloned(),
+				cipher: var("SSL_CIPHER").cloned(),
+				client_cert: var("SSL_CLIENT_CERT").cloned(),
+				client_subject: var("SSL_CLIENT_S_DN").cloned(),
+			}),
+		}
+	}
+}
+
+impl From<Response> for SResponse {
+	fn from(res: Response) -> Self {
+		// Front-ends don't hand SCGI the client's connection, so nothing can switch protocols.
+		// Dropping the upgrade stops whatever was waiting on it.
+		if res.upgrade.is_some() {
+			return SResponse {
+				status: 400,
+				headers: vec![],
+				body: vec![],
+				stream: None,
+			};
+		}
+		let headers = res
+			.headers
+			.iter()
+			.flat_map(|(k, values)| values.split('\n').map(|v| (k.clone(), v.to_string())))
+			.collect();
+		SResponse {
+			status: if res.status == 0 { 200 } else { res.status },
+			headers,
+			body: res.body,
+			stream: res.stream,
+		}
+	}
+}
+
+/// The reason phrase sent after a status.
+fn reason(status: u16) -> &'static str {
+	match status {
+		200 => "OK",
+		201 => "Created",
+		202 => "Accepted",
+		204 => "No Content",
+		206 => "Partial Content",
+		301 => "Moved Permanently",
+		302 => "Found",
+		303 => "See Other",
+		304 => "Not Modified",
+		307 => "Temporary Redirect",
+		308 => "Permanent Redirect",
+		_ if status < 300 => "OK",
+		_ if status < 400 => "Redirect",
+		_ => status_title(status),
+	}
+}
@@ -0,0 +1,118 @@
+//! Writing a line per request to directories' access logs, in the Combined Log Format.
+
+use std::{
+	fs::OpenOptions,
+	io::Write,
+	net::IpAddr,
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::structures::{Request, Response};
+use url::Url;
+
+const MONTHS: [&str; 12] = [
+	"Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Appends a request's line to the log at `path`, creating it if needed.
+/// Failing to write is logged to stderr, since the request has been answered either way.
+pub(super) fn append(
+	path: &Path,
+	client: Option<IpAddr>,
+	url: &Url,
+	request: &Request,
+	response: &Response,
+) {
+	let line = line(client, url, request, response, SystemTime::now());
+	// Each line goes out in a single append, so concurrent requests don't interleave.
+	let written = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.and_then(|mut file| file.write_all(line.as_bytes()));
+	if let Err(e) = written {
+		tracing::error!("Failed to write to the access log {}: {e}", path.display());
+	}
+}
+
+/// Formats a request's line, like
+/// `203.0.113.7 - - [16/Oct/2026:14:30:03 +0000] "GET /index.html HTTP/1.1" 200 3 "-" "curl/8.0"`.
+/// `url` is the one that was requested, and the protocol is the version the client spoke,
+/// or wwebs' own name for the protocol if it doesn't have versions (like Gemini).
+fn line(
+	client: Option<IpAddr>,
+	url: &Url,
+	request: &Request,
+	response: &Response,
+	now: SystemTime,
+) -> String {
+	let mut target = url.path().to_string();
+	if let Some(query) = url.query() {
+		target.push('?');
+		target.push_str(query);
+	}
+	// A streamed body's size isn't known until it has been sent.
+	let bytes = match (response.stream.is_some(), response.body.len()) {
+		(true, _) | (false, 0) => "-".to_string(),
+		(false, len) => len.to_string(),
+	};
+	let quoted = |value: Option<&str>| value.map_or_else(|| "-".to_string(), escape);
+	format!(
+		"{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"\n",
+		client.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+		clf_date(now),
+		escape(&request.verb),
+		escape(&target),
+		request
+			.version
+			.as_deref()
+			.map_or_else(|| request.proto.to_ascii_uppercase(), escape),
+		response.status,
+		bytes,
+		quoted(request.header("Referer")),
+		quoted(request.header("User-Agent")),
+	)
+}
+
+/// Escapes quotes, backslashes and control characters, so a value can't break out of its field or line.
+fn escape(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			c if c.is_control() => escaped.extend(c.escape_default()),
+			c => escaped.push(c),
+		}
+	}
+	escaped
+}
+
+/// Formats a time like `16/Oct/2026:14:30:03 +0000`, always in UTC.
+fn clf_date(time: SystemTime) -> String {
+	let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+	let (days, secs) = (secs / 86400, secs % 86400);
+	// Converts days since the epoch to a date, after Howard Hinnant's `civil_from_days`.
+	let days = days + 719_468;
+	let era = days / 146_097;
+	let day_of_era = days % 146_097;
+	let year_of_era =
+		(day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let shifted_month = (5 * day_of_year + 2) / 153;
+	let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+	let month = if shifted_month < 10 {
+		shifted_month + 3
+	} else {
+		shifted_month - 9
+	};
+	let year = year_of_era + era * 400 + u64::from(month <= 2);
+	format!(
+		"{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+		MONTHS[usize::try_from(month - 1).unwrap_or_default()],
+		secs / 3600,
+		secs / 60 % 60,
+		secs % 60
+	)
+}
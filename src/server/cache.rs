@@ -0,0 +1,249 @@
+//! Reusing the responses of targets for identical requests, in directories that opt in,
+//! and the compressed bodies and validators of static files.
+
+use std::{
+	collections::HashMap,
+	io,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant, SystemTime},
+};
+
+use crate::structures::Response;
+
+/// The cached responses, shared between every clone of a server.
+#[derive(Clone, Default)]
+pub(super) struct ResponseCache(Arc<Mutex<Entries>>);
+
+#[derive(Default)]
+struct Entries {
+	entries: HashMap<String, Entry>,
+	/// Counts up with every use, so the entry with the lowest `used` is the least recently used.
+	clock: u64,
+}
+
+struct Entry {
+	response: Response,
+	expires: Instant,
+	used: u64,
+}
+
+impl ResponseCache {
+	/// Returns a copy of the response cached under a key, unless there isn't one or it has expired.
+	/// # Panics
+	/// Panics if the lock was poisoned.
+	pub(super) fn get(&self, key: &str) -> Option<Response> {
+		let mut cache = self.0.lock().unwrap();
+		cache.clock += 1;
+		let clock = cache.clock;
+		match cache.entries.get_mut(key) {
+			Some(entry) if entry.expires > Instant::now() => {
+				entry.used = clock;
+				Some(entry.response.clone())
+			}
+			Some(_) => {
+				cache.entries.remove(key);
+				None
+			}
+			None => None,
+		}
+	}
+
+	/// Caches a response under a key for `ttl`,
+	/// evicting expired entries and then the least recently used until there's room for it in `max_entries`.
+	/// # Panics
+	/// Panics if the lock was poisoned.
+	pub(super) fn insert(
+		&self,
+		key: String,
+		response: Response,
+		ttl: Duration,
+		max_entries: usize,
+	) {
+		let Some(expires) = Instant::now().checked_add(ttl) else {
+			return;
+		};
+		if max_entries == 0 {
+			return;
+		}
+		let mut cache = self.0.lock().unwrap();
+		let now = Instant::now();
+		cache.entries.retain(|_, entry| entry.expires > now);
+		while cache.entries.len() >= max_entries && !cache.entries.contains_key(&key) {
+			let Some(oldest) = cache
+				.entries
+				.iter()
+				.min_by_key(|(_, entry)| entry.used)
+				.map(|(key, _)| key.clone())
+			else {
+				break;
+			};
+			cache.entries.remove(&oldest);
+		}
+		cache.clock += 1;
+		let used = cache.clock;
+		cache.entries.insert(
+			key,
+			Entry {
+				response,
+				expires,
+				used,
+			},
+		);
+	}
+}
+
+/// The compressed bodies of static files, shared between every clone of a server,
+/// so a file is only compressed once for each encoding until it's modified.
+#[derive(Clone, Default)]
+pub(super) struct CompressedCache(Arc<Mutex<Variants>>);
+
+/// A static file, by its path and when it was modified, and an encoding it was compressed with.
+pub(super) type VariantKey = (PathBuf, SystemTime, &'static str);
+
+#[derive(Default)]
+struct Variants {
+	entries: HashMap<VariantKey, Variant>,
+	/// How many bytes the cached bodies add up to.
+	size: usize,
+	/// Counts up with every use, so the variant with the lowest `used` is the least recently used.
+	clock: u64,
+}
+
+struct Variant {
+	body: Vec<u8>,
+	used: u64,
+}
+
+impl CompressedCache {
+	/// The most bytes of compressed bodies kept at once.
+	const MAX_SIZE: usize = 32 * 1024 * 1024;
+
+	/// Returns the compressed body cached under a key, or compresses it with `compress` and caches it,
+	/// evicting the least recently used bodies until it fits in `MAX_SIZE`.
+	/// # Panics
+	/// Panics if the lock was poisoned.
+	pub(super) fn get_or_compress(
+		&self,
+		key: VariantKey,
+		compress: impl FnOnce() -> io::Result<Vec<u8>>,
+	) -> io::Result<Vec<u8>> {
+		{
+			let mut cache = self.0.lock().unwrap();
+			cache.clock += 1;
+			let clock = cache.clock;
+			if let Some(variant) = cache.entries.get_mut(&key) {
+				variant.used = clock;
+				return Ok(variant.body.clone());
+			}
+		}
+		// Compressing doesn't hold the lock, so other files aren't kept waiting on it.
+		let body = compress()?;
+		if body.len() > Self::MAX_SIZE {
+			return Ok(body);
+		}
+		let mut cache = self.0.lock().unwrap();
+		if let Some(replaced) = cache.entries.remove(&key) {
+			cache.size -= replaced.body.len();
+		}
+		while cache.size + body.len() > Self::MAX_SIZE {
+			let Some(oldest) = cache
+				.entries
+				.iter()
+				.min_by_key(|(_, variant)| variant.used)
+				.map(|(key, _)| key.clone())
+			else {
+				break;
+			};
+			if let Some(evicted) = cache.entries.remove(&oldest) {
+				cache.size -= evicted.body.len();
+			}
+		}
+		cache.clock += 1;
+		let used = cache.clock;
+		cache.size += body.len();
+		cache.entries.insert(
+			key,
+			Variant {
+				body: body.clone(),
+				used,
+			},
+		);
+		Ok(body)
+	}
+}
+
+/// The validators of static files, shared between every clone of a server,
+/// so conditional requests arriving together share one look at the file, and its `ETag` and `Last-Modified`
+/// are only formatted again once its size or modification time changes.
+/// Each file has a lock of its own, so a slow look at one doesn't hold up requests for others.
+#[derive(Clone, Default)]
+pub(super) struct ValidatorCache(Arc<Mutex<HashMap<PathBuf, Memo>>>);
+
+/// The last look at a file, if there was one, locked while it's looked at again.
+type Memo = Arc<Mutex<Option<Validated>>>;
+
+/// What a look at a static file found.
+#[derive(Clone)]
+pub(super) struct Validated {
+	/// The file's size.
+	pub(super) len: u64,
+	/// When the file was modified, if the platform knows.
+	pub(super) modified: Option<SystemTime>,
+	/// The file's `ETag` and `Last-Modified` headers.
+	pub(super) headers: Vec<(String, String)>,
+	/// When the file was looked at.
+	checked: Instant,
+}
+
+impl ValidatorCache {
+	/// How long a look at a file is reused for conditional requests.
+	const FRESH_FOR: Duration = Duration::from_secs(1);
+	/// The most files remembered. It's only a memo, so it's emptied once it's this large.
+	const MAX_ENTRIES: usize = 10_000;
+
+	/// The validators of the file at `path`, reusing the last look at it if it's fresh and `reuse` is set
+	/// (for conditional requests, which don't read the file), and otherwise looking again with `stat`,
+	/// which gives its size and modification time.
+	/// The file's lock is held while looking, so requests for it arriving together wait for one look rather than each taking their own.
+	/// # Panics
+	/// Panics if a lock was poisoned.
+	pub(super) fn get(
+		&self,
+		path: &Path,
+		reuse: bool,
+		stat: impl FnOnce() -> io::Result<(u64, Option<SystemTime>)>,
+	) -> io::Result<Validated> {
+		let entry = {
+			let mut cache = self.0.lock().unwrap();
+			// Emptying it while a file is being looked at only costs a second look at that file.
+			if cache.len() >= Self::MAX_ENTRIES && !cache.contains_key(path) {
+				cache.clear();
+			}
+			cache.entry(path.to_path_buf()).or_default().clone()
+		};
+		let mut memo = entry.lock().unwrap();
+		let now = Instant::now();
+		if let Some(memo) = memo
+			.as_ref()
+			.filter(|memo| reuse && now.duration_since(memo.checked) < Self::FRESH_FOR)
+		{
+			return Ok(memo.clone());
+		}
+		let (len, modified) = stat()?;
+		let headers = match memo.as_ref() {
+			Some(memo) if (memo.len, memo.modified) == (len, modified) => memo.headers.clone(),
+			_ => modified
+				.map(|modified| super::validators(len, modified))
+				.unwrap_or_default(),
+		};
+		let validated = Validated {
+			len,
+			modified,
+			headers,
+			checked: now,
+		};
+		*memo = Some(validated.clone());
+		Ok(validated)
+	}
+}
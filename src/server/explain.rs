@@ -0,0 +1,120 @@
+//! Describing how a request would be resolved, for debugging a site's layout and configuration.
+
+use crate::{files::wwebs::WWebS, structures::Request};
+
+use super::{
+	forced_status, get_files_at, interpreter_for, method_answer, Handlers, Server, Target,
+};
+
+impl Server {
+	/// Walks a request's path the way `exec` would, describing each step, without running anything.
+	/// Each line says which directory was entered and what it brings (configuration and handlers),
+	/// and the last says what answers the request: a file, statically or as CGI, or one of wwebs' own responses.
+	/// Gatekeepers may still turn the request away when it's really made, since they aren't run here.
+	pub fn explain(&self, request: &mut Request) -> Vec<String> {
+		let mut steps = vec![];
+		if let Some(response) = self.preflight(request) {
+			steps.push(format!(
+				"Answered with a {} before resolving (maintenance, nesting, or a non-canonical path)",
+				response.status
+			));
+			return steps;
+		}
+		let (root, skip) = self.root_for(request);
+		if skip > 0 {
+			steps.push(format!("Aliased to {}", root.display()));
+		}
+		let mut config = WWebS::default();
+		let mut segment = skip;
+		loop {
+			let (path, exec, target) = match self.locate(request, &root, skip, segment) {
+				Ok(found) => found,
+				Err(response) => {
+					steps.push(format!(
+						"Answered with a {}, nothing can be served at {}",
+						response.status,
+						request.url.path()
+					));
+					return steps;
+				}
+			};
+			if target == Target::File {
+				let interpreter = interpreter_for(&path, &config);
+				let kind = match (exec || interpreter.is_some(), &config.fastcgi, interpreter) {
+					(false, _, _) => "served statically".to_string(),
+					(true, Some(upstream), _) => format!("run on the FastCGI upstream {upstream}"),
+					(true, None, Some(interpreter)) => format!("run as CGI through {interpreter}"),
+					(true, None, None) => "run as CGI".to_string(),
+				};
+				steps.push(format!("File {}, {kind}", path.display()));
+				steps.extend(method_step(request, &config));
+				return steps;
+			}
+
+			steps.push(format!("Directory {}", path.display()));
+			match Self::extend_config(&mut config, &path) {
+				Ok(()) if path.join(".wwebs.toml").exists() => {
+					steps.push("  applies its .wwebs.toml".to_string());
+				}
+				Ok(()) => {}
+				Err(_) if self.config().strict_config => {
+					steps.push("Answered with a 500, its .wwebs.toml is invalid".to_string());
+					return steps;
+				}
+				Err(e) => steps.push(format!("  ignores its invalid .wwebs.toml: {e}")),
+			}
+			let handlers = Handlers::in_files(&get_files_at(&path));
+			for (kind, names) in [
+				("gatekeepers", &handlers.gatekeepers),
+				("request transformers", &handlers.req_transformers),
+				("response transformers", &handlers.res_transformers),
+				("loggers", &handlers.loggers),
+			] {
+				if !names.is_empty() {
+					steps.push(format!("  {kind}: {}", names.join(", ")));
+				}
+			}
+			if let Some(forced) = forced_status(request, &config) {
+				steps.push(format!(
+					"Answered with a {} for the file's extension",
+					forced.status
+				));
+				return steps;
+			}
+			let answers_here = config.proxy_pass.is_some()
+				|| request.url.path_segments().map_or(0, Iterator::count) == segment;
+			if let Some(step) = answers_here
+				.then(|| method_step(request, &config))
+				.flatten()
+			{
+				steps.push(step);
+				return steps;
+			}
+			if let Some(upstream) = &config.proxy_pass {
+				steps.push(format!("Forwarded to the upstream {upstream}"));
+				return steps;
+			}
+			if request.url.path_segments().map_or(0, Iterator::count) == segment {
+				if let Some(answer) = self.enter_index(request, &path, &config) {
+					steps.push(format!(
+						"Answered with a {} (a redirect or directory listing)",
+						answer.status
+					));
+					return steps;
+				}
+				steps.push(format!("  rewritten to the index, {}", request.url.path()));
+			}
+			segment += 1;
+		}
+	}
+}
+
+/// Describes how a directory's `methods` answer the request instead of its target, if they do.
+fn method_step(request: &Request, config: &WWebS) -> Option<String> {
+	method_answer(request, config).map(|answer| {
+		format!(
+			"Answered with a {} for {} instead",
+			answer.status, request.verb
+		)
+	})
+}
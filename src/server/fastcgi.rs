@@ -0,0 +1,275 @@
+//! Talking to `FastCGI` upstreams, as an alternative to spawning a process for every request.
+
+use std::{
+	collections::HashMap,
+	ffi::OsString,
+	io::{self, Read, Write},
+	net::TcpStream,
+	os::unix::{ffi::OsStringExt, net::UnixStream},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use super::BodyReservation;
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+/// Every request has a connection to itself, so they can all use the same ID.
+const REQUEST_ID: u16 = 1;
+/// The most idle connections kept open to each upstream.
+const MAX_IDLE: usize = 16;
+
+/// Idle connections to `FastCGI` upstreams by address, shared between every clone of a server.
+#[derive(Clone, Default)]
+pub(super) struct FastCgiPool(Arc<Mutex<HashMap<String, Vec<Connection>>>>);
+
+/// What an upstream answered a request with.
+pub(super) struct FastCgiOutput {
+	pub stdout: Vec<u8>,
+	pub stderr: Vec<u8>,
+	/// The equivalent of a CGI binary's exit status.
+	pub app_status: u32,
+}
+
+impl FastCgiPool {
+	/// Sends a request to the upstream at `address`, which is either `unix:` followed by a socket path or a `host:port`.
+	/// Errors if the upstream hasn't answered within `timeout`, with `TimedOut` or `WouldBlock`,
+	/// or if stdout grows past `max_output` bytes. If it doesn't fit in `reservation` as it's read, it errors with `OutOfMemory`.
+	/// # Panics
+	/// Panics if the lock was poisoned.
+	pub(super) fn request(
+		&self,
+		address: &str,
+		params: &[(OsString, OsString)],
+		stdin: &[u8],
+		timeout: Option<Duration>,
+		max_output: Option<usize>,
+		reservation: &mut BodyReservation,
+	) -> io::Result<FastCgiOutput> {
+		let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+		let idle = self.0.lock().unwrap().get_mut(address).and_then(Vec::pop);
+		// An idle connection may have been closed by the upstream since, which shows before it answers anything.
+		// Those are retried on a fresh connection, while anything that fails later isn't, so requests never run twice.
+		if let Some(mut connection) = idle {
+			let mut answered = false;
+			match exchange(
+				&mut connection,
+				params,
+				stdin,
+				deadline,
+				max_output,
+				reservation,
+				&mut answered,
+			) {
+				Ok(output) => {
+					self.release(address, connection);
+					return Ok(output);
+				}
+				Err(e) if answered => return Err(e),
+				Err(_) => {}
+			}
+		}
+		let mut connection = Connection::open(address)?;
+		let output = exchange(
+			&mut connection,
+			params,
+			stdin,
+			deadline,
+			max_output,
+			reservation,
+			&mut false,
+		)?;
+		self.release(address, connection);
+		Ok(output)
+	}
+
+	/// Keeps a connection around for the next request to the same upstream, if there's room.
+	fn release(&self, address: &str, connection: Connection) {
+		let mut pool = self.0.lock().unwrap();
+		let idle = pool.entry(address.to_string()).or_default();
+		if idle.len() < MAX_IDLE {
+			idle.push(connection);
+		}
+	}
+}
+
+/// Sends a whole request over a connection and reads the whole response.
+/// `answered` is set once the upstream sends anything back.
+fn exchange(
+	connection: &mut Connection,
+	params: &[(OsString, OsString)],
+	stdin: &[u8],
+	deadline: Option<Instant>,
+	max_output: Option<usize>,
+	reservation: &mut BodyReservation,
+	answered: &mut bool,
+) -> io::Result<FastCgiOutput> {
+	connection.set_deadline(deadline)?;
+	let mut begin = RESPONDER.to_be_bytes().to_vec();
+	begin.extend([KEEP_CONN, 0, 0, 0, 0, 0]);
+	let mut encoded = vec![];
+	for (name, value) in params {
+		let (name, value) = (name.clone().into_vec(), value.clone().into_vec());
+		encode_length(&mut encoded, name.len());
+		encode_length(&mut encoded, value.len());
+		encoded.extend(name);
+		encoded.extend(value);
+	}
+	// The whole request is buffered, so it goes out in as few writes as possible.
+	let mut request = vec![];
+	push_record(&mut request, BEGIN_REQUEST, &begin);
+	push_stream(&mut request, PARAMS, &encoded);
+	push_stream(&mut request, STDIN, stdin);
+	connection.write_all(&request)?;
+
+	let mut output = FastCgiOutput {
+		stdout: vec![],
+		stderr: vec![],
+		app_status: 0,
+	};
+	loop {
+		connection.set_deadline(deadline)?;
+		let (kind, content) = read_record(connection)?;
+		*answered = true;
+		match kind {
+			STDOUT => {
+				if !reservation.try_grow(content.len()) {
+					return Err(io::Error::new(
+						io::ErrorKind::OutOfMemory,
+						"FastCGI output doesn't fit in max_buffered_bytes",
+					));
+				}
+				output.stdout.extend(content);
+				if let Some(limit) = max_output.filter(|&limit| output.stdout.len() > limit) {
+					return Err(io::Error::other(format!(
+						"FastCGI output exceeded the limit of {limit} bytes"
+					)));
+				}
+			}
+			STDERR => output.stderr.extend(content),
+			END_REQUEST if content.len() >= 5 => {
+				if content[4] != 0 {
+					return Err(io::Error::other(format!(
+						"the upstream refused the request, with protocol status {}",
+						content[4]
+					)));
+				}
+				output.app_status =
+					u32::from_be_bytes([content[0], content[1], content[2], content[3]]);
+				return Ok(output);
+			}
+			// Anything else is for management records, which are never asked for.
+			_ => {}
+		}
+	}
+}
+
+/// Appends the length of a name or value, in one byte if it fits or four otherwise.
+fn encode_length(buf: &mut Vec<u8>, len: usize) {
+	match u8::try_from(len) {
+		Ok(len) if len < 0x80 => buf.push(len),
+		#[allow(clippy::cast_possible_truncation)]
+		_ => buf.extend((len as u32 | 0x8000_0000).to_be_bytes()),
+	}
+}
+
+/// Appends a single record with the given content, which must fit in one.
+fn push_record(buf: &mut Vec<u8>, kind: u8, content: &[u8]) {
+	let [len_hi, len_lo] = u16::try_from(content.len())
+		.unwrap_or(u16::MAX)
+		.to_be_bytes();
+	let [id_hi, id_lo] = REQUEST_ID.to_be_bytes();
+	buf.extend([VERSION, kind, id_hi, id_lo, len_hi, len_lo, 0, 0]);
+	buf.extend(content);
+}
+
+/// Appends a stream as however many records it takes, then the empty record that ends it.
+fn push_stream(buf: &mut Vec<u8>, kind: u8, content: &[u8]) {
+	for chunk in content.chunks(usize::from(u16::MAX)) {
+		push_record(buf, kind, chunk);
+	}
+	push_record(buf, kind, &[]);
+}
+
+/// Reads the next record meant for this request, returning its type and content.
+fn read_record(connection: &mut Connection) -> io::Result<(u8, Vec<u8>)> {
+	loop {
+		let mut header = [0; 8];
+		connection.read_exact(&mut header)?;
+		let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+		let mut content = vec![0; len + usize::from(header[6])];
+		connection.read_exact(&mut content)?;
+		content.truncate(len);
+		if u16::from_be_bytes([header[2], header[3]]) == REQUEST_ID {
+			return Ok((header[1], content));
+		}
+	}
+}
+
+/// A connection to an upstream, over TCP or a Unix socket.
+enum Connection {
+	Tcp(TcpStream),
+	Unix(UnixStream),
+}
+
+impl Connection {
+	fn open(address: &str) -> io::Result<Connection> {
+		match address.strip_prefix("unix:") {
+			Some(path) => UnixStream::connect(path).map(Connection::Unix),
+			None => TcpStream::connect(address).map(Connection::Tcp),
+		}
+	}
+
+	/// Makes reads and writes time out once `deadline` passes.
+	fn set_deadline(&self, deadline: Option<Instant>) -> io::Result<()> {
+		let timeout = match deadline {
+			Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+				Some(left) if !left.is_zero() => Some(left),
+				_ => return Err(io::ErrorKind::TimedOut.into()),
+			},
+			None => None,
+		};
+		match self {
+			Connection::Tcp(stream) => {
+				stream.set_read_timeout(timeout)?;
+				stream.set_write_timeout(timeout)
+			}
+			Connection::Unix(stream) => {
+				stream.set_read_timeout(timeout)?;
+				stream.set_write_timeout(timeout)
+			}
+		}
+	}
+}
+
+impl Read for Connection {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Connection::Tcp(stream) => stream.read(buf),
+			Connection::Unix(stream) => stream.read(buf),
+		}
+	}
+}
+
+impl Write for Connection {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			Connection::Tcp(stream) => stream.write(buf),
+			Connection::Unix(stream) => stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Connection::Tcp(stream) => stream.flush(),
+			Connection::Unix(stream) => stream.flush(),
+		}
+	}
+}
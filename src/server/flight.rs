@@ -0,0 +1,74 @@
+//! Sharing one CGI run between identical concurrent requests.
+
+use std::{
+	collections::HashMap,
+	sync::{Arc, Condvar, Mutex},
+};
+
+use crate::structures::Response;
+
+/// The CGI runs currently in flight, shared between every clone of a server.
+#[derive(Clone, Default)]
+pub(super) struct InFlight(Arc<Mutex<HashMap<String, Arc<Flight>>>>);
+
+/// A single in-flight run that other requests can wait on.
+#[derive(Default)]
+struct Flight {
+	response: Mutex<Option<Response>>,
+	done: Condvar,
+}
+
+/// Publishes the leader's response and retires the flight, even if the leader panicked.
+struct Landing<'a> {
+	in_flight: &'a InFlight,
+	key: &'a str,
+	flight: &'a Flight,
+}
+
+impl Drop for Landing<'_> {
+	fn drop(&mut self) {
+		if let Ok(mut in_flight) = self.in_flight.0.lock() {
+			in_flight.remove(self.key);
+		}
+		if let Ok(mut response) = self.flight.response.lock() {
+			response.get_or_insert_with(Response::internal_server_error);
+		}
+		self.flight.done.notify_all();
+	}
+}
+
+impl InFlight {
+	/// Runs `f`, unless a run with the same key is already in flight,
+	/// in which case this waits for that run and returns a copy of its response.
+	/// # Panics
+	/// Panics if a lock was poisoned.
+	pub(super) fn run(&self, key: &str, f: impl FnOnce() -> Response) -> Response {
+		let (flight, leader) = {
+			let mut in_flight = self.0.lock().unwrap();
+			if let Some(flight) = in_flight.get(key) {
+				(flight.clone(), false)
+			} else {
+				let flight = Arc::new(Flight::default());
+				in_flight.insert(key.to_string(), flight.clone());
+				(flight, true)
+			}
+		};
+
+		if leader {
+			let _landing = Landing {
+				in_flight: self,
+				key,
+				flight: &flight,
+			};
+			let response = f();
+			*flight.response.lock().unwrap() = Some(response.clone());
+			response
+		} else {
+			let mut response = flight.response.lock().unwrap();
+			while response.is_none() {
+				response = flight.done.wait(response).unwrap();
+			}
+			response.clone().unwrap()
+		}
+	}
+}
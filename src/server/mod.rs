@@ -1,35 +1,271 @@
 //! The backend for wwebs.
 
+mod access_log;
+mod cache;
+mod explain;
+mod fastcgi;
+mod flight;
+mod pipes;
+mod proxy;
+mod stream;
+#[cfg(test)]
+mod tests;
+
 use std::{
+	cmp::Ordering,
 	collections::HashMap,
 	ffi::OsString,
-	os::unix::prelude::PermissionsExt,
-	path::{Path, PathBuf},
+	fmt::Write,
+	fs::File,
+	io::{self, Read, Seek, SeekFrom, Write as _},
+	net::IpAddr,
+	os::unix::{ffi::OsStringExt, fs::OpenOptionsExt, prelude::PermissionsExt},
+	path::{Component, Path, PathBuf},
+	sync::{
+		atomic::{self, AtomicBool, AtomicU64, AtomicUsize},
+		mpsc, Arc, Mutex, RwLock,
+	},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use encoding_rs::Encoding;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
 use subprocess::{Popen, PopenConfig};
+use tokio::sync::watch;
 
 use crate::{
-	files::wwebs::WWebS,
-	structures::{Request, Response},
+	files::{
+		server::ServerConfig,
+		wwebs::{CompressionInfo, Prefer, UserAgentRule, WWebS},
+	},
+	structures::{BodyStream, Request, Response, UpgradeSink},
+};
+
+#[cfg(feature = "http")]
+use self::proxy::ProxyClient;
+use self::{
+	cache::{CompressedCache, ResponseCache, ValidatorCache},
+	fastcgi::FastCgiPool,
+	flight::InFlight,
 };
 
 /// The backend server for wwebs.
 #[derive(Clone)]
 pub struct Server {
 	workdir: PathBuf,
+	config: Arc<RwLock<Arc<ServerConfig>>>,
+	in_flight: InFlight,
+	cache: ResponseCache,
+	compressed: CompressedCache,
+	validators: ValidatorCache,
+	fastcgi: FastCgiPool,
+	#[cfg(feature = "http")]
+	proxy: ProxyClient,
+	listeners: Arc<watch::Sender<usize>>,
+	/// Whether the server is shutting down.
+	shutdown: Arc<watch::Sender<bool>>,
+	/// Compiled user agent patterns, so each is only compiled once. Invalid patterns are `None`.
+	regexes: Arc<Mutex<HashMap<String, Option<Regex>>>>,
+	/// How many CGI binaries are running right now.
+	running_cgi: Arc<AtomicUsize>,
+	connections: Arc<Connections>,
+	/// How many bytes of request and response bodies are held in memory right now.
+	buffered: Arc<AtomicUsize>,
+	/// The running CGI binaries, by a token for each run.
+	children: Children,
+	gatekeepers: Arc<RwLock<Vec<Arc<Gatekeeper>>>>,
 }
 
+/// A gatekeeper implemented in Rust, for embedding wwebs.
+/// Like gatekeeper files, returning a response that isn't OK rejects the request with it,
+/// while returning `None` (or an OK response) lets the request through.
+pub type Gatekeeper = dyn Fn(&Request) -> Option<Response> + Send + Sync;
+
 impl Server {
 	/// Creates the `DefaultBackend` with a given working directory.
 	#[must_use]
 	pub fn new(path: PathBuf) -> Server {
-		Server { workdir: path }
+		Self::with_config(path, ServerConfig::default())
+	}
+
+	/// Creates the `DefaultBackend` with a given working directory and server configuration.
+	#[must_use]
+	pub fn with_config(path: PathBuf, config: ServerConfig) -> Server {
+		Server {
+			workdir: path,
+			config: Arc::new(RwLock::new(Arc::new(config))),
+			in_flight: InFlight::default(),
+			cache: ResponseCache::default(),
+			compressed: CompressedCache::default(),
+			validators: ValidatorCache::default(),
+			fastcgi: FastCgiPool::default(),
+			#[cfg(feature = "http")]
+			proxy: ProxyClient::default(),
+			listeners: Arc::new(watch::channel(0).0),
+			shutdown: Arc::new(watch::channel(false).0),
+			regexes: Arc::default(),
+			running_cgi: Arc::default(),
+			children: Arc::default(),
+			connections: Arc::default(),
+			buffered: Arc::default(),
+			gatekeepers: Arc::default(),
+		}
+	}
+
+	/// Adds an in-process gatekeeper, for every clone of this server.
+	/// In-process gatekeepers run before any gatekeeper files, once per request.
+	/// # Panics
+	/// Panics if the gatekeeper lock was poisoned.
+	pub fn add_gatekeeper(&self, gatekeeper: Box<Gatekeeper>) {
+		self.gatekeepers
+			.write()
+			.unwrap()
+			.push(Arc::from(gatekeeper));
+	}
+
+	/// Returns the directory the server serves.
+	#[must_use]
+	pub fn workdir(&self) -> &Path {
+		&self.workdir
+	}
+
+	/// Returns a snapshot of the current server configuration.
+	/// # Panics
+	/// Panics if the configuration lock was poisoned.
+	#[must_use]
+	pub fn config(&self) -> Arc<ServerConfig> {
+		self.config.read().unwrap().clone()
+	}
+
+	/// Atomically replaces the server configuration, for every clone of this server.
+	/// Requests already in flight keep the snapshot they started with.
+	/// # Panics
+	/// Panics if the configuration lock was poisoned.
+	pub fn set_config(&self, config: ServerConfig) {
+		*self.config.write().unwrap() = Arc::new(config);
+	}
+
+	/// Re-reads the server configuration from a file and applies it.
+	/// If the file can't be read or parsed, the old configuration is kept.
+	/// # Errors
+	/// Errors if the new configuration couldn't be loaded.
+	pub fn reload_config(&self, path: &Path) -> anyhow::Result<()> {
+		let config = ServerConfig::load(path)?;
+		self.set_config(config);
+		Ok(())
+	}
+
+	/// Records that a protocol has bound its listener.
+	/// Protocols should call this once they are ready to accept connections.
+	pub fn listener_bound(&self) {
+		self.listeners.send_modify(|count| *count += 1);
+	}
+
+	/// Waits until at least `count` protocol listeners are bound.
+	pub async fn listeners_bound(&self, count: usize) {
+		let mut listeners = self.listeners.subscribe();
+		// The sender lives as long as `self`, so this can't fail.
+		let _ = listeners.wait_for(|bound| *bound >= count).await;
+	}
+
+	/// Tells every protocol to stop taking new connections, and to return from `run` once it has answered the requests it's working on.
+	pub fn shut_down(&self) {
+		self.shutdown.send_replace(true);
+	}
+
+	/// Waits until the server starts shutting down, which protocols should stop accepting connections at.
+	pub async fn shutting_down(&self) {
+		let mut shutdown = self.shutdown.subscribe();
+		// The sender lives as long as `self`, so this can't fail.
+		let _ = shutdown.wait_for(|shutting_down| *shutting_down).await;
+	}
+
+	/// Records that a protocol accepted a connection, which stays open until the returned guard is dropped.
+	/// Returns `None` if `max_connections` are already open, in which case the connection should be turned away.
+	#[must_use]
+	pub fn open_connection(&self) -> Option<ConnectionGuard> {
+		let max = self.config().limits.max_connections.unwrap_or(usize::MAX);
+		self.connections
+			.active
+			.fetch_update(
+				atomic::Ordering::SeqCst,
+				atomic::Ordering::SeqCst,
+				|active| (active < max).then_some(active + 1),
+			)
+			.ok()?;
+		self.connections
+			.accepted
+			.fetch_add(1, atomic::Ordering::SeqCst);
+		Some(ConnectionGuard(self.connections.clone()))
+	}
+
+	/// Returns how many connections are open right now.
+	#[must_use]
+	pub fn active_connections(&self) -> usize {
+		self.connections.active.load(atomic::Ordering::SeqCst)
+	}
+
+	/// Returns how many connections have been accepted since the server started, not counting any turned away.
+	#[must_use]
+	pub fn accepted_connections(&self) -> usize {
+		self.connections.accepted.load(atomic::Ordering::SeqCst)
+	}
+
+	/// Accounts for a body a protocol is holding in memory, until the returned reservation is dropped.
+	/// Returns `None` if that would take the total past `max_buffered_bytes`, in which case the request should be turned away.
+	#[must_use]
+	pub fn reserve_body(&self, bytes: usize) -> Option<BodyReservation> {
+		let mut reservation = BodyReservation {
+			buffered: self.buffered.clone(),
+			max: self
+				.config()
+				.limits
+				.max_buffered_bytes
+				.unwrap_or(usize::MAX),
+			bytes: 0,
+		};
+		reservation.try_grow(bytes).then_some(reservation)
+	}
+
+	/// Returns how many bytes of bodies the protocols are holding in memory right now.
+	#[must_use]
+	pub fn buffered_bytes(&self) -> usize {
+		self.buffered.load(atomic::Ordering::SeqCst)
+	}
+
+	/// Finds the address of the client that made a request.
+	/// If the request came through one of the `trusted_proxies`, this is the address they forwarded in `X-Forwarded-For`,
+	/// skipping any other trusted proxies along the way.
+	#[must_use]
+	pub fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+		let peer = request.remote_addr?.ip();
+		let server_config = self.config();
+		let trusted = server_config.trusted_proxies.as_deref().unwrap_or_default();
+		if !trusted.contains(&peer) {
+			return Some(peer);
+		}
+		let Some(forwarded) = request.header("X-Forwarded-For") else {
+			return Some(peer);
+		};
+		// Each proxy appends the address it got the request from, so the last untrusted one is the client.
+		let mut client = peer;
+		for hop in forwarded.rsplit(',') {
+			let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+				break;
+			};
+			client = hop;
+			if !trusted.contains(&hop) {
+				break;
+			}
+		}
+		Some(client)
 	}
 
 	/// Run a CGI binary. Don't call this on a static file, it won't go well.
 	/// # Panics
-	/// Panics if the path is empty.
+	/// Panics if the path is empty, or outside the root the request resolves in.
 	#[must_use]
 	pub fn run_cgi(
 		&self,
@@ -38,366 +274,1282 @@ impl Server {
 		config: &WWebS,
 		query_strings: &HashMap<String, String>,
 	) -> Response {
-		// Make path relative
-		let rel_path = path.strip_prefix(&self.workdir).unwrap();
-		// Determine the path "inside" the target CGI binary
-		let inside_path: PathBuf =
-			if rel_path.components().count() < request.url.path_segments().unwrap().count() {
-				// There is indeed a path inside the CGI binary
-				let mut path_starts = rel_path.components().count();
-				let last_component = rel_path
-					.components()
-					.last()
-					.unwrap()
-					.as_os_str()
-					.to_string_lossy()
-					.to_string();
-				if [
-					".gatekeeper",
-					".req_transformer",
-					".logger",
-					".res_transformer",
-				]
-				.contains(&last_component.as_str())
-				{
-					path_starts -= 1;
-				}
-
-				request
-					.url
-					.path_segments()
-					.unwrap()
-					.skip(path_starts)
-					.collect()
-			} else {
-				PathBuf::default()
-			};
-		let inside_path = inside_path.to_string_lossy().to_string();
-
-		let p = Popen::create(
-			&[path.to_string_lossy().to_string(), inside_path],
-			PopenConfig {
-				stdin: subprocess::Redirection::Pipe,
-				stdout: subprocess::Redirection::Pipe,
-				stderr: subprocess::Redirection::Pipe,
-				cwd: Some(path.parent().unwrap().as_os_str().to_os_string()),
-				env: Some({
-					let mut env: Vec<(OsString, OsString)> = vec![];
-					env.push(("PROTO".into(), request.proto.into()));
-					for (k, v) in &request.headers {
-						env.push((("HEADER_".to_string() + k).into(), v.into()));
-					}
-					for (k, v) in query_strings {
-						env.push((("QUERY_".to_string() + k).into(), v.into()));
-					}
-					env.push(("VERB".into(), request.verb.clone().into()));
-					env.push(("REQUESTED".into(), request.url.path().into()));
-					for (k, v) in config.env.as_ref().unwrap_or(&HashMap::default()) {
-						env.push((k.into(), v.clone().into()));
-					}
-					if let Ok(path) = std::env::var("PATH") {
-						env.push(("PATH".into(), path.into()));
-					}
-					env
-				}),
+		// The output is held in memory as it's read, so there has to be room for it.
+		let Some(mut reservation) = self.reserve_body(0) else {
+			return Response {
+				status: 503,
 				..Default::default()
-			},
-		);
-
-		if let Err(e) = p {
-			eprintln!("{}", e);
-			return Response::internal_server_error();
+			};
+		};
+		if let Some(upstream) = &config.fastcgi {
+			return self.run_fastcgi(
+				request,
+				path,
+				config,
+				query_strings,
+				upstream,
+				&mut reservation,
+			);
 		}
-
-		let mut p = p.unwrap();
+		let mut cgi = match self.spawn_cgi(request, path, config, query_strings) {
+			Ok(cgi) => cgi,
+			Err(response) => return response,
+		};
 
 		// Write the request body, and store the response.
-		let (stdout, stderr) = match p.communicate_bytes(Some(&request.body)) {
-			Ok((a, b)) => (a.unwrap_or_default(), b.unwrap_or_default()),
+		let (stdout, stderr) = match pipes::communicate(
+			&mut cgi.p,
+			&request.body,
+			config.stdin_timeout,
+			self.config().limits.max_response_size,
+			reservation,
+		) {
+			Ok(output) => output,
+			Err(e) if e.kind() == io::ErrorKind::OutOfMemory => {
+				tracing::warn!("Refusing {}: {e}", path.display());
+				return Response {
+					status: 503,
+					..Default::default()
+				};
+			}
 			Err(e) => {
-				eprintln!("{:?}", e);
+				tracing::error!("Failed to run {}: {e}", path.display());
 				return Response::internal_server_error();
 			}
 		};
 
 		// Wait for p to exit...
-		let exit_status = p.wait().unwrap_or(subprocess::ExitStatus::Exited(500));
+		let exit_status = cgi.p.wait().unwrap_or(subprocess::ExitStatus::Exited(500));
+		if cgi.timed_out() {
+			return Response {
+				status: 504,
+				..Default::default()
+			};
+		}
 
 		// Build the response.
 		let mut response = Response {
-			status: match exit_status {
-				subprocess::ExitStatus::Exited(0) => 200,
-				#[allow(clippy::cast_possible_truncation)]
-				subprocess::ExitStatus::Exited(n) => n as u16,
-				v => {
-					eprintln!("{:?}", v);
-					500
-				}
-			},
-			headers: HashMap::default(),
+			status: self.exit_status_code(path, exit_status),
 			body: stdout,
+			external: true,
+			..Default::default()
 		};
 
 		// Parse the stderr...
-		parse_output_commands(&stderr, &mut response);
+		parse_output_commands(&stderr, &mut response, request, path);
 
 		response
 	}
 
-	/// Execute a given path segment from a request.
-	/// Recursively calls itself until we hit the final `run_cgi`.
-	/// # Panics
-	/// Panics when the url is a non-base url, which should never happen.
-	#[must_use]
-	pub fn exec(&self, request: &mut Request, segment: usize, config: &mut WWebS) -> Response {
-		let path: PathBuf = request
-			.url
-			.path_segments()
-			.expect("Unexpected cannot-be-a-base url")
-			.take(segment)
-			.map(|segment| {
-				if let Some(percent_index) = segment.find('%') {
-					&segment[..percent_index]
-				} else {
-					segment
-				}
-			})
-			.collect();
-
-		// Make the path absolute
-		let path = self.workdir.join(path);
-		let mut config = config.clone();
-
-		// Get query strings
-		let query_strings: HashMap<String, String> = request
-			.url
-			.query_pairs()
-			.map(|(a, b)| (a.to_string(), b.to_string()))
-			.collect();
-
-		// Check that the path exists.
-		if !path.exists() {
+	/// Runs dynamic content on a `FastCGI` upstream instead of spawning it, with the same environment as params.
+	/// The upstream also gets the content's file as `SCRIPT_FILENAME`.
+	/// Its output is reserved for in `reservation` as it arrives.
+	fn run_fastcgi(
+		&self,
+		request: &Request,
+		path: &Path,
+		config: &WWebS,
+		query_strings: &HashMap<String, String>,
+		upstream: &str,
+		reservation: &mut BodyReservation,
+	) -> Response {
+		// Nobody would see the response.
+		if request.cancellation.is_cancelled() {
 			return Response {
-				status: 404,
+				status: 503,
 				..Default::default()
 			};
 		}
-
-		// Check that the path is allowed, and maybe executable.
-		let (allowed, exec) = {
-			let path = path.clone();
-			let allowed_res: anyhow::Result<(bool, bool)> = (|| {
-				let meta = std::fs::metadata(path)?;
-				let permissions = meta.permissions();
-				let mode = permissions.mode();
-				Ok(((mode & 0o004) > 0, (mode & 0o001) > 0))
-			})();
-			allowed_res.unwrap_or((false, false))
-		};
-		if !allowed {
-			return Response {
-				status: 404,
-				..Default::default()
-			};
+		let mut extras: Vec<(OsString, OsString)> = vec![
+			("SCRIPT_FILENAME".into(), path.into()),
+			(
+				"CONTENT_LENGTH".into(),
+				request.body.len().to_string().into(),
+			),
+		];
+		extras.extend(self.script_env(request, path));
+		if let Some(ip) = self.client_ip(request) {
+			extras.push(("REMOTE_ADDR".into(), ip.to_string().into()));
 		}
-
-		// Allocate the response
-		let mut response: Response = Response::default();
-
-		// Get the files in the directory
-		let files: Vec<String> = get_files_at(&path);
-
-		// If the path is a dir, perform all pre-request scoped operations.
-		if path.is_dir() {
-			// Extend config if possible
-			Self::extend_config(&mut config, &path);
-			// Evaluate all of the gatekeepers
-			self.eval_gatekeepers(
-				&files,
-				&path,
-				request,
-				&config,
-				&mut response,
-				&query_strings,
-			);
-			// Execute all of the request transformers, but only if the response isn't already bad.
-			if response.is_ok() {
-				self.eval_req_transformers(&files, &path, request, &config, &query_strings);
+		let params = build_cgi_env(request, config, query_strings, extras);
+		let output = self.fastcgi.request(
+			upstream,
+			&params,
+			&request.body,
+			config.timeout,
+			self.config().limits.max_response_size,
+			reservation,
+		);
+		match output {
+			Ok(output) => {
+				let mut response = Response {
+					status: Response::status_for_exit_code(output.app_status),
+					body: output.stdout,
+					external: true,
+					..Default::default()
+				};
+				parse_output_commands(&output.stderr, &mut response, request, path);
+				response
 			}
-			// If the target is a directory and we are at the end, rewrite it to use the index.
-			if response.is_ok()
-				&& request.url.path_segments().unwrap().count() == segment
-				&& path.is_dir()
+			Err(e) if e.kind() == io::ErrorKind::OutOfMemory => {
+				tracing::warn!("Refusing {}: {e}", path.display());
+				Response {
+					status: 503,
+					..Default::default()
+				}
+			}
+			Err(e)
+				if matches!(
+					e.kind(),
+					io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+				) =>
 			{
-				let index = config
-					.clone()
-					.resolution
-					.and_then(|v| v.index)
-					.unwrap_or_else(|| "index.html".to_string());
-				request.url.path_segments_mut().unwrap().push(&index);
-			}
-		}
-		// Evaluate the target, but only if the request isn't already bad.
-		if response.is_ok() {
-			// Is the target a file?
-			if path.is_file() {
-				response = self.run_file(exec, &path, request, &config, &query_strings);
-			} else {
-				// The target is a directory, so we move into it.
-				response = self.exec(request, segment + 1, &mut config);
+				tracing::warn!(
+					"The FastCGI upstream {upstream} timed out on {}",
+					path.display()
+				);
+				Response {
+					status: 504,
+					..Default::default()
+				}
+			}
+			Err(e) => {
+				tracing::error!(
+					"The FastCGI upstream {upstream} failed on {}: {e}",
+					path.display()
+				);
+				Response {
+					status: 502,
+					..Default::default()
+				}
 			}
 		}
-		if path.is_dir() {
-			self.eval_res_transformers(
-				&files,
-				&path,
-				&config,
-				&mut response,
-				request,
-				&query_strings,
-			);
-		}
-		if response.status == 0 {
-			response.status = 200;
-		}
-		// Run the loggers.
-		self.run_loggers(&files, &path, &config, &response, request, &query_strings);
-		response
 	}
 
-	fn run_loggers(
+	/// Runs a CGI binary like `run_cgi`, but streams its output instead of waiting for all of it.
+	/// The binary's output commands have to come first, ended by a blank line on stderr (or by closing it),
+	/// since they're parsed before the body starts flowing. Its exit status isn't known in time to be the response's,
+	/// so the status defaults to 200 unless a `status` command says otherwise.
+	/// # Panics
+	/// Panics if the path is empty, or outside the root the request resolves in.
+	#[must_use]
+	pub fn stream_cgi(
 		&self,
-		files: &[String],
+		request: &mut Request,
 		path: &Path,
 		config: &WWebS,
-		response: &Response,
-		request: &mut Request,
 		query_strings: &HashMap<String, String>,
-	) {
-		// Get the list of loggers.
-		let mut loggers: Vec<&String> = files.iter().filter(|v| v.starts_with(".logger")).collect();
-		loggers.sort();
-		// Execute all of the response transformers.
-		for logger in loggers {
-			let path = path.join(logger);
-			let mut extended_config = config.clone();
-			extended_config
-				.env
-				.get_or_insert(HashMap::default())
-				.insert("STATUS".to_string(), response.status.to_string());
-			let _res = self.run_cgi(&mut request.clone(), &path, &extended_config, query_strings);
+	) -> Response {
+		let mut cgi = match self.spawn_cgi(request, path, config, query_strings) {
+			Ok(cgi) => cgi,
+			Err(response) => return response,
+		};
+		if let Some(stdin) = cgi.p.stdin.take() {
+			pipes::feed(stdin, request.body.clone(), config.stdin_timeout);
+		}
+		let commands = match cgi
+			.p
+			.stderr
+			.take()
+			.map(|stderr| pipes::read_commands(stderr, log_context(request, path)))
+		{
+			Some(Ok(commands)) => commands,
+			Some(Err(e)) => {
+				tracing::error!("Failed to run {}: {e}", path.display());
+				return Response::internal_server_error();
+			}
+			None => vec![],
+		};
+		let mut response = Response {
+			status: 200,
+			external: true,
+			..Default::default()
+		};
+		parse_output_commands(&commands, &mut response, request, path);
+		if let Some(stdout) = cgi.p.stdout.take() {
+			let limit = self.config().limits.max_response_size;
+			response.stream = Some(BodyStream::new(stream::CgiOutput::new(
+				stdout,
+				cgi,
+				path.to_path_buf(),
+				limit,
+			)));
 		}
+		response
 	}
 
-	fn eval_res_transformers(
+	/// Starts a CGI binary to handle a WebSocket, for the protocol to relay over its stdin and stdout.
+	/// The response switches protocols, with the binary's stdout as its `stream` and its stdin as its `upgrade`.
+	/// Only `log` commands on stderr do anything, since there's no response for the others to change.
+	fn websocket_cgi(
 		&self,
-		files: &[String],
+		request: &mut Request,
 		path: &Path,
 		config: &WWebS,
-		response: &mut Response,
-		request: &Request,
 		query_strings: &HashMap<String, String>,
-	) {
-		// Get the list of response transformers.
-		let mut res_transformers: Vec<&String> = files
-			.iter()
-			.filter(|v| v.starts_with(".res_transformer"))
-			.collect();
-		res_transformers.sort();
-		// Execute all of the response transformers.
-		for transformer in res_transformers {
-			let path = path.join(transformer);
-			let mut extended_config = config.clone();
-			let env = extended_config.env.get_or_insert(HashMap::default());
-			env.insert("STATUS".to_string(), response.status.to_string());
-			let request = Request {
-				proto: request.proto,
-				verb: "GET".to_string(),
-				url: request.url.clone(),
-				headers: response.headers.clone(),
-				body: response.body.clone(),
-			};
-			let res = self.run_cgi(&mut request.clone(), &path, &extended_config, query_strings);
-			response.body = res.body;
-			for (k, v) in res.headers {
-				if v.is_empty() {
-					response.headers.remove(&k);
-				} else {
-					response.headers.insert(k, v);
-				}
-			}
-			response.status = res.status;
+	) -> Response {
+		let mut cgi = match self.spawn_cgi(request, path, config, query_strings) {
+			Ok(cgi) => cgi,
+			Err(response) => return response,
+		};
+		if let Some(stderr) = cgi.p.stderr.take() {
+			pipes::log_commands(stderr, log_context(request, path));
+		}
+		let (Some(stdin), Some(stdout)) = (cgi.p.stdin.take(), cgi.p.stdout.take()) else {
+			return Response::internal_server_error();
+		};
+		Response {
+			status: 101,
+			stream: Some(BodyStream::new(stream::CgiOutput::new(
+				stdout,
+				cgi,
+				path.to_path_buf(),
+				None,
+			))),
+			upgrade: Some(UpgradeSink::new(stdin)),
+			..Default::default()
 		}
 	}
 
-	fn run_file(
+	/// Starts a CGI binary, with its request's environment and its pipes open,
+	/// unless its request was cancelled or too many CGIs are running already.
+	// Errors are rare, so the response isn't worth boxing.
+	#[allow(clippy::result_large_err)]
+	fn spawn_cgi(
 		&self,
-		exec: bool,
-		path: &Path,
 		request: &mut Request,
+		path: &Path,
 		config: &WWebS,
 		query_strings: &HashMap<String, String>,
-	) -> Response {
-		// Is the file static?
-		match exec {
-			false => {
-				// Try to read the static file.
-				if let Ok(data) = std::fs::read(path) {
-					Response {
-						status: 200,
-						body: data,
-						headers: HashMap::default(),
+	) -> Result<RunningCgi, Response> {
+		// Nobody would see the response.
+		if request.cancellation.is_cancelled() {
+			return Err(Response {
+				status: 503,
+				..Default::default()
+			});
+		}
+
+		let inside_path = self.inside_path(request, path);
+
+		let Some(permit) = self.cgi_permit() else {
+			tracing::warn!(
+				"Refusing to run {}, too many CGIs are running",
+				path.display()
+			);
+			return Err(Response {
+				status: 503,
+				..Default::default()
+			});
+		};
+
+		// Files that can't run themselves go through their extension's interpreter, which gets the file's path first.
+		let mut argv = vec![path.to_string_lossy().to_string(), inside_path];
+		if let Some(interpreter) = interpreter_for(path, config) {
+			argv.insert(0, interpreter.to_string());
+		}
+		let p = Popen::create(
+			&argv,
+			PopenConfig {
+				stdin: subprocess::Redirection::Pipe,
+				stdout: subprocess::Redirection::Pipe,
+				stderr: subprocess::Redirection::Pipe,
+				cwd: Some(path.parent().unwrap().as_os_str().to_os_string()),
+				// A process group of its own, so anything it spawns can be killed along with it.
+				setpgid: true,
+				env: Some({
+					let mut extras = self.script_env(request, path).to_vec();
+					if let Some(path) = std::env::var_os("PATH") {
+						extras.push(("PATH".into(), path));
 					}
-				} else {
-					Response {
-						status: 500,
-						..Default::default()
+					if let Some(ip) = self.client_ip(request) {
+						extras.push(("REMOTE_ADDR".into(), ip.to_string().into()));
 					}
+					build_cgi_env(request, config, query_strings, extras)
+				}),
+				..Default::default()
+			},
+		);
+
+		let p = match p {
+			Ok(p) => p,
+			Err(e) => {
+				tracing::error!("Failed to start {}: {e}", path.display());
+				return Err(Response::internal_server_error());
+			}
+		};
+		let child = p
+			.pid()
+			.map(|pid| ChildGuard::new(&self.children, pid, request.url.path()));
+		if let Some(token) = child.as_ref().map(|child| child.token) {
+			let children = self.children.clone();
+			request.cancellation.on_cancel(move || {
+				// The child may have finished long ago, in which case it's not listed anymore.
+				if let Some(child) = children.lock().unwrap().get(&token) {
+					tracing::warn!(
+						"Killing the CGI for {}, its request was cancelled",
+						child.path
+					);
+					kill_process_group(child.pid);
 				}
+			});
+		}
+		let watchdog = config
+			.timeout
+			.zip(child.as_ref())
+			.map(|(timeout, child)| Watchdog::start(&self.children, child.token, timeout));
+		Ok(RunningCgi {
+			path: path.to_path_buf(),
+			p,
+			watchdog,
+			_child: child,
+			_permit: permit,
+		})
+	}
+
+	/// Turns how a CGI binary exited into the status of its response.
+	fn exit_status_code(&self, path: &Path, exit_status: subprocess::ExitStatus) -> u16 {
+		match exit_status {
+			subprocess::ExitStatus::Exited(code) => Response::status_for_exit_code(code),
+			subprocess::ExitStatus::Signaled(signal) => {
+				tracing::error!(
+					"{} was killed by {}",
+					path.display(),
+					describe_signal(signal.into())
+				);
+				self.config().signal_status.unwrap_or(500)
+			}
+			v => {
+				tracing::error!("{} exited unexpectedly: {v:?}", path.display());
+				500
 			}
-			true => self.run_cgi(request, path, config, query_strings),
 		}
 	}
 
-	fn eval_gatekeepers(
-		&self,
-		files: &[String],
-		path: &Path,
-		request: &Request,
-		config: &WWebS,
-		response: &mut Response,
-		query_strings: &HashMap<String, String>,
-	) {
-		// Get the list of gatekeepers
-		let mut gatekeepers: Vec<&String> = files
-			.iter()
-			.filter(|v| v.starts_with(".gatekeeper"))
+	/// Determines the path "inside" a CGI binary, which is what's left of the request after the binary.
+	fn inside_path(&self, request: &Request, path: &Path) -> String {
+		let inside_path: PathBuf = request
+			.url
+			.path_segments()
+			.unwrap()
+			.skip(self.script_segments(request, path))
 			.collect();
-		gatekeepers.sort();
-		// Execute all of the gatekeepers.
-		for gatekeeper in gatekeepers {
-			let path = path.join(gatekeeper);
-			let res = self.run_cgi(&mut request.clone(), &path, config, query_strings);
-			if !res.is_ok() {
-				*response = res;
-			}
+		inside_path.to_string_lossy().to_string()
+	}
+
+	/// Counts the segments of a request's path that lead to a CGI binary, rather than being inside it.
+	/// Handlers aren't in the path themselves, so only their directory counts.
+	fn script_segments(&self, request: &Request, path: &Path) -> usize {
+		// Make path relative to the root it was resolved in
+		let (root, skip) = self.root_for(request);
+		let rel_path = path.strip_prefix(&root).unwrap();
+		let consumed = skip + rel_path.components().count();
+		let count = request.url.path_segments().unwrap().count();
+		if consumed >= count {
+			return count;
+		}
+		let last_component = rel_path
+			.components()
+			.next_back()
+			.unwrap()
+			.as_os_str()
+			.to_string_lossy()
+			.to_string();
+		// Handlers (numbered or not) aren't part of the URL, so they consume no segment of their own.
+		if HANDLER_PREFIXES
+			.iter()
+			.any(|prefix| last_component.starts_with(prefix))
+		{
+			consumed - 1
+		} else {
+			consumed
 		}
 	}
 
-	fn eval_req_transformers(
+	/// The `SCRIPT_NAME` and `PATH_INFO` of a CGI binary, decoded, as traditional CGI programs expect them.
+	/// For `/script.cgi/extra/path`, they're `/script.cgi` and `/extra/path`.
+	fn script_env(&self, request: &Request, path: &Path) -> [(OsString, OsString); 2] {
+		let script = request
+			.url
+			.path_segments()
+			.unwrap()
+			.take(self.script_segments(request, path))
+			.fold(String::new(), |script, segment| script + "/" + segment);
+		let path_info = request.url.path().strip_prefix(&script).unwrap_or_default();
+		let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().to_string();
+		[
+			("SCRIPT_NAME".into(), decode(&script).into()),
+			("PATH_INFO".into(), decode(path_info).into()),
+		]
+	}
+
+	/// Waits for running CGI binaries to finish, for up to the configured `shutdown_grace`.
+	/// Any still running afterwards are killed, and logged with the path they were serving.
+	/// Call this once the protocols have stopped taking new requests.
+	/// # Panics
+	/// Panics if the child list lock was poisoned.
+	pub async fn finish_cgi(&self) {
+		let grace = self
+			.config()
+			.shutdown_grace
+			.unwrap_or(Duration::from_secs(30));
+		let deadline = tokio::time::Instant::now() + grace;
+		while !self.children.lock().unwrap().is_empty() && tokio::time::Instant::now() < deadline {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+		for child in self.children.lock().unwrap().values() {
+			tracing::warn!(
+				"Killing the CGI for {}, it didn't finish in time",
+				child.path
+			);
+			kill_process_group(child.pid);
+		}
+	}
+
+	/// Lists the handlers in a directory, relative to the working directory, in the order they run.
+	/// This only reads the directory, nothing is executed.
+	#[must_use]
+	pub fn handlers_at(&self, path: &Path) -> Handlers {
+		Handlers::in_files(&get_files_at(&self.workdir.join(path)))
+	}
+
+	/// Claims a slot to run a CGI binary in, unless `max_concurrent_cgi` are already running.
+	fn cgi_permit(&self) -> Option<CgiPermit> {
+		let max = self
+			.config()
+			.limits
+			.max_concurrent_cgi
+			.unwrap_or(usize::MAX);
+		self.running_cgi
+			.fetch_update(
+				atomic::Ordering::SeqCst,
+				atomic::Ordering::SeqCst,
+				|running| (running < max).then_some(running + 1),
+			)
+			.ok()?;
+		Some(CgiPermit(self.running_cgi.clone()))
+	}
+
+	/// Resolves a request like a `GET` would, but returns only its status and headers.
+	/// Static files aren't read; `Content-Length` still reports what the body would have been.
+	/// # Panics
+	/// Panics when the url is a non-base url, which should never happen.
+	#[must_use]
+	pub fn head(&self, request: &mut Request) -> Response {
+		request.verb = "HEAD".to_string();
+		let mut response = self.exec(request, 0, &mut WWebS::default());
+		// A streamed body has to be read through to know how long it would have been.
+		if let Err(e) = response.buffer_stream() {
+			tracing::error!(
+				"Failed to read the output for HEAD {}: {e}",
+				request.url.path()
+			);
+			return Response::internal_server_error();
+		}
+		if get_header(&response.headers, "Content-Length").is_none() {
+			response.headers.insert(
+				"Content-Length".to_string(),
+				response.body.len().to_string(),
+			);
+		}
+		response.body.clear();
+		response
+	}
+
+	/// Execute a given path segment from a request.
+	/// Recursively resolves each segment until we hit the final `run_cgi`.
+	/// # Panics
+	/// Panics when the url is a non-base url, which should never happen.
+	#[must_use]
+	pub fn exec(&self, request: &mut Request, segment: usize, config: &mut WWebS) -> Response {
+		// Everything logged while answering a request is in its span, so it's clear which request it was about.
+		let span = (segment == 0).then(|| {
+			tracing::info_span!(
+				"request",
+				verb = %request.verb,
+				url = %request.url,
+				path = tracing::field::Empty,
+				status = tracing::field::Empty,
+			)
+		});
+		let _entered = span.as_ref().map(tracing::Span::enter);
+		let mut timings = Timings::default();
+		// Resolving may rewrite the URL (like for an index), but the log should show what was asked for.
+		let url = request.url.clone();
+		let mut response = self.resolve(request, segment, config, &mut timings);
+		if segment == 0 {
+			self.apply_error_page(request, &mut response);
+			compress(request, &mut response, &self.compressed);
+		}
+		// The breakdown is only complete for the request as a whole.
+		if segment == 0 && self.config().server_timing {
+			response
+				.headers
+				.insert("Server-Timing".to_string(), timings.header_value());
+		}
+		let access_log = response
+			.directory
+			.as_ref()
+			.and_then(|d| d.access_log.as_ref());
+		if let (0, Some(path)) = (segment, access_log) {
+			access_log::append(path, self.client_ip(request), &url, request, &response);
+		}
+		if let Some(span) = &span {
+			span.record("status", response.status);
+		}
+		response
+	}
+
+	/// Replaces the body of an error response with the configured error page for its status, if there is one.
+	/// Only wwebs' own bodies are replaced, never one a CGI wrote.
+	fn apply_error_page(&self, request: &Request, response: &mut Response) {
+		let default_body = Response::internal_server_error().body;
+		if response.status < 400
+			|| request.proto == "Gemini"
+			|| (response.has_body() && response.body != default_body)
+		{
+			return;
+		}
+		let status = response.status.to_string();
+		// The answering directory's page wins, and the server's is the fallback if it has none or it can't be read.
+		let directory_page = response
+			.directory
+			.as_ref()
+			.and_then(|directory| directory.errors.as_ref()?.get(&status).cloned());
+		let server_page = self
+			.config()
+			.error_pages
+			.as_ref()
+			.and_then(|pages| pages.get(&status))
+			.map(|page| self.workdir.join(page));
+		for page in directory_page.into_iter().chain(server_page) {
+			match std::fs::read(&page) {
+				Ok(body) => {
+					if page
+						.extension()
+						.is_some_and(|ext| ext == "html" || ext == "htm")
+					{
+						response.headers.insert(
+							"Content-Type".to_string(),
+							"text/html; charset=utf-8".to_string(),
+						);
+					}
+					response.body = body;
+					return;
+				}
+				Err(e) => tracing::error!("Failed to read the error page {}: {e}", page.display()),
+			}
+		}
+	}
+
+	/// Resolves a single path segment, adding the time spent in each stage to `timings`.
+	fn resolve(
 		&self,
-		files: &[String],
+		request: &mut Request,
+		segment: usize,
+		config: &mut WWebS,
+		timings: &mut Timings,
+	) -> Response {
+		if segment == 0 {
+			if let Some(response) = self.preflight(request) {
+				return response;
+			}
+		}
+
+		// Aliased paths start resolving at the alias' own root, skipping the workdir entirely.
+		let (root, skip) = self.root_for(request);
+		if segment < skip {
+			return self.resolve(request, skip, config, timings);
+		}
+
+		let (path, exec, target) = match self.locate(request, &root, skip, segment) {
+			Ok(found) => found,
+			Err(mut response) => {
+				problem_json(request, config, &mut response);
+				response.directory = Some(Box::new(config.clone()));
+				return response;
+			}
+		};
+		let mut config = config.clone();
+
+		// Get query strings
+		let query_strings = request.query();
+
+		// Allocate the response, and whether it's the final answer even if it's OK (like a directory listing).
+		let (mut response, mut answered) = (Response::default(), false);
+
+		// Get the handlers in the directory
+		let handlers = Handlers::in_files(&get_files_at(&path));
+
+		// If the path is a dir, perform all pre-request scoped operations.
+		if target == Target::Directory {
+			// Extend config if possible, and see if it forces an answer.
+			if let Some(forced) = self.enter_config(request, &mut config, &path) {
+				response = forced;
+			}
+			// Evaluate all of the gatekeepers
+			timed(&mut timings.gatekeepers, || {
+				// In-process gatekeepers guard the whole root, so they only run at its top, before any files.
+				// The first to deny the request answers it, and nothing after it runs.
+				let denial = (segment == skip)
+					.then(|| self.eval_hooks(request))
+					.flatten()
+					.or_else(|| client_cert_rejection(request, &config))
+					.or_else(|| {
+						self.eval_gatekeepers(
+							&handlers.gatekeepers,
+							&path,
+							request,
+							&config,
+							&query_strings,
+						)
+					});
+				if let Some(denial) = denial {
+					response = denial;
+				}
+			});
+			// Execute all of the request transformers and see if the directory answers, but only if the response isn't already bad.
+			if response.is_ok() {
+				timed(&mut timings.req_transformers, || {
+					self.eval_req_transformers(
+						&handlers.req_transformers,
+						&path,
+						request,
+						&config,
+						&query_strings,
+					);
+				});
+				if let Some(answer) =
+					self.answer_directory(request, segment, &path, &config, timings)
+				{
+					response = answer;
+					answered = true;
+				}
+			}
+		}
+		// A deeper directory has already had its say on any error it answered with.
+		let recursed = response.is_ok() && !answered && target == Target::Directory;
+		// Evaluate the target, but only if the request isn't already bad or answered.
+		if response.is_ok() && !answered {
+			response = match target {
+				Target::File => timed(&mut timings.target, || {
+					method_answer(request, &config).unwrap_or_else(|| {
+						self.run_file(exec, &path, request, &config, &query_strings)
+					})
+				}),
+				// The target is a directory, so we move into it.
+				Target::Directory => self.resolve(request, segment + 1, &mut config, timings),
+			};
+		}
+		if !recursed {
+			problem_json(request, &config, &mut response);
+			response.directory = Some(Box::new(config.clone()));
+		}
+		if target == Target::Directory {
+			timed(&mut timings.res_transformers, || {
+				self.eval_res_transformers(
+					&handlers.res_transformers,
+					&path,
+					&config,
+					&mut response,
+					request,
+					&query_strings,
+				);
+			});
+		}
+		if response.status == 0 {
+			response.status = 200;
+		}
+		// Run the loggers.
+		self.run_loggers(
+			&handlers.loggers,
+			&path,
+			&config,
+			&response,
+			request,
+			&query_strings,
+		);
+		response
+	}
+
+	/// Answers a request in a directory rather than letting it resolve further, if the directory says so.
+	/// A directory that forwards its requests answers for everything under it, so nothing in it is looked for,
+	/// and one at the end of the path is rewritten to its index, unless it answers with a redirect or listing instead.
+	fn answer_directory(
+		&self,
+		request: &mut Request,
+		segment: usize,
+		path: &Path,
+		config: &WWebS,
+		timings: &mut Timings,
+	) -> Option<Response> {
+		let at_end = request.url.path_segments().unwrap().count() == segment;
+		if config.proxy_pass.is_none() && !at_end {
+			return None;
+		}
+		// It's gated by its methods like a file would be, while directories the request only passes through aren't,
+		// so a deeper one can allow more.
+		if let Some(answer) = method_answer(request, config) {
+			return Some(answer);
+		}
+		if let Some(upstream) = &config.proxy_pass {
+			return Some(timed(&mut timings.target, || {
+				self.proxy(request, segment, upstream, config)
+			}));
+		}
+		self.enter_index(request, path, config)
+	}
+
+	/// Rewrites a request for a directory to its index, the first of its candidates that can be served, or returns the answer instead:
+	/// a redirect if a user agent rule says so or the path lacks a trailing slash that `redirect_trailing_slash` wants,
+	/// or a listing if the index is missing and `autoindex` is on.
+	fn enter_index(&self, request: &mut Request, path: &Path, config: &WWebS) -> Option<Response> {
+		let rule = self.user_agent_rule(request, config);
+		if let Some(rule) = rule.filter(|rule| rule.redirect.is_some()) {
+			return Some(redirect(
+				rule.redirect_status.unwrap_or(302),
+				rule.redirect.clone().unwrap_or_default(),
+			));
+		}
+		let resolution = config.resolution.as_ref();
+		let slashless = !request.url.path().ends_with('/');
+		if slashless && resolution.and_then(|v| v.redirect_trailing_slash) == Some(true) {
+			let mut location = format!("{}/", request.url.path());
+			if let Some(query) = request.url.query() {
+				location.push('?');
+				location.push_str(query);
+			}
+			return Some(redirect(
+				self.config().canonical_redirect_status.unwrap_or(301),
+				location,
+			));
+		}
+		let mut candidates = match rule.and_then(|rule| rule.index.clone()) {
+			Some(index) => vec![index],
+			None => resolution
+				.and_then(|v| v.index.as_ref())
+				.map(|index| index.candidates(request.proto))
+				.unwrap_or_default(),
+		};
+		if candidates.is_empty() {
+			let by_protocol = resolution.and_then(|v| v.protocol_index) == Some(true);
+			candidates.push(
+				match request.proto {
+					"Gemini" if by_protocol => "index.gmi",
+					_ => "index.html",
+				}
+				.to_string(),
+			);
+		}
+		let found = candidates
+			.iter()
+			.find(|name| Target::inspect(&path.join(name)).is_some());
+		let autoindex = resolution.and_then(|v| v.autoindex) == Some(true);
+		if autoindex && found.is_none() {
+			return Some(list_directory(request, path));
+		}
+		// Without any candidate that can be served, the first stands in, and isn't found like any other missing file.
+		let index = found.unwrap_or(&candidates[0]).clone();
+		request.url.path_segments_mut().unwrap().push(&index);
+		None
+	}
+
+	/// Finds the filesystem path for the first `segment` segments of a request, resolved in `root`.
+	/// Returns `None` if the path would climb out of its root or reach a reserved file, even once decoded.
+	fn path_at(request: &Request, root: &Path, skip: usize, segment: usize) -> Option<PathBuf> {
+		let path: PathBuf = request
+			.url
+			.path_segments()
+			.expect("Unexpected cannot-be-a-base url")
+			.take(segment)
+			.skip(skip)
+			.map(decode_segment)
+			.collect::<Option<_>>()?;
+
+		// Never let a path climb out of its root, or name a handler or config file.
+		if path.components().any(|component| match component {
+			Component::Normal(name) => is_reserved(&name.to_string_lossy()),
+			_ => true,
+		}) {
+			return None;
+		}
+
+		// Make the path absolute
+		Some(root.join(path))
+	}
+
+	/// Finds what the first `segment` segments of a request point to, returning its path, whether it's executable, and what kind of target it is.
+	/// Errors with the response to send instead if it's missing, not allowed, or should be redirected to.
+	// Errors are rare, so the response isn't worth boxing.
+	#[allow(clippy::result_large_err)]
+	fn locate(
+		&self,
+		request: &Request,
+		root: &Path,
+		skip: usize,
+		segment: usize,
+	) -> Result<(PathBuf, bool, Target), Response> {
+		let not_found = || Response {
+			status: 404,
+			..Default::default()
+		};
+		let path = Self::path_at(request, root, skip, segment).ok_or_else(not_found)?;
+		if let Some(redirect) = self.symlink_redirect(request, root, skip, segment, &path) {
+			return Err(redirect);
+		}
+		// Check that the path exists and is allowed, and maybe executable, and what kind of target it is.
+		let (exec, target) = Target::inspect(&path).ok_or_else(not_found)?;
+		Ok((path, exec, target))
+	}
+
+	/// Redirects a request through a symlinked directory to the directory it links to, if the server is configured to.
+	fn symlink_redirect(
+		&self,
+		request: &Request,
+		root: &Path,
+		skip: usize,
+		segment: usize,
 		path: &Path,
+	) -> Option<Response> {
+		let server_config = self.config();
+		if !server_config.redirect_symlinked_dirs || segment == skip {
+			return None;
+		}
+		if !std::fs::symlink_metadata(path).ok()?.is_symlink() || !path.is_dir() {
+			return None;
+		}
+		let target = std::fs::canonicalize(path).ok()?;
+		let linked = target
+			.strip_prefix(std::fs::canonicalize(root).ok()?)
+			.ok()?;
+		let segments: Vec<String> = request
+			.url
+			.path_segments()?
+			.map(|segment| percent_decode_str(segment).decode_utf8_lossy().to_string())
+			.collect();
+		let mut location = request.url.clone();
+		location
+			.path_segments_mut()
+			.ok()?
+			.clear()
+			.extend(&segments[..skip])
+			.extend(linked.iter().map(|name| name.to_string_lossy()))
+			.extend(&segments[segment..]);
+		Some(redirect(
+			server_config.canonical_redirect_status.unwrap_or(301),
+			location.to_string(),
+		))
+	}
+
+	/// Finds the directory a request resolves in, and how many of its path segments that directory stands for.
+	/// The longest matching alias wins, otherwise it's the workdir.
+	/// A request for just an alias' name (like `/docs`, but not `/docs/`) that's also a file in the workdir
+	/// gets the file instead when the file's directory prefers files.
+	fn root_for(&self, request: &Request) -> (PathBuf, usize) {
+		let server_config = self.config();
+		let segments: Vec<&str> = request
+			.url
+			.path_segments()
+			.map(Iterator::collect)
+			.unwrap_or_default();
+		server_config
+			.aliases
+			.iter()
+			.flatten()
+			.filter_map(|(prefix, dir)| {
+				let prefix: Vec<&str> = prefix.split('/').filter(|s| !s.is_empty()).collect();
+				segments
+					.starts_with(&prefix)
+					.then(|| (self.workdir.join(dir), prefix.len()))
+			})
+			.filter(|(_, skip)| segments.len() > *skip || !self.prefers_file(request, *skip))
+			.max_by_key(|(_, skip)| *skip)
+			.unwrap_or_else(|| (self.workdir.clone(), 0))
+	}
+
+	/// Whether the first `segment` segments of a request name a file in the workdir, whose directory's `prefer` picks it over a directory.
+	/// The directories' configurations are only read when there's such a file, since that's rare.
+	fn prefers_file(&self, request: &Request, segment: usize) -> bool {
+		let Some(path) = Self::path_at(request, &self.workdir, 0, segment) else {
+			return false;
+		};
+		if !matches!(Target::inspect(&path), Some((_, Target::File))) {
+			return false;
+		}
+		let mut config = WWebS::default();
+		for directory in (0..segment).filter_map(|i| Self::path_at(request, &self.workdir, 0, i)) {
+			// An invalid configuration is already reported when requests resolve through it.
+			let _ = Self::extend_config(&mut config, &directory);
+		}
+		config.resolution.and_then(|v| v.prefer) == Some(Prefer::File)
+	}
+
+	/// Checks that apply to the request as a whole, before any path resolution.
+	fn preflight(&self, request: &mut Request) -> Option<Response> {
+		let server_config = self.config();
+		if server_config.maintenance {
+			return Some(Response {
+				status: 503,
+				..Default::default()
+			});
+		}
+
+		// Every segment is looked into, so oversized paths are refused before any of that.
+		let limits = &server_config.limits;
+		let segments = request.url.path_segments().map_or(0, Iterator::count);
+		if request.url.as_str().len() > limits.max_url_length || segments > limits.max_path_segments
+		{
+			return Some(Response {
+				status: 414,
+				..Default::default()
+			});
+		}
+
+		// A CGI calling back into the server could otherwise fan out without end.
+		let depth = request_depth(request);
+		if server_config
+			.limits
+			.max_depth
+			.is_some_and(|max| depth > max)
+		{
+			tracing::warn!(
+				"Refusing {}, it's nested {depth} requests deep",
+				request.url.path()
+			);
+			return Some(Response {
+				status: 508,
+				..Default::default()
+			});
+		}
+
+		// Control characters in paths are attacks or bugs, and filesystems don't take kindly to them.
+		let decoded = percent_decode_str(request.url.path()).decode_utf8_lossy();
+		if decoded.chars().any(char::is_control) {
+			tracing::warn!(
+				"Rejecting a request for {}, its path has control characters",
+				request.url.path()
+			);
+			return Some(Response {
+				status: 400,
+				..Default::default()
+			});
+		}
+
+		// Resolve the path as clients would, so odd spellings of a path can't reach odd places.
+		let normalized = normalize_path(request.url.path());
+		if normalized != request.url.path() {
+			if server_config.redirect_to_canonical {
+				let mut location = request.url.clone();
+				location.set_path(&normalized);
+				return Some(redirect(
+					server_config.canonical_redirect_status.unwrap_or(301),
+					location.to_string(),
+				));
+			}
+			request.url.set_path(&normalized);
+		}
+		None
+	}
+
+	/// Finds the first of the directory's user agent rules that matches the request, if any.
+	fn user_agent_rule<'a>(
+		&self,
+		request: &Request,
+		config: &'a WWebS,
+	) -> Option<&'a UserAgentRule> {
+		let rules = config.user_agent_rules.as_ref()?;
+		let user_agent = request.header("User-Agent")?;
+		let mut regexes = self.regexes.lock().unwrap();
+		rules.iter().find(|rule| {
+			regexes
+				.entry(rule.pattern.clone())
+				.or_insert_with(|| {
+					Regex::new(&rule.pattern)
+						.map_err(|e| tracing::warn!("Ignoring invalid user agent pattern: {e}"))
+						.ok()
+				})
+				.as_ref()
+				.is_some_and(|regex| regex.is_match(user_agent))
+		})
+	}
+
+	fn run_loggers(
+		&self,
+		loggers: &[String],
+		path: &Path,
+		config: &WWebS,
+		response: &Response,
 		request: &mut Request,
+		query_strings: &HashMap<String, String>,
+	) {
+		// Execute all of the response transformers.
+		for logger in loggers {
+			let path = path.join(logger);
+			let mut extended_config = config.for_handlers();
+			extended_config
+				.env
+				.get_or_insert(HashMap::default())
+				.insert("STATUS".to_string(), response.status.to_string());
+			let _res = self.run_cgi(&mut request.clone(), &path, &extended_config, query_strings);
+		}
+	}
+
+	fn eval_res_transformers(
+		&self,
+		res_transformers: &[String],
+		path: &Path,
 		config: &WWebS,
+		response: &mut Response,
+		request: &Request,
 		query_strings: &HashMap<String, String>,
 	) {
-		// Get the list of request transformers.
-		let mut transformers: Vec<&String> = files
+		// Execute all of the response transformers.
+		// There's nothing for them to transform on an upgraded connection.
+		if res_transformers.is_empty() || response.upgrade.is_some() {
+			return;
+		}
+		// Transformers read the body whole, so a streamed one has to be collected first.
+		if let Err(e) = response.buffer_stream() {
+			tracing::error!("Failed to read a streamed body for transforming: {e}");
+		}
+		// The request's body can't go in the environment, so transformers get it in a file of its own.
+		let body_file = match BodyFile::create(&request.body) {
+			Ok(file) => file,
+			Err(e) => {
+				tracing::error!("Failed to store a request body for transforming: {e}");
+				None
+			}
+		};
+		for transformer in res_transformers {
+			let path = path.join(transformer);
+			let mut extended_config = config.for_handlers();
+			let env = extended_config.env.get_or_insert(HashMap::default());
+			env.insert("STATUS".to_string(), response.status.to_string());
+			for (k, v) in &request.headers {
+				env.insert(env_name("REQUEST_HEADER_", k), v.clone());
+			}
+			if let Some(file) = &body_file {
+				env.insert(
+					"REQUEST_BODY_FILE".to_string(),
+					file.0.to_string_lossy().to_string(),
+				);
+			}
+			let request = Request {
+				proto: request.proto,
+				verb: request.verb.clone(),
+				url: request.url.clone(),
+				headers: response.headers.clone(),
+				body: response.body.clone(),
+				cancellation: request.cancellation.clone(),
+				remote_addr: request.remote_addr,
+				local_addr: request.local_addr,
+				version: request.version.clone(),
+				tls: request.tls.clone(),
+			};
+			let res = self.run_cgi(&mut request.clone(), &path, &extended_config, query_strings);
+			response.body = res.body;
+			response.file = None;
+			for (k, v) in res.headers {
+				if v.is_empty() {
+					response.headers.remove(&k);
+				} else {
+					response.headers.insert(k, v);
+				}
+			}
+			response.status = res.status;
+		}
+	}
+
+	fn run_file(
+		&self,
+		exec: bool,
+		path: &Path,
+		request: &mut Request,
+		config: &WWebS,
+		query_strings: &HashMap<String, String>,
+	) -> Response {
+		tracing::Span::current().record("path", tracing::field::display(path.display()));
+		let exec = exec || interpreter_for(path, config).is_some();
+		let key = cache_key(request, config);
+		if let Some(mut response) = key.as_deref().and_then(|key| self.cache.get(key)) {
+			revalidate(request, &mut response);
+			// The cached body is whole, so a range of it is sliced out rather than read again.
+			if let Some(range) = request.header("Range").filter(|_| response.status == 200) {
+				slice_range(range, &mut response);
+			}
+			return response;
+		}
+		// Is the file static?
+		let mut response = match exec {
+			false => self.serve_static(request, path, config),
+			true => self.run_target_cgi(request, path, config, query_strings),
+		};
+		if let (Some(key), Some(cache)) = (key, &config.cache) {
+			// Static files are cached for as long as the directory says, but CGI binaries have to ask.
+			let ttl = match exec {
+				false => cache.ttl.filter(|_| response.status == 200),
+				true => response.cache_for,
+			};
+			// A range isn't the whole response, so it can't stand in for one.
+			let ranged = request.header("Range").is_some();
+			if let (Some(ttl), None, false) = (ttl, &response.stream, ranged) {
+				let max_entries = cache.max_entries.unwrap_or(1000);
+				self.cache.insert(key, response.clone(), ttl, max_entries);
+			}
+		}
+		revalidate(request, &mut response);
+		response
+	}
+
+	/// Serves a static file.
+	fn serve_static(&self, request: &Request, path: &Path, config: &WWebS) -> Response {
+		let mut headers = HashMap::default();
+		if let Some(links) = preload_links(request, path, config) {
+			headers.insert("Link".to_string(), links);
+		}
+		if let Some(cache_control) = cache_control_for(&self.config(), path) {
+			headers.insert("Cache-Control".to_string(), cache_control);
+		}
+		if let Some(mime) = content_type_for(path, config) {
+			headers.insert("Content-Type".to_string(), mime);
+		}
+		if let Some(disposition) = content_disposition(path, config) {
+			headers.insert("Content-Disposition".to_string(), disposition);
+		}
+		headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+		// Conditional requests arriving together share a look at the file, since they may not need to read it.
+		let conditional = request.header("If-None-Match").is_some()
+			|| request.header("If-Modified-Since").is_some();
+		let Ok(validated) = self.validators.get(path, conditional, || {
+			std::fs::metadata(path).map(|meta| (meta.len(), meta.modified().ok()))
+		}) else {
+			return Response {
+				status: 500,
+				..Default::default()
+			};
+		};
+		headers.extend(validated.headers);
+		// A client that already has the file is answered without reading it.
+		let mut response = Response {
+			status: 200,
+			headers,
+			..Default::default()
+		};
+		revalidate(request, &mut response);
+		if response.status == 304 {
+			return response;
+		}
+		let Response {
+			mut status,
+			mut headers,
+			..
+		} = response;
+		// Only a body that's the whole file can have its compressed variants cached.
+		let mut file = None;
+		// HEAD only needs the size, so don't bother reading the file.
+		let body = if request.verb == "HEAD" {
+			headers.insert("Content-Length".to_string(), validated.len.to_string());
+			Ok(vec![])
+		} else if let Some(range) = request.header("Range") {
+			read_range(path, range, &mut status, &mut headers)
+		} else {
+			file = validated
+				.modified
+				.map(|modified| Box::new((path.to_path_buf(), modified)));
+			std::fs::read(path)
+		};
+		match body {
+			Ok(body) => Response {
+				status,
+				headers,
+				body,
+				file,
+				..Default::default()
+			},
+			Err(_) => Response {
+				status: 500,
+				..Default::default()
+			},
+		}
+	}
+
+	/// Runs the CGI binary a request resolved to, as opposed to a handler.
+	fn run_target_cgi(
+		&self,
+		request: &mut Request,
+		path: &Path,
+		config: &WWebS,
+		query_strings: &HashMap<String, String>,
+	) -> Response {
+		// Identical safe requests can share one run, if the directory opts in.
+		let safe = matches!(request.verb.as_str(), "" | "GET" | "HEAD");
+		let mut response = if config.websocket == Some(true) && is_websocket_upgrade(request) {
+			self.websocket_cgi(request, path, config, query_strings)
+		} else if safe && config.single_flight == Some(true) {
+			let key = format!(
+				"{} {} {}\n{}",
+				request.proto,
+				request.verb,
+				request.url,
+				credentials(request)
+			);
+			let mut request = request.clone();
+			self.in_flight.run(&key, || {
+				self.run_cgi(&mut request, path, config, query_strings)
+			})
+		} else if config.streaming == Some(true)
+			&& config.transcode_from.is_none()
+			&& config.fastcgi.is_none()
+		{
+			// Shared and transcoded output is needed whole, so those are never streamed, and neither is FastCGI's.
+			self.stream_cgi(request, path, config, query_strings)
+		} else {
+			self.run_cgi(request, path, config, query_strings)
+		};
+		transcode_to_utf8(config, &mut response);
+		response
+	}
+
+	/// Runs the in-process gatekeepers, in the order they were added, until one denies the request.
+	/// Returns the denying gatekeeper's response, if any did.
+	fn eval_hooks(&self, request: &Request) -> Option<Response> {
+		// Snapshot the list, so a gatekeeper can add another without deadlocking.
+		let gatekeepers = self.gatekeepers.read().unwrap().clone();
+		gatekeepers
+			.into_iter()
+			.filter_map(|gatekeeper| gatekeeper(request))
+			.find(|res| !res.is_ok())
+	}
+
+	/// Runs a directory's gatekeepers in order until one denies the request.
+	/// Returns the denying gatekeeper's response, if any did.
+	fn eval_gatekeepers(
+		&self,
+		gatekeepers: &[String],
+		path: &Path,
+		request: &Request,
+		config: &WWebS,
+		query_strings: &HashMap<String, String>,
+	) -> Option<Response> {
+		let config = &config.for_handlers();
+		gatekeepers
 			.iter()
-			.filter(|v| v.starts_with(".req_transformer"))
-			.collect();
-		transformers.sort();
+			.map(|gatekeeper| {
+				let path = path.join(gatekeeper);
+				self.run_cgi(&mut request.clone(), &path, config, query_strings)
+			})
+			.find(|res| !res.is_ok())
+	}
+
+	fn eval_req_transformers(
+		&self,
+		transformers: &[String],
+		path: &Path,
+		request: &mut Request,
+		config: &WWebS,
+		query_strings: &HashMap<String, String>,
+	) {
+		let config = &config.for_handlers();
 		// Execute all of the request transformers.
 		for transformer in transformers {
 			let path = path.join(transformer);
@@ -410,27 +1562,675 @@ impl Server {
 						request.headers.insert(k, v);
 					}
 				}
-				request.body = res.body;
+				request.body = res.body;
+			}
+		}
+	}
+
+	/// Applies a directory's configuration over its parent's, returning the answer it forces, if any:
+	/// a 500 in strict mode when its `.wwebs.toml` is invalid, or its `extension_status` for the requested file.
+	fn enter_config(&self, request: &Request, config: &mut WWebS, path: &Path) -> Option<Response> {
+		let invalid = Self::extend_config(config, path).is_err() && self.config().strict_config;
+		invalid
+			.then(Response::internal_server_error)
+			.or_else(|| forced_status(request, config))
+	}
+
+	/// Merges a directory's `.wwebs.toml` over `config`, if it has one.
+	/// One that can't be read or parsed is logged and left out, and errors so the request can be refused in strict mode.
+	fn extend_config(config: &mut WWebS, path: &Path) -> anyhow::Result<()> {
+		let config_path = path.join(".wwebs.toml");
+		let config_string = match std::fs::read_to_string(&config_path) {
+			Ok(config_string) => config_string,
+			// Most directories don't have a configuration of their own, which isn't worth mentioning.
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => {
+				tracing::error!("Ignoring {}, it can't be read: {e}", config_path.display());
+				return Err(e.into());
+			}
+		};
+		let mut extension: WWebS = match toml::from_str(&config_string) {
+			Ok(extension) => extension,
+			Err(e) => {
+				tracing::error!("Ignoring {}, it's invalid: {e}", config_path.display());
+				return Err(e.into());
+			}
+		};
+		// Error pages are relative to the directory that names them, which is lost once configs are merged.
+		if let Some(errors) = &mut extension.errors {
+			for page in errors.values_mut() {
+				*page = path.join(&page);
+			}
+		}
+		// The directory's own settings win, and anything it leaves out is inherited.
+		*config = config.clone() & extension;
+		Ok(())
+	}
+}
+
+/// Percent-decodes one segment of a request's path into a file name.
+/// Returns `None` for segments that decode to a separator, so an encoded `/` can't reach into another directory.
+/// Percent signs that don't start a valid escape are kept as they are.
+fn decode_segment(segment: &str) -> Option<OsString> {
+	let bytes: Vec<u8> = percent_decode_str(segment).collect();
+	if bytes.contains(&b'/') || bytes.contains(&0) {
+		return None;
+	}
+	Some(OsString::from_vec(bytes))
+}
+
+/// Replaces the body of one of wwebs' own error responses with an RFC 7807 problem, if the directory asks for that
+/// and the client doesn't prefer HTML.
+fn problem_json(request: &Request, config: &WWebS, response: &mut Response) {
+	let default_body = Response::internal_server_error().body;
+	if config.problem_json != Some(true)
+		|| response.external
+		|| response.status < 400
+		|| request.proto == "Gemini"
+		|| (response.has_body() && response.body != default_body)
+		|| !prefers_json(request.header("Accept"))
+	{
+		return;
+	}
+	let title = status_title(response.status);
+	let problem = serde_json::json!({
+		"type": "about:blank",
+		"title": title,
+		"status": response.status,
+		"detail": format!("{title} while answering {}", request.url.path()),
+	});
+	response.headers.insert(
+		"Content-Type".to_string(),
+		"application/problem+json".to_string(),
+	);
+	response.body = problem.to_string().into_bytes();
+}
+
+/// Checks whether an `Accept` header ranks JSON at least as high as HTML.
+/// Clients that don't say are assumed to want JSON, since that's what the directory asked for.
+fn prefers_json(accept: Option<&str>) -> bool {
+	let Some(accept) = accept else {
+		return true;
+	};
+	let mut json = 0.0_f32;
+	let mut html = 0.0_f32;
+	for range in accept.split(',') {
+		let mut params = range.split(';').map(str::trim);
+		let mime = params.next().unwrap_or_default().to_ascii_lowercase();
+		let quality = params
+			.find_map(|param| param.strip_prefix("q="))
+			.and_then(|q| q.parse().ok())
+			.unwrap_or(1.0);
+		match mime.as_str() {
+			"application/problem+json" | "application/json" | "application/*" => {
+				json = json.max(quality);
+			}
+			"text/html" | "text/*" => html = html.max(quality),
+			"*/*" => {
+				json = json.max(quality);
+				html = html.max(quality);
+			}
+			_ => {}
+		}
+	}
+	json >= html
+}
+
+/// The usual reason phrase for an error status.
+pub(crate) fn status_title(status: u16) -> &'static str {
+	match status {
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		410 => "Gone",
+		413 => "Payload Too Large",
+		414 => "URI Too Long",
+		429 => "Too Many Requests",
+		431 => "Request Header Fields Too Large",
+		500 => "Internal Server Error",
+		502 => "Bad Gateway",
+		503 => "Service Unavailable",
+		504 => "Gateway Timeout",
+		_ if status < 500 => "Client Error",
+		_ => "Server Error",
+	}
+}
+
+/// Finds the fixed response the directory configures for the requested file's extension, if any.
+fn forced_status(request: &Request, config: &WWebS) -> Option<Response> {
+	let name = request.url.path_segments()?.next_back()?;
+	let forced = by_extension(config.extension_status.as_ref()?, name)?;
+	Some(Response {
+		status: forced.status,
+		body: forced.body.clone().unwrap_or_default().into_bytes(),
+		..Default::default()
+	})
+}
+
+/// Answers requests whose method the directory doesn't list in `methods` with a 405, and `OPTIONS` with what it does list.
+/// Directories without `methods` let every method through to the target.
+fn method_answer(request: &Request, config: &WWebS) -> Option<Response> {
+	let methods = config.methods.as_ref()?;
+	let listed = |method: &str| methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+	let mut allowed = methods.clone();
+	// HEAD is a GET without the body, and OPTIONS is answered right here.
+	if listed("GET") && !listed("HEAD") {
+		allowed.push("HEAD".to_string());
+	}
+	if !listed("OPTIONS") {
+		allowed.push("OPTIONS".to_string());
+	}
+	let verb = match request.verb.as_str() {
+		"" => "GET",
+		verb => verb,
+	};
+	let status = match verb {
+		"OPTIONS" => 204,
+		_ if allowed.iter().any(|m| m.eq_ignore_ascii_case(verb)) => return None,
+		_ => 405,
+	};
+	Some(Response {
+		status,
+		headers: HashMap::from([("Allow".to_string(), allowed.join(", "))]),
+		..Default::default()
+	})
+}
+
+/// Checks the request's client certificate against the directory's allowlist, if it has one.
+/// Only Gemini requests carry certificates, so anything else is treated as having none.
+fn client_cert_rejection(request: &Request, config: &WWebS) -> Option<Response> {
+	let allowed = config.allowed_client_certs.as_ref()?;
+	let cert = (request.proto == "Gemini")
+		.then(|| request.headers.get("UserCert"))
+		.flatten();
+	let (status, message) = match cert {
+		None => (401, "A client certificate is required"),
+		Some(cert) if !allowed.contains(cert) => (403, "This certificate is not authorized"),
+		Some(_) => return None,
+	};
+	// Gemini sends the body of failures as their meta line.
+	Some(Response {
+		status,
+		body: message.as_bytes().to_vec(),
+		..Default::default()
+	})
+}
+
+/// Builds a redirect to `location`.
+/// Statuses that aren't redirects are logged and replaced with a 302.
+fn redirect(status: u16, location: String) -> Response {
+	let status = if [301, 302, 303, 307, 308].contains(&status) {
+		status
+	} else {
+		tracing::warn!("{status} isn't a redirect status, using 302 instead");
+		302
+	};
+	Response {
+		status,
+		headers: HashMap::from([("Location".to_string(), location)]),
+		..Default::default()
+	}
+}
+
+/// Counts the connections the protocols have accepted.
+#[derive(Default)]
+struct Connections {
+	active: AtomicUsize,
+	accepted: AtomicUsize,
+}
+
+/// An open connection, which is closed in the server's counts when dropped.
+pub struct ConnectionGuard(Arc<Connections>);
+
+impl Drop for ConnectionGuard {
+	fn drop(&mut self) {
+		self.0.active.fetch_sub(1, atomic::Ordering::SeqCst);
+	}
+}
+
+/// Bytes of a body held in memory, which are given back to the server's total when dropped.
+pub struct BodyReservation {
+	buffered: Arc<AtomicUsize>,
+	/// The `max_buffered_bytes` when the reservation was made.
+	max: usize,
+	bytes: usize,
+}
+
+impl BodyReservation {
+	/// Adds more bytes to the reservation, unless that would take the total past `max_buffered_bytes`.
+	/// Returns whether they were added, so a body can be reserved for as it's read, and turned away once it doesn't fit.
+	/// A total that's already past the limit fits nothing more, not even 0 bytes.
+	pub fn try_grow(&mut self, bytes: usize) -> bool {
+		let grown = self
+			.buffered
+			.fetch_update(atomic::Ordering::SeqCst, atomic::Ordering::SeqCst, |used| {
+				used.checked_add(bytes).filter(|total| *total <= self.max)
+			})
+			.is_ok();
+		if grown {
+			self.bytes += bytes;
+		}
+		grown
+	}
+
+	/// Adds more bytes to the reservation, even past the limit, since they're already in memory.
+	/// Going past the limit only turns away later requests.
+	pub fn grow(&mut self, bytes: usize) {
+		self.buffered.fetch_add(bytes, atomic::Ordering::SeqCst);
+		self.bytes += bytes;
+	}
+}
+
+impl Drop for BodyReservation {
+	fn drop(&mut self) {
+		self.buffered
+			.fetch_sub(self.bytes, atomic::Ordering::SeqCst);
+	}
+}
+
+/// A slot for a running CGI binary, which is given back when dropped.
+struct CgiPermit(Arc<AtomicUsize>);
+
+impl Drop for CgiPermit {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, atomic::Ordering::SeqCst);
+	}
+}
+
+/// Kills a CGI binary, along with anything it spawned, since each leads its own process group.
+/// Only call this for children still in the server's list, since the pid could be reused otherwise.
+/// Even then, it could only have been reused in the moment between the child being reaped and leaving the list.
+fn kill_process_group(pid: u32) {
+	if let Ok(pid) = libc::pid_t::try_from(pid) {
+		// SAFETY: `kill` has no memory safety requirements.
+		unsafe {
+			libc::kill(-pid, libc::SIGKILL);
+		}
+	}
+}
+
+/// Names a signal that killed a CGI binary, with a hint at the usual culprit for the common ones.
+fn describe_signal(signal: i32) -> String {
+	let hint = match signal {
+		libc::SIGKILL => "SIGKILL, possibly from running out of memory",
+		libc::SIGSEGV => "SIGSEGV, a crash",
+		libc::SIGABRT => "SIGABRT, an abort",
+		libc::SIGBUS => "SIGBUS, a crash",
+		libc::SIGFPE => "SIGFPE, an arithmetic error",
+		libc::SIGPIPE => "SIGPIPE, writing to a closed pipe",
+		libc::SIGTERM => "SIGTERM",
+		libc::SIGINT => "SIGINT",
+		libc::SIGXCPU => "SIGXCPU, exceeding its CPU time limit",
+		_ => return format!("signal {signal}"),
+	};
+	format!("signal {signal} ({hint})")
+}
+
+/// Kills a CGI binary that's still running once its timeout passes, unless dropped first.
+struct Watchdog {
+	_done: mpsc::Sender<()>,
+	fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+	fn start(children: &Children, token: u64, timeout: Duration) -> Watchdog {
+		let (done, finished) = mpsc::channel::<()>();
+		let fired = Arc::new(AtomicBool::new(false));
+		let children = children.clone();
+		let watchdog_fired = fired.clone();
+		std::thread::spawn(move || {
+			if finished.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+				// Only kill it if it's still running, since its pid could have been reused otherwise.
+				if let Some(child) = children.lock().unwrap().get(&token) {
+					watchdog_fired.store(true, atomic::Ordering::SeqCst);
+					kill_process_group(child.pid);
+				}
 			}
+		});
+		Watchdog { _done: done, fired }
+	}
+
+	/// Whether the timeout passed and the binary was killed.
+	fn fired(self) -> bool {
+		self.fired.load(atomic::Ordering::SeqCst)
+	}
+}
+
+/// A CGI binary that's been started, along with everything that has to last as long as it runs.
+/// The fields drop in order, so the child is waited for before it leaves the server's list and gives back its slot.
+struct RunningCgi {
+	path: PathBuf,
+	p: Popen,
+	watchdog: Option<Watchdog>,
+	_child: Option<ChildGuard>,
+	_permit: CgiPermit,
+}
+
+impl RunningCgi {
+	/// Whether the binary was killed for running past its timeout, logging it if so.
+	fn timed_out(&mut self) -> bool {
+		let timed_out = self.watchdog.take().is_some_and(Watchdog::fired);
+		if timed_out {
+			tracing::warn!(
+				"Killed {}, it ran for longer than its timeout",
+				self.path.display()
+			);
+		}
+		timed_out
+	}
+}
+
+/// The running CGI binaries, shared between every clone of a server.
+/// They're listed by a token unique to each run rather than by pid, since a pid is reused once its process is reaped,
+/// and anything still holding on to a finished run's token (like its request's cancellation) mustn't reach another.
+type Children = Arc<Mutex<HashMap<u64, Child>>>;
+
+/// A running CGI binary, as listed in the server's children.
+struct Child {
+	pid: u32,
+	/// The path of the request it's serving.
+	path: String,
+}
+
+/// Lists a running CGI binary in the server's children, until dropped.
+struct ChildGuard {
+	children: Children,
+	token: u64,
+}
+
+impl ChildGuard {
+	fn new(children: &Children, pid: u32, path: &str) -> ChildGuard {
+		static TOKENS: AtomicU64 = AtomicU64::new(0);
+		let token = TOKENS.fetch_add(1, atomic::Ordering::SeqCst);
+		let child = Child {
+			pid,
+			path: path.to_string(),
+		};
+		children.lock().unwrap().insert(token, child);
+		ChildGuard {
+			children: children.clone(),
+			token,
+		}
+	}
+}
+
+impl Drop for ChildGuard {
+	fn drop(&mut self) {
+		self.children.lock().unwrap().remove(&self.token);
+	}
+}
+
+/// A private temporary file holding a request body, removed once dropped.
+struct BodyFile(PathBuf);
+
+impl BodyFile {
+	/// Writes a body to a new file, unless it's empty.
+	fn create(body: &[u8]) -> std::io::Result<Option<BodyFile>> {
+		static COUNT: AtomicUsize = AtomicUsize::new(0);
+		if body.is_empty() {
+			return Ok(None);
+		}
+		let path = std::env::temp_dir().join(format!(
+			"wwebs-body-{}-{}",
+			std::process::id(),
+			COUNT.fetch_add(1, atomic::Ordering::Relaxed)
+		));
+		let mut file = std::fs::OpenOptions::new()
+			.write(true)
+			.create_new(true)
+			.mode(0o600)
+			.open(&path)?;
+		// Own the path before writing, so a failed write still removes it.
+		let body_file = BodyFile(path);
+		file.write_all(body)?;
+		Ok(Some(body_file))
+	}
+}
+
+impl Drop for BodyFile {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.0);
+	}
+}
+
+/// How long each stage of resolving a request took, summed over every directory it passed through.
+#[derive(Default)]
+struct Timings {
+	gatekeepers: Duration,
+	req_transformers: Duration,
+	target: Duration,
+	res_transformers: Duration,
+}
+
+impl Timings {
+	/// Renders the stages as a `Server-Timing` header value, in milliseconds.
+	fn header_value(&self) -> String {
+		[
+			("gatekeeper", self.gatekeepers),
+			("req_transformer", self.req_transformers),
+			("target", self.target),
+			("res_transformer", self.res_transformers),
+		]
+		.iter()
+		.map(|(name, duration)| format!("{name};dur={:.3}", duration.as_secs_f64() * 1000.0))
+		.collect::<Vec<_>>()
+		.join(", ")
+	}
+}
+
+/// Runs `f`, adding how long it took to `total`.
+fn timed<T>(total: &mut Duration, f: impl FnOnce() -> T) -> T {
+	let start = Instant::now();
+	let result = f();
+	*total += start.elapsed();
+	result
+}
+
+/// The prefixes of handler file names.
+const HANDLER_PREFIXES: [&str; 4] = [
+	".gatekeeper",
+	".req_transformer",
+	".res_transformer",
+	".logger",
+];
+
+/// The handlers in a directory, by kind, in the order they run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Handlers {
+	/// The `.gatekeeper*` files.
+	pub gatekeepers: Vec<String>,
+	/// The `.req_transformer*` files.
+	pub req_transformers: Vec<String>,
+	/// The `.res_transformer*` files.
+	pub res_transformers: Vec<String>,
+	/// The `.logger*` files.
+	pub loggers: Vec<String>,
+}
+
+impl Handlers {
+	/// Picks the handlers out of a directory's file names.
+	fn in_files(files: &[String]) -> Handlers {
+		let [gatekeepers, request_transformers, response_transformers, loggers] = HANDLER_PREFIXES
+			.map(|prefix| {
+				let mut handlers: Vec<String> = files
+					.iter()
+					.filter(|name| name.starts_with(prefix))
+					.cloned()
+					.collect();
+				handlers.sort_by(|a, b| natural_cmp(a, b));
+				handlers
+			});
+		Handlers {
+			gatekeepers,
+			req_transformers: request_transformers,
+			res_transformers: response_transformers,
+			loggers,
 		}
 	}
+}
+
+/// Lists the files in a directory that others can read, skipping dotfiles (which covers handlers and configuration).
+/// Gemini gets gemtext, and everything else gets HTML.
+fn list_directory(request: &Request, path: &Path) -> Response {
+	let mut entries: Vec<(String, Target)> = get_files_at(path)
+		.into_iter()
+		.filter(|name| !name.starts_with('.'))
+		.filter_map(|name| {
+			let (_, target) = Target::inspect(&path.join(&name))?;
+			Some((name, target))
+		})
+		.collect();
+	entries.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+	let title = format!(
+		"Index of {}",
+		percent_decode_str(request.url.path()).decode_utf8_lossy()
+	);
+	// Links are absolute, so they work whether or not the directory was requested with a trailing slash.
+	let base = request.url.path().trim_end_matches('/').to_string() + "/";
+	let links = entries.into_iter().map(|(name, target)| {
+		let slash = if target == Target::Directory { "/" } else { "" };
+		let href = format!("{base}{}{slash}", utf8_percent_encode(&name, UNRESERVED));
+		(href, name + slash)
+	});
+	let (mime, body) = if request.proto == "Gemini" {
+		let items = links.fold(String::new(), |mut items, (href, name)| {
+			let _ = writeln!(items, "=> {href} {name}");
+			items
+		});
+		("text/gemini", format!("# {title}\n\n{items}"))
+	} else {
+		let title = escape_html(&title);
+		let items = links.fold(String::new(), |mut items, (href, name)| {
+			let _ = writeln!(
+				items,
+				"<li><a href=\"{href}\">{}</a></li>",
+				escape_html(&name)
+			);
+			items
+		});
+		(
+			"text/html; charset=utf-8",
+			format!(
+				"<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n<ul>\n{items}</ul>\n</body>\n</html>\n"
+			),
+		)
+	};
+	Response {
+		status: 200,
+		headers: HashMap::from([("Content-Type".to_string(), mime.to_string())]),
+		body: body.into_bytes(),
+		..Default::default()
+	}
+}
 
-	fn extend_config(config: &mut WWebS, path: &Path) {
-		*config = {
-			let config_res: anyhow::Result<WWebS> = (|| {
-				let config_path = path.join(".wwebs.toml");
-				let config_string = std::fs::read_to_string(config_path)?;
-				let config = toml::from_str(&config_string)?;
-				Ok(config)
-			})();
-			config_res.unwrap_or_else(|_| config.clone())
+/// Escapes text for use in HTML, including inside quoted attributes.
+fn escape_html(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+		.replace('\'', "&#39;")
+}
+
+/// Compares names so runs of digits are compared by value, so `.logger2` comes before `.logger10`.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+	let chunks = |s: &str| {
+		let mut chunks: Vec<(bool, String)> = vec![];
+		for c in s.chars() {
+			let digit = c.is_ascii_digit();
+			match chunks.last_mut() {
+				Some((last_digit, chunk)) if *last_digit == digit => chunk.push(c),
+				_ => chunks.push((digit, c.to_string())),
+			}
+		}
+		chunks
+	};
+	let (a, b) = (chunks(a), chunks(b));
+	for ((a_digit, a), (b_digit, b)) in a.iter().zip(&b) {
+		let ordering = match (a_digit, b_digit) {
+			// Longer runs of digits are bigger, once leading zeroes are gone.
+			(true, true) => {
+				let (a, b) = (a.trim_start_matches('0'), b.trim_start_matches('0'));
+				a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+			}
+			_ => a.cmp(b),
 		};
+		if ordering.is_ne() {
+			return ordering;
+		}
+	}
+	a.len().cmp(&b.len())
+}
+
+/// Whether a file name is reserved for handlers or configuration, so it can never be requested directly.
+/// Other dotfiles (like `.well-known`) are served like anything else.
+fn is_reserved(name: &str) -> bool {
+	name == ".wwebs.toml"
+		|| HANDLER_PREFIXES
+			.iter()
+			.any(|prefix| name.starts_with(prefix))
+}
+
+/// Collapses repeated slashes and resolves `.` and `..` segments, never climbing above the root.
+/// A trailing slash is kept, since it changes how relative links resolve.
+fn normalize_path(path: &str) -> String {
+	let mut segments: Vec<&str> = vec![];
+	for segment in path.split('/') {
+		match segment {
+			"" | "." => {}
+			".." => {
+				segments.pop();
+			}
+			segment => segments.push(segment),
+		}
+	}
+	let mut normalized = "/".to_string() + &segments.join("/");
+	if !segments.is_empty() && path.ends_with('/') {
+		normalized.push('/');
+	}
+	normalized
+}
+
+/// What a path resolved to.
+/// Symlinks are followed before classifying, so a link to a directory is entered like a directory,
+/// and a name can only ever be one or the other within a single root. Where an alias overlays a file, `Prefer` decides.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Target {
+	/// A directory, which is entered and may provide handlers and configuration.
+	Directory,
+	/// A regular file, which is served statically or executed.
+	File,
+}
+
+impl Target {
+	/// Checks that a path exists and is readable by others, returning whether it's executable and what kind of target it is.
+	fn inspect(path: &Path) -> Option<(bool, Target)> {
+		let meta = std::fs::metadata(path).ok()?;
+		let mode = meta.permissions().mode();
+		if mode & 0o004 == 0 {
+			return None;
+		}
+		// Special files (fifos, sockets, devices) are never served.
+		Some((mode & 0o001 > 0, Target::of(&meta)?))
+	}
+
+	/// Classifies followed metadata, returning `None` for anything that is neither a file nor a directory.
+	fn of(meta: &std::fs::Metadata) -> Option<Target> {
+		if meta.is_dir() {
+			Some(Target::Directory)
+		} else if meta.is_file() {
+			Some(Target::File)
+		} else {
+			None
+		}
 	}
 }
 
 fn get_files_at(path: &Path) -> Vec<String> {
 	if path.is_dir() {
-		let path = path;
 		let files_res: anyhow::Result<_> = (|| {
 			let readdir = std::fs::read_dir(path)?;
 			Ok(readdir
@@ -444,27 +2244,733 @@ fn get_files_at(path: &Path) -> Vec<String> {
 	}
 }
 
-fn parse_output_commands(stderr: &[u8], response: &mut Response) {
+/// The part of a file a `Range` header asks for.
+enum ByteRange {
+	/// The whole file, because the header isn't a single range of bytes wwebs understands.
+	Whole,
+	/// Nothing, because the range starts past the end of the file.
+	Unsatisfiable,
+	/// The bytes from the first offset to the second, inclusive.
+	Bytes(u64, u64),
+}
+
+impl ByteRange {
+	/// Parses a `Range` header against a file of `len` bytes.
+	/// Only single ranges are supported, so anything else (like several ranges at once) asks for the whole file.
+	fn parse(header: &str, len: u64) -> ByteRange {
+		let Some(spec) = header.trim().strip_prefix("bytes=") else {
+			return ByteRange::Whole;
+		};
+		let Some((start, end)) = spec.split_once('-') else {
+			return ByteRange::Whole;
+		};
+		let (start, end) = (start.trim(), end.trim());
+		if spec.contains(',') || (start.is_empty() && end.is_empty()) {
+			return ByteRange::Whole;
+		}
+		let (Ok(start), Ok(end)) = (
+			(!start.is_empty())
+				.then(|| start.parse::<u64>())
+				.transpose(),
+			(!end.is_empty()).then(|| end.parse::<u64>()).transpose(),
+		) else {
+			return ByteRange::Whole;
+		};
+		match (start, end) {
+			// A suffix, like `bytes=-500` for the last 500 bytes.
+			(None, Some(suffix)) if suffix == 0 || len == 0 => ByteRange::Unsatisfiable,
+			(None, Some(suffix)) => ByteRange::Bytes(len.saturating_sub(suffix), len - 1),
+			(Some(start), Some(end)) if start > end => ByteRange::Whole,
+			(Some(start), _) if start >= len => ByteRange::Unsatisfiable,
+			(Some(start), end) => ByteRange::Bytes(start, end.unwrap_or(u64::MAX).min(len - 1)),
+			(None, None) => ByteRange::Whole,
+		}
+	}
+}
+
+/// Reads the part of a static file a `Range` header asks for, setting the status and `Content-Range` to match.
+fn read_range(
+	path: &Path,
+	range: &str,
+	status: &mut u16,
+	headers: &mut HashMap<String, String>,
+) -> std::io::Result<Vec<u8>> {
+	let mut file = File::open(path)?;
+	let len = file.metadata()?.len();
+	let mut body = vec![];
+	match ByteRange::parse(range, len) {
+		ByteRange::Whole => {
+			file.read_to_end(&mut body)?;
+		}
+		ByteRange::Unsatisfiable => {
+			*status = 416;
+			headers.insert("Content-Range".to_string(), format!("bytes */{len}"));
+		}
+		ByteRange::Bytes(start, end) => {
+			file.seek(SeekFrom::Start(start))?;
+			file.take(end - start + 1).read_to_end(&mut body)?;
+			*status = 206;
+			headers.insert(
+				"Content-Range".to_string(),
+				format!("bytes {start}-{end}/{len}"),
+			);
+		}
+	}
+	Ok(body)
+}
+
+/// Cuts a whole response down to the part a `Range` header asks for, setting the status and `Content-Range` to match,
+/// if the response accepts ranges.
+fn slice_range(range: &str, response: &mut Response) {
+	if get_header(&response.headers, "Accept-Ranges") != Some("bytes") {
+		return;
+	}
+	let len = response.body.len() as u64;
+	match ByteRange::parse(range, len) {
+		ByteRange::Whole => {}
+		ByteRange::Unsatisfiable => {
+			response.status = 416;
+			response.body.clear();
+			response
+				.headers
+				.insert("Content-Range".to_string(), format!("bytes */{len}"));
+		}
+		ByteRange::Bytes(start, end) => {
+			// The range is within the body, so it fits in a usize.
+			let (Ok(first), Ok(last)) = (usize::try_from(start), usize::try_from(end)) else {
+				return;
+			};
+			response.body = response.body[first..=last].to_vec();
+			response.file = None;
+			response.status = 206;
+			response.headers.insert(
+				"Content-Range".to_string(),
+				format!("bytes {start}-{end}/{len}"),
+			);
+		}
+	}
+}
+
+/// Collects the configured preload `Link` values for a static HTML file, if any apply.
+fn preload_links(request: &Request, path: &Path, config: &WWebS) -> Option<String> {
+	let is_html = path
+		.extension()
+		.is_some_and(|ext| ext == "html" || ext == "htm");
+	if !is_html {
+		return None;
+	}
+	let mut patterns: Vec<_> = config.preload.as_ref()?.iter().collect();
+	// Sort so the header is stable no matter how the map iterates.
+	patterns.sort();
+	let links: Vec<&str> = patterns
+		.into_iter()
+		.filter(|(pattern, _)| glob_match(pattern, request.url.path()))
+		.flat_map(|(_, links)| links.iter().map(String::as_str))
+		.collect();
+	// Link allows several values separated by commas in a single header.
+	(!links.is_empty()).then(|| links.join(", "))
+}
+
+/// Finds the configured `Cache-Control` for a static file by its extension, preferring the longest match.
+fn cache_control_for(server_config: &ServerConfig, path: &Path) -> Option<String> {
+	let name = path.file_name()?.to_string_lossy();
+	by_extension(server_config.cache_control.as_ref()?, &name).cloned()
+}
+
+/// Works out the `Content-Type` of a static file from its extension, preferring the directory's own table.
+/// Gemtext isn't in the usual tables, so it's recognized here.
+fn content_type_for(path: &Path, config: &WWebS) -> Option<String> {
+	let name = path.file_name()?.to_string_lossy();
+	if let Some(mime) = config
+		.mime
+		.as_ref()
+		.and_then(|mime| by_extension(mime, &name))
+	{
+		return Some(mime.clone());
+	}
+	if has_extension(&name, "gmi") || has_extension(&name, "gemini") {
+		return Some("text/gemini".to_string());
+	}
+	mime_guess::from_path(path)
+		.first()
+		.map(|mime| mime.to_string())
+}
+
+/// Looks up a file name in a map keyed by extension (like `"png"` or `"tar.gz"`), preferring the longest match.
+/// Matching ignores case and a leading dot on the keys.
+fn by_extension<'a, V>(map: &'a HashMap<String, V>, name: &str) -> Option<&'a V> {
+	map.iter()
+		.map(|(extension, value)| (extension.trim_start_matches('.'), value))
+		.filter(|(extension, _)| has_extension(name, extension))
+		.max_by_key(|(extension, _)| extension.len())
+		.map(|(_, value)| value)
+}
+
+/// Finds the interpreter the directory configures for a file's extension, unless the file is executable and runs itself.
+fn interpreter_for<'a>(path: &Path, config: &'a WWebS) -> Option<&'a str> {
+	let name = path.file_name()?.to_str()?;
+	let interpreter = by_extension(config.interpreters.as_ref()?, name)?;
+	let executable =
+		std::fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o001 != 0);
+	(!executable).then_some(interpreter.as_str())
+}
+
+/// Checks whether a file name ends in an extension, ignoring case and a leading dot on the extension.
+fn has_extension(name: &str, extension: &str) -> bool {
+	let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+	name.to_ascii_lowercase()
+		.strip_suffix(&extension)
+		.is_some_and(|stem| stem.ends_with('.'))
+}
+
+/// The characters URLs never need escaped.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'-')
+	.remove(b'.')
+	.remove(b'_')
+	.remove(b'~');
+
+/// The characters RFC 5987 allows unescaped in an extended parameter value.
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+	.remove(b'!')
+	.remove(b'#')
+	.remove(b'$')
+	.remove(b'&')
+	.remove(b'+')
+	.remove(b'-')
+	.remove(b'.')
+	.remove(b'^')
+	.remove(b'_')
+	.remove(b'`')
+	.remove(b'|')
+	.remove(b'~');
+
+/// Builds the `Content-Disposition` header for a static file, if it should be downloaded.
+/// The name is given both as a plain ASCII fallback and, per RFC 5987, as percent-encoded UTF-8.
+fn content_disposition(path: &Path, config: &WWebS) -> Option<String> {
+	let name = path.file_name()?.to_string_lossy();
+	let download = config.download == Some(true)
+		|| config
+			.download_extensions
+			.iter()
+			.flatten()
+			.any(|extension| has_extension(&name, extension));
+	if !download {
+		return None;
+	}
+	let fallback: String = name
+		.chars()
+		.map(|c| match c {
+			' '..='~' if c != '"' && c != '\\' => c,
+			_ => '_',
+		})
+		.collect();
+	if fallback == name {
+		return Some(format!("attachment; filename=\"{name}\""));
+	}
+	Some(format!(
+		"attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+		utf8_percent_encode(&name, ATTR_CHAR)
+	))
+}
+
+/// Matches text against a pattern where `*` stands for any run of characters.
+fn glob_match(pattern: &str, text: &str) -> bool {
+	match pattern.split_once('*') {
+		None => pattern == text,
+		Some((prefix, rest)) => {
+			let Some(text) = text.strip_prefix(prefix) else {
+				return false;
+			};
+			(0..=text.len())
+				.filter(|i| text.is_char_boundary(*i))
+				.any(|i| glob_match(rest, &text[i..]))
+		}
+	}
+}
+
+/// Re-encodes a CGI's text response from the directory's legacy charset to UTF-8, if one is configured.
+/// Responses that aren't `text/*`, or that declare some other charset, are left alone.
+fn transcode_to_utf8(config: &WWebS, response: &mut Response) {
+	let Some(label) = &config.transcode_from else {
+		return;
+	};
+	let Some(encoding) = Encoding::for_label(label.as_bytes()) else {
+		tracing::warn!("Unknown charset {label:?} in transcode_from");
+		return;
+	};
+	let Some((key, content_type)) = response
+		.headers
+		.iter()
+		.find(|(k, _)| k.eq_ignore_ascii_case("Content-Type"))
+		.map(|(k, v)| (k.clone(), v.clone()))
+	else {
+		return;
+	};
+
+	let mut params = content_type.split(';').map(str::trim);
+	let mime = params.next().unwrap_or_default();
+	if !mime.to_ascii_lowercase().starts_with("text/") {
+		return;
+	}
+	let (charsets, mut params): (Vec<_>, Vec<_>) = params.partition(|param| {
+		param
+			.split_once('=')
+			.is_some_and(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+	});
+	let declared = charsets
+		.first()
+		.and_then(|param| param.split_once('='))
+		.map(|(_, v)| v.trim().trim_matches('"'));
+	if declared.is_some_and(|declared| Encoding::for_label(declared.as_bytes()) != Some(encoding)) {
+		return;
+	}
+
+	let (text, _, _) = encoding.decode(&response.body);
+	response.body = text.into_owned().into_bytes();
+	params.insert(0, mime);
+	params.push("charset=utf-8");
+	response.headers.insert(key, params.join("; "));
+}
+
+/// Looks up a header by name, ignoring case and treating `-` and `_` as equal.
+fn get_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+	let normalize = |s: &str| s.to_ascii_lowercase().replace('-', "_");
+	let name = normalize(name);
+	headers
+		.iter()
+		.find(|(k, _)| normalize(k) == name)
+		.map(|(_, v)| v.as_str())
+}
+
+/// Compresses a response's body with the best encoding the client accepts, if its directory allows it.
+/// Static files are only compressed once for each encoding, until they're modified.
+fn compress(request: &Request, response: &mut Response, variants: &CompressedCache) {
+	let Some(compression) = response
+		.directory
+		.as_ref()
+		.and_then(|directory| directory.compression.as_ref())
+		.filter(|c| c.enabled == Some(true))
+	else {
+		return;
+	};
+	let mime = get_header(&response.headers, "Content-Type")
+		.unwrap_or_default()
+		.to_ascii_lowercase();
+	let compressible = match &compression.types {
+		Some(types) => types.iter().any(|prefix| mime.starts_with(prefix.as_str())),
+		None => CompressionInfo::DEFAULT_TYPES
+			.iter()
+			.any(|prefix| mime.starts_with(prefix)),
+	};
+	if !compressible
+		|| response.stream.is_some()
+		|| response.body.len() < compression.min_size.unwrap_or(1024)
+		|| matches!(response.status, 204 | 206 | 304)
+		|| get_header(&response.headers, "Content-Encoding").is_some()
+	{
+		return;
+	}
+	let accepted = |encoding: &str| {
+		request
+			.header("Accept-Encoding")
+			.unwrap_or_default()
+			.split(',')
+			.any(|item| {
+				let mut params = item.split(';').map(str::trim);
+				let accepted = params
+					.next()
+					.is_some_and(|name| name.eq_ignore_ascii_case(encoding));
+				let quality = params
+					.find_map(|param| param.strip_prefix("q="))
+					.map_or(1.0, |q| q.parse().unwrap_or(0.0));
+				accepted && quality > 0.0
+			})
+	};
+	// Whether the body is compressed depends on the client, so caches need to know to keep them apart.
+	response.append_header("Vary", "Accept-Encoding");
+	let Some(encoding) = ["gzip", "deflate"].into_iter().find(|e| accepted(e)) else {
+		return;
+	};
+	let compressed = match response.file.take() {
+		Some(file) => {
+			let (path, modified) = *file;
+			variants.get_or_compress((path, modified, encoding), || {
+				encode(encoding, &response.body)
+			})
+		}
+		None => encode(encoding, &response.body),
+	};
+	match compressed {
+		Ok(body) => {
+			response.body = body;
+			response
+				.headers
+				.retain(|k, _| !k.eq_ignore_ascii_case("Content-Length"));
+			response
+				.headers
+				.insert("Content-Encoding".to_string(), encoding.to_string());
+			// The compressed body is a different representation, so its validator can only be weak.
+			if let Some(etag) =
+				get_header(&response.headers, "ETag").filter(|e| !e.starts_with("W/"))
+			{
+				let etag = format!("W/{etag}");
+				response
+					.headers
+					.retain(|k, _| !k.eq_ignore_ascii_case("ETag"));
+				response.headers.insert("ETag".to_string(), etag);
+			}
+		}
+		Err(e) => tracing::error!("Failed to compress {}: {e}", request.url.path()),
+	}
+}
+
+/// Compresses a body with an encoding, which is either `gzip` or `deflate`.
+fn encode(encoding: &str, body: &[u8]) -> io::Result<Vec<u8>> {
+	if encoding == "gzip" {
+		let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+		encoder.write_all(body)?;
+		encoder.finish()
+	} else {
+		// HTTP's deflate is the zlib format, not raw deflate.
+		let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+		encoder.write_all(body)?;
+		encoder.finish()
+	}
+}
+
+/// The `ETag` and `Last-Modified` of a static file, going by its size and modification time.
+fn validators(len: u64, modified: SystemTime) -> Vec<(String, String)> {
+	let nanos = modified
+		.duration_since(UNIX_EPOCH)
+		.map(|since| since.as_nanos())
+		.unwrap_or_default();
+	vec![
+		("ETag".to_string(), format!("\"{len:x}-{nanos:x}\"")),
+		(
+			"Last-Modified".to_string(),
+			httpdate::fmt_http_date(modified),
+		),
+	]
+}
+
+/// Downgrades a successful response to a 304 if the client already has it,
+/// going by its `ETag` (from a CGI, or a static file's), or failing that its `Last-Modified`.
+/// A CGI that answers with its own status (including its own 304) is left alone.
+/// Only `GET` and `HEAD` can be answered with a 304, so other methods are answered as if they had no conditions.
+fn revalidate(request: &Request, response: &mut Response) {
+	let safe = matches!(request.verb.as_str(), "" | "GET" | "HEAD");
+	if !safe || !response.is_ok() {
+		return;
+	}
+	// If-Modified-Since is only looked at when there's no If-None-Match, which is the more precise of the two.
+	let not_modified = if let Some(if_none_match) = request.header("If-None-Match") {
+		let Some(etag) = get_header(&response.headers, "ETag") else {
+			return;
+		};
+		// If-None-Match always uses the weak comparison.
+		let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+		let etag = weak(etag);
+		if_none_match
+			.split(',')
+			.any(|tag| tag.trim() == "*" || weak(tag) == etag)
+	} else {
+		let (Some(last_modified), Some(if_modified_since)) = (
+			get_header(&response.headers, "Last-Modified"),
+			request.header("If-Modified-Since"),
+		) else {
+			return;
+		};
+		match (
+			httpdate::parse_http_date(last_modified),
+			httpdate::parse_http_date(if_modified_since),
+		) {
+			(Ok(last_modified), Ok(if_modified_since)) => last_modified <= if_modified_since,
+			_ => false,
+		}
+	};
+	if not_modified {
+		response.status = 304;
+		response.body.clear();
+		response.stream = None;
+	}
+}
+
+/// Builds the environment for a CGI binary from the request and directory configuration.
+/// `extras` are appended last, so they can't be shadowed by the request.
+fn build_cgi_env(
+	request: &Request,
+	config: &WWebS,
+	query_strings: &HashMap<String, String>,
+	extras: Vec<(OsString, OsString)>,
+) -> Vec<(OsString, OsString)> {
+	let mut env: Vec<(OsString, OsString)> = vec![];
+	env.push(("PROTO".into(), request.proto.into()));
+	for (k, v) in &request.headers {
+		env.push((env_name("HEADER_", k).into(), v.into()));
+	}
+	for (k, v) in query_strings {
+		env.push((env_name("QUERY_", k).into(), v.into()));
+	}
+	// The ordered query comes after the named keys, since the last of two variables with the same name wins,
+	// and a query key like `count` shouldn't be able to hide it.
+	env.push((
+		"QUERY_STRING".into(),
+		request.url.query().unwrap_or_default().into(),
+	));
+	let mut count = 0;
+	for (i, (k, v)) in request.url.query_pairs().enumerate() {
+		env.push((format!("QUERY_{i}_KEY").into(), k.as_ref().into()));
+		env.push((format!("QUERY_{i}_VALUE").into(), v.as_ref().into()));
+		count = i + 1;
+	}
+	env.push(("QUERY_COUNT".into(), count.to_string().into()));
+	// Gemini's query is the user's answer to an input prompt, percent-encoded, rather than key-value pairs.
+	if let ("Gemini", Some(query)) = (request.proto, request.url.query()) {
+		let input = percent_decode_str(query).decode_utf8_lossy();
+		env.push(("QUERY_INPUT".into(), input.as_ref().into()));
+	}
+	env.push((
+		"WWEBS_DEPTH".into(),
+		(request_depth(request) + 1).to_string().into(),
+	));
+	env.push(("VERB".into(), request.verb.clone().into()));
+	env.push(("REQUESTED".into(), request.url.path().into()));
+	// These come before the directory's own, so a site behind a proxy can name itself.
+	env.extend(server_env(request));
+	for (k, v) in config.env.as_ref().unwrap_or(&HashMap::default()) {
+		env.push((k.into(), v.into()));
+	}
+	env.extend(extras);
+	env
+}
+
+/// The variables CGI uses to describe the server's end of a request:
+/// `SERVER_NAME`, `SERVER_PORT`, `SERVER_PROTOCOL`, and `HTTPS` when it came over TLS.
+fn server_env(request: &Request) -> Vec<(OsString, OsString)> {
+	// Parsed as a URL's authority, the `Host` header's port is split off, even after an IPv6 address.
+	let host = request
+		.header("Host")
+		.and_then(|host| url::Url::parse(&format!("http://{host}/")).ok());
+	let name = host
+		.as_ref()
+		.and_then(url::Url::host_str)
+		.or_else(|| request.url.host_str())
+		.unwrap_or("localhost");
+	let port = request
+		.local_addr
+		.map(|addr| addr.port())
+		.or_else(|| host.as_ref().and_then(url::Url::port))
+		.or_else(|| request.url.port_or_known_default());
+	let protocol = request
+		.version
+		.clone()
+		.unwrap_or_else(|| request.proto.to_ascii_uppercase());
+	let mut env: Vec<(OsString, OsString)> = vec![
+		("SERVER_NAME".into(), name.into()),
+		("SERVER_PROTOCOL".into(), protocol.into()),
+	];
+	if let Some(port) = port {
+		env.push(("SERVER_PORT".into(), port.to_string().into()));
+	}
+	if request.url.scheme() == "https" {
+		env.push(("HTTPS".into(), "on".into()));
+	}
+	if let Some(tls) = &request.tls {
+		for (name, value) in [
+			("SSL_PROTOCOL", &tls.protocol),
+			("SSL_CIPHER", &tls.cipher),
+			("SSL_CLIENT_CERT", &tls.client_cert),
+			("SSL_CLIENT_S_DN", &tls.client_subject),
+		] {
+			if let Some(value) = value {
+				env.push((name.into(), value.into()));
+			}
+		}
+	}
+	env
+}
+
+/// Splits the arguments of a `header` or `add-header` command into the header's name and value.
+/// The name ends at the first space, and everything after it is the value, verbatim.
+/// Names that aren't valid HTTP tokens are logged and ignored, rather than failing the whole response later.
+fn header_command(pair: &str) -> Option<(&str, &str)> {
+	let (key, value) = pair.trim_start().split_once(' ')?;
+	let valid = !key.is_empty()
+		&& key
+			.bytes()
+			.all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+	if !valid {
+		tracing::warn!("Ignoring a header with the invalid name {key:?}");
+		return None;
+	}
+	Some((key, value))
+}
+
+/// Returns whether a request asks to upgrade its connection to a WebSocket.
+fn is_websocket_upgrade(request: &Request) -> bool {
+	request.header("Upgrade").is_some_and(|upgrade| {
+		upgrade
+			.split(',')
+			.any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+	})
+}
+
+/// The key a request's target response is cached under, unless it can't be cached.
+fn cache_key(request: &Request, config: &WWebS) -> Option<String> {
+	let cache = config.cache.as_ref()?;
+	let safe = matches!(request.verb.as_str(), "" | "GET" | "HEAD");
+	// Range requests share the key of the whole response, which they're sliced out of.
+	if !safe || is_websocket_upgrade(request) {
+		return None;
+	}
+	let mut key = format!(
+		"{} {} {}\n{}",
+		request.proto,
+		request.verb,
+		request.url,
+		credentials(request)
+	);
+	for name in cache.vary.iter().flatten() {
+		key.push('\n');
+		key.push_str(request.header(name).unwrap_or_default());
+	}
+	Some(key)
+}
+
+/// The request's cookies and `Authorization`, in a stable order, so responses that may depend on who asked
+/// are only shared between requests from the same client.
+fn credentials(request: &Request) -> String {
+	let mut cookies: Vec<String> = request
+		.headers
+		.iter()
+		.filter(|(name, _)| name.starts_with("Cookie_"))
+		.map(|(name, value)| format!("{name}={value}"))
+		.collect();
+	cookies.sort();
+	format!(
+		"{}\n{}",
+		request.header("Authorization").unwrap_or_default(),
+		cookies.join("; ")
+	)
+}
+
+/// Names an environment variable after a key from the request, like `X-My-Header` becoming `HEADER_X_MY_HEADER`.
+/// As with CGI meta-variables, the key is uppercased and anything but letters, digits and `_` becomes `_`,
+/// so a client can't smuggle an `=` or control character into the environment.
+fn env_name(prefix: &str, key: &str) -> String {
+	let key = key.chars().map(|c| {
+		if c.is_ascii_alphanumeric() {
+			c.to_ascii_uppercase()
+		} else {
+			'_'
+		}
+	});
+	prefix.chars().chain(key).collect()
+}
+
+/// How many requests deep a request is nested, going by the `WWebS-Depth` header that CGIs calling back into the server forward.
+/// Requests without it are at the top, at depth 0.
+fn request_depth(request: &Request) -> usize {
+	request
+		.header("WWebS-Depth")
+		.and_then(|depth| depth.trim().parse().ok())
+		.unwrap_or(0)
+}
+
+/// The `Content-Type` CGI output is assumed to have when it doesn't say.
+fn default_mime(request: &Request) -> &'static str {
+	if request.proto == "Gemini" {
+		"text/gemini"
+	} else {
+		"text/plain"
+	}
+}
+
+/// Sets the charset parameter of the response's `Content-Type`, replacing any it already declared.
+/// Responses without a `Content-Type` get `default_mime`.
+fn set_charset(response: &mut Response, charset: &str, default_mime: &str) {
+	let key = response
+		.headers
+		.keys()
+		.find(|k| k.eq_ignore_ascii_case("Content-Type"))
+		.cloned()
+		.unwrap_or_else(|| "Content-Type".to_string());
+	let content_type = response
+		.headers
+		.get(&key)
+		.map_or(default_mime, String::as_str);
+	let mut params: Vec<&str> = content_type
+		.split(';')
+		.map(str::trim)
+		.filter(|param| {
+			!param
+				.split_once('=')
+				.is_some_and(|(k, _)| k.trim().eq_ignore_ascii_case("charset"))
+		})
+		.collect();
+	let charset = format!("charset={charset}");
+	params.push(&charset);
+	let value = params.join("; ");
+	response.headers.insert(key, value);
+}
+
+/// Anything that isn't a command is logged as it is, so scripts' errors show up, marked as such.
+fn parse_output_commands(stderr: &[u8], response: &mut Response, request: &Request, path: &Path) {
+	// The charset is applied last, so it doesn't matter whether it comes before or after the Content-Type.
+	let mut charset = None;
 	for line in String::from_utf8(stderr.to_vec())
 		.unwrap_or_else(|_| String::default())
 		.lines()
 	{
-		if line.starts_with("log ") {
-			eprintln!("{}", line.strip_prefix("log ").unwrap_or("???"));
-		} else if line.starts_with("header ") {
-			let _res: Option<()> = (|| {
-				let pair = line.strip_prefix("header ")?;
-				let split = pair.find(' ')?;
-				let key = &pair[..split];
-				let value = &pair[1 + split..];
+		if let Some(message) = line.strip_prefix("log ") {
+			tracing::info!("{}: {message}", log_context(request, path));
+		} else if let Some(pair) = line.strip_prefix("header ") {
+			if let Some((key, value)) = header_command(pair) {
 				response.headers.insert(key.to_string(), value.to_string());
-				Some(())
-			})();
+			}
+		} else if let Some(pair) = line.strip_prefix("add-header ") {
+			if let Some((key, value)) = header_command(pair) {
+				response.append_header(key, value);
+			}
 		} else if line.starts_with("status ") {
 			let status = line.strip_prefix("status ").unwrap().parse().unwrap_or(500);
 			response.status = status;
+		} else if let Some(args) = line.strip_prefix("redirect ") {
+			let mut args = args.split_whitespace();
+			if let Some(location) = args.next() {
+				let status = args.next().and_then(|s| s.parse().ok()).unwrap_or(302);
+				let redirect = redirect(status, location.to_string());
+				response.status = redirect.status;
+				response.headers.extend(redirect.headers);
+			}
+		} else if let Some(lang) = line.strip_prefix("lang ") {
+			response
+				.headers
+				.insert("Content-Language".to_string(), lang.trim().to_string());
+		} else if let Some(secs) = line.strip_prefix("cache ") {
+			response.cache_for = secs
+				.trim()
+				.parse()
+				.ok()
+				.and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+		} else if let Some(name) = line.strip_prefix("charset ") {
+			charset = Some(name.trim().to_string());
 		} else {
-			eprintln!("{}", line);
+			tracing::warn!("{} (stderr): {line}", log_context(request, path));
 		}
 	}
+	if let Some(charset) = charset {
+		set_charset(response, &charset, default_mime(request));
+	}
+}
+
+/// Says which content, answering which request, a line it logged came from, like `/srv/site/form.cgi (POST /form.cgi?step=2)`.
+fn log_context(request: &Request, path: &Path) -> String {
+	let mut target = request.url.path().to_string();
+	if let Some(query) = request.url.query() {
+		target.push('?');
+		target.push_str(query);
+	}
+	format!("{} ({} {target})", path.display(), request.verb)
 }
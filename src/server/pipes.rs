@@ -0,0 +1,182 @@
+//! Talking to CGI children over their standard streams.
+
+use std::{
+	fs::File,
+	io::{self, BufRead, BufReader, Read, Write},
+	os::unix::io::AsRawFd,
+	thread::JoinHandle,
+	time::{Duration, Instant},
+};
+
+use subprocess::Popen;
+
+use super::BodyReservation;
+
+/// Writes `input` to the child's stdin while draining its stdout and stderr, and returns both.
+/// If `stdin_deadline` passes before the child has taken all of its input,
+/// stdin is closed early and the child is left to finish producing output.
+/// If stdout grows past `max_output` bytes, it's closed early and this errors,
+/// and if it doesn't fit in `reservation` as it's read, it errors with `OutOfMemory`.
+pub(super) fn communicate(
+	p: &mut Popen,
+	input: &[u8],
+	stdin_deadline: Option<Duration>,
+	max_output: Option<usize>,
+	reservation: BodyReservation,
+) -> io::Result<(Vec<u8>, Vec<u8>)> {
+	let stdout = p
+		.stdout
+		.take()
+		.map(|file| drain(file, max_output, Some(reservation)));
+	let stderr = p.stderr.take().map(|file| drain(file, None, None));
+	if let Some(stdin) = p.stdin.take() {
+		write_input(stdin, input, stdin_deadline)?;
+	}
+	Ok((join(stdout)?, join(stderr)?))
+}
+
+/// Writes `input` to a streaming child's stdin on a thread of its own, then closes it.
+/// Writing on the side means a child that starts writing output before reading all of its input can't deadlock.
+pub(super) fn feed(stdin: File, input: Vec<u8>, deadline: Option<Duration>) {
+	std::thread::spawn(move || {
+		if let Err(e) = write_input(stdin, &input, deadline) {
+			tracing::error!("Failed to write a CGI's input: {e}");
+		}
+	});
+}
+
+/// Reads a streaming child's output commands, which end at a blank line or when stderr closes.
+/// Anything it writes to stderr afterwards only has its `log` commands logged, after `context`.
+pub(super) fn read_commands(stderr: File, context: String) -> io::Result<Vec<u8>> {
+	let mut stderr = BufReader::new(stderr);
+	let mut commands = vec![];
+	loop {
+		let mut line = vec![];
+		if stderr.read_until(b'\n', &mut line)? == 0 || line.trim_ascii().is_empty() {
+			break;
+		}
+		commands.extend(line);
+	}
+	log_commands(stderr, context);
+	Ok(commands)
+}
+
+/// Logs the `log` commands a child writes to stderr, after `context`, on a thread of its own, until it closes it.
+pub(super) fn log_commands(stderr: impl Read + Send + 'static, context: String) {
+	std::thread::spawn(move || {
+		for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+			if let Some(message) = line.strip_prefix("log ") {
+				tracing::info!("{context}: {message}");
+			}
+		}
+	});
+}
+
+/// Reads the whole pipe, or errors as soon as it holds more than `limit` bytes, or more than `reservation` can grow by.
+/// Closing the pipe early makes a child that keeps writing fail with `EPIPE`.
+fn drain(
+	mut file: File,
+	limit: Option<usize>,
+	mut reservation: Option<BodyReservation>,
+) -> JoinHandle<io::Result<Vec<u8>>> {
+	std::thread::spawn(move || {
+		let mut buf = vec![];
+		let mut chunk = vec![0; 8192];
+		loop {
+			let n = match file.read(&mut chunk) {
+				Ok(0) => return Ok(buf),
+				Ok(n) => n,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Err(e),
+			};
+			if let Some(limit) = limit.filter(|&limit| buf.len() + n > limit) {
+				return Err(io::Error::other(format!(
+					"CGI output exceeded the limit of {limit} bytes"
+				)));
+			}
+			if reservation.as_mut().is_some_and(|r| !r.try_grow(n)) {
+				return Err(io::Error::new(
+					io::ErrorKind::OutOfMemory,
+					"CGI output doesn't fit in max_buffered_bytes",
+				));
+			}
+			buf.extend_from_slice(&chunk[..n]);
+		}
+	})
+}
+
+fn join(handle: Option<JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+	match handle {
+		Some(handle) => handle
+			.join()
+			.unwrap_or_else(|_| Err(io::Error::other("reader panicked"))),
+		None => Ok(vec![]),
+	}
+}
+
+/// Writes the input, then closes stdin by dropping it.
+/// A child that exits without reading everything isn't an error.
+fn write_input(mut stdin: File, input: &[u8], deadline: Option<Duration>) -> io::Result<()> {
+	let ignore_broken_pipe = |res: io::Result<()>| match res {
+		Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+		res => res,
+	};
+	let Some(deadline) = deadline else {
+		return ignore_broken_pipe(stdin.write_all(input));
+	};
+
+	let deadline = Instant::now() + deadline;
+	set_nonblocking(&stdin)?;
+	let mut written = 0;
+	while written < input.len() {
+		match stdin.write(&input[written..]) {
+			Ok(n) => written += n,
+			Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+				let remaining = deadline.saturating_duration_since(Instant::now());
+				if remaining.is_zero() || !wait_writable(&stdin, remaining)? {
+					tracing::warn!(
+						"CGI didn't read its input in time, closing stdin after {written} of {} bytes",
+						input.len()
+					);
+					return Ok(());
+				}
+			}
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return ignore_broken_pipe(Err(e)),
+		}
+	}
+	Ok(())
+}
+
+fn set_nonblocking(file: &File) -> io::Result<()> {
+	let fd = file.as_raw_fd();
+	// SAFETY: `fd` is a valid descriptor owned by `file` for the duration of these calls.
+	unsafe {
+		let flags = libc::fcntl(fd, libc::F_GETFL);
+		if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+	Ok(())
+}
+
+/// Waits until the pipe can take more data, returning `false` if the timeout passes first.
+fn wait_writable(file: &File, timeout: Duration) -> io::Result<bool> {
+	let mut pollfd = libc::pollfd {
+		fd: file.as_raw_fd(),
+		events: libc::POLLOUT,
+		revents: 0,
+	};
+	let timeout = i32::try_from(timeout.as_millis().max(1)).unwrap_or(i32::MAX);
+	// SAFETY: `pollfd` is a single valid entry that outlives the call.
+	match unsafe { libc::poll(std::ptr::addr_of_mut!(pollfd), 1, timeout) } {
+		n if n < 0 => {
+			let e = io::Error::last_os_error();
+			match e.kind() {
+				io::ErrorKind::Interrupted => Ok(true),
+				_ => Err(e),
+			}
+		}
+		n => Ok(n > 0),
+	}
+}
@@ -0,0 +1,296 @@
+//! Forwarding requests to upstream HTTP servers, for directories with a `proxy_pass`.
+
+#[cfg(feature = "http")]
+use std::{
+	io,
+	sync::{mpsc, Arc, OnceLock},
+};
+
+#[cfg(feature = "http")]
+use hyper::{
+	body::HttpBody,
+	client::HttpConnector,
+	header::{HeaderName, HeaderValue},
+	Body, Client, HeaderMap, StatusCode,
+};
+#[cfg(feature = "http")]
+use tokio::runtime::Handle;
+#[cfg(feature = "http")]
+use url::Url;
+
+#[cfg(feature = "http")]
+use super::request_depth;
+use super::Server;
+use crate::{
+	files::wwebs::WWebS,
+	structures::{Request, Response},
+};
+
+/// Headers that only describe a single connection, so they're never forwarded in either direction.
+#[cfg(feature = "http")]
+const HOP_BY_HOP: [&str; 9] = [
+	"connection",
+	"keep-alive",
+	"proxy-authenticate",
+	"proxy-authorization",
+	"proxy-connection",
+	"te",
+	"trailer",
+	"transfer-encoding",
+	"upgrade",
+];
+
+/// The client for upstreams, shared between every clone of a server.
+/// hyper's client is async while resolving isn't, so requests run on a runtime of the proxy's own,
+/// started on a thread of its own the first time a request is forwarded.
+#[cfg(feature = "http")]
+#[derive(Clone, Default)]
+pub(super) struct ProxyClient(Arc<OnceLock<Option<Forwarder>>>);
+
+/// A runtime to forward requests on, and the client that sends them.
+#[cfg(feature = "http")]
+struct Forwarder {
+	runtime: Handle,
+	client: Client<HttpConnector>,
+}
+
+#[cfg(feature = "http")]
+impl ProxyClient {
+	/// The runtime and client to forward requests with, or `None` if the runtime couldn't be started.
+	fn get(&self) -> Option<&Forwarder> {
+		self.0
+			.get_or_init(|| {
+				let runtime = tokio::runtime::Builder::new_current_thread()
+					.enable_all()
+					.build()
+					.map_err(|e| tracing::error!("Failed to start the proxy's runtime: {e}"))
+					.ok()?;
+				let handle = runtime.handle().clone();
+				// The runtime runs for as long as the process, driven by a thread that does nothing else.
+				std::thread::spawn(move || runtime.block_on(std::future::pending::<()>()));
+				Some(Forwarder {
+					runtime: handle,
+					client: Client::new(),
+				})
+			})
+			.as_ref()
+	}
+}
+
+impl Server {
+	/// Forwards a request to the `proxy_pass` upstream of the directory at `segment`, with the rest of its path after the directory,
+	/// and relays the upstream's answer. Answers a 502 if the upstream can't be reached, and a 504 if it runs out of `timeout`.
+	#[cfg(feature = "http")]
+	pub(super) fn proxy(
+		&self,
+		request: &Request,
+		segment: usize,
+		upstream: &str,
+		config: &WWebS,
+	) -> Response {
+		let bad_gateway = Response {
+			status: 502,
+			..Default::default()
+		};
+		let Some(url) = upstream_url(request, segment, upstream) else {
+			tracing::warn!("Can't forward to {upstream}, it isn't an http:// URL");
+			return bad_gateway;
+		};
+		let Some(forwarder) = self.proxy.get() else {
+			return bad_gateway;
+		};
+		let outgoing = match forwarded_request(request, &url) {
+			Ok(outgoing) => outgoing,
+			Err(e) => {
+				tracing::warn!("Can't forward to {url}: {e}");
+				return bad_gateway;
+			}
+		};
+
+		let client = forwarder.client.clone();
+		let timeout = config.timeout;
+		let max_size = self.config().limits.max_response_size;
+		let (sender, receiver) = mpsc::channel();
+		forwarder.runtime.spawn(async move {
+			let exchange = exchange(&client, outgoing, max_size);
+			let result = match timeout {
+				Some(timeout) => tokio::time::timeout(timeout, exchange)
+					.await
+					.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+				None => exchange.await,
+			};
+			// The request may have given up waiting, in which case there's nobody to tell.
+			let _ = sender.send(result);
+		});
+		let (status, headers, body) = match receiver.recv() {
+			Ok(Ok(answer)) => answer,
+			Ok(Err(e)) if e.kind() == io::ErrorKind::TimedOut => {
+				tracing::warn!("{url} took too long to answer");
+				return Response {
+					status: 504,
+					..Default::default()
+				};
+			}
+			Ok(Err(e)) => {
+				tracing::error!("Failed to forward to {url}: {e}");
+				return bad_gateway;
+			}
+			Err(_) => return bad_gateway,
+		};
+
+		let mut response = Response {
+			status: status.as_u16(),
+			body,
+			external: true,
+			..Default::default()
+		};
+		let listed = connection_listed(headers.get("connection").and_then(|v| v.to_str().ok()));
+		for (name, value) in &headers {
+			// The body is whole again, and its length is set when it's sent (after any compression).
+			if is_hop_by_hop(name.as_str(), &listed) || name == "content-length" {
+				continue;
+			}
+			if let Ok(value) = value.to_str() {
+				response.append_header(name.as_str(), value);
+			}
+		}
+		response
+	}
+
+	/// Without hyper, there's no client to forward requests with.
+	#[cfg(not(feature = "http"))]
+	#[allow(clippy::unused_self)]
+	pub(super) fn proxy(
+		&self,
+		_request: &Request,
+		_segment: usize,
+		upstream: &str,
+		_config: &WWebS,
+	) -> Response {
+		tracing::warn!("Can't forward to {upstream}, wwebs was built without the http feature");
+		Response {
+			status: 502,
+			..Default::default()
+		}
+	}
+}
+
+/// The URL to forward a request to: the upstream's, with the part of the request's path after the directory at `segment` added,
+/// and the request's query. Returns `None` if the upstream isn't an `http://` URL.
+#[cfg(feature = "http")]
+fn upstream_url(request: &Request, segment: usize, upstream: &str) -> Option<Url> {
+	let mut url = Url::parse(upstream)
+		.ok()
+		.filter(|url| url.scheme() == "http")?;
+	let rest: Vec<&str> = request
+		.url
+		.path_segments()
+		.map(|segments| segments.skip(segment).collect())
+		.unwrap_or_default();
+	// The segments are still percent-encoded, so they're joined as they are rather than encoded again.
+	if !rest.is_empty() {
+		let path = format!("{}/{}", url.path().trim_end_matches('/'), rest.join("/"));
+		url.set_path(&path);
+	}
+	url.set_query(request.url.query());
+	Some(url)
+}
+
+/// Builds the request for the upstream, with the client's headers (other than hop-by-hop ones) and body.
+/// The client's address is added to `X-Forwarded-For`, and the scheme and host it asked for are sent as `X-Forwarded-Proto` and `X-Forwarded-Host`.
+/// `WWebS-Depth` is one deeper than the request's, so an upstream that's wwebs itself can break the loop.
+#[cfg(feature = "http")]
+fn forwarded_request(request: &Request, url: &Url) -> hyper::http::Result<hyper::Request<Body>> {
+	let verb = match request.verb.as_str() {
+		"" => "GET",
+		verb => verb,
+	};
+	let mut forwarded = hyper::Request::builder()
+		.method(verb)
+		.uri(url.as_str())
+		.body(Body::from(request.body.clone()))?;
+	let headers = forwarded.headers_mut();
+	let listed = connection_listed(request.header("Connection"));
+	let mut cookies = vec![];
+	for (name, value) in &request.headers {
+		// Protocols split cookies into a header each, so they're joined back into one.
+		if let Some(cookie) = name.strip_prefix("Cookie_") {
+			cookies.push(format!("{cookie}={value}"));
+			continue;
+		}
+		let name = name.to_ascii_lowercase();
+		// hyper sets `Host` and `Content-Length` for the upstream itself.
+		if is_hop_by_hop(&name, &listed)
+			|| ["host", "content-length", "x-forwarded-for"].contains(&name.as_str())
+		{
+			continue;
+		}
+		insert(headers, &name, value);
+	}
+	if !cookies.is_empty() {
+		insert(headers, "cookie", &cookies.join("; "));
+	}
+	let mut forwarded_for: Vec<String> = request
+		.header("X-Forwarded-For")
+		.into_iter()
+		.map(str::to_string)
+		.collect();
+	if let Some(addr) = request.remote_addr {
+		forwarded_for.push(addr.ip().to_string());
+	}
+	if !forwarded_for.is_empty() {
+		insert(headers, "x-forwarded-for", &forwarded_for.join(", "));
+	}
+	insert(headers, "x-forwarded-proto", request.url.scheme());
+	if let Some(host) = request.header("Host") {
+		insert(headers, "x-forwarded-host", host);
+	}
+	headers.insert("wwebs-depth", HeaderValue::from(request_depth(request) + 1));
+	Ok(forwarded)
+}
+
+/// Sends a request to its upstream and reads the whole answer, erroring if its body grows past `max_size` bytes.
+#[cfg(feature = "http")]
+async fn exchange(
+	client: &Client<HttpConnector>,
+	request: hyper::Request<Body>,
+	max_size: Option<usize>,
+) -> io::Result<(StatusCode, HeaderMap, Vec<u8>)> {
+	let response = client.request(request).await.map_err(io::Error::other)?;
+	let (parts, mut body) = response.into_parts();
+	let mut buffer = vec![];
+	while let Some(chunk) = body.data().await {
+		buffer.extend(chunk.map_err(io::Error::other)?);
+		if max_size.is_some_and(|max| buffer.len() > max) {
+			return Err(io::Error::other("the response is too large"));
+		}
+	}
+	Ok((parts.status, parts.headers, buffer))
+}
+
+/// Adds a header, leaving out ones whose name or value isn't valid.
+#[cfg(feature = "http")]
+fn insert(headers: &mut HeaderMap, name: &str, value: &str) {
+	if let (Ok(name), Ok(value)) = (
+		HeaderName::from_bytes(name.as_bytes()),
+		HeaderValue::from_str(value),
+	) {
+		headers.append(name, value);
+	}
+}
+
+/// The headers a `Connection` header names, which are hop-by-hop too.
+#[cfg(feature = "http")]
+fn connection_listed(connection: Option<&str>) -> Vec<String> {
+	connection
+		.unwrap_or_default()
+		.split(',')
+		.map(|name| name.trim().to_ascii_lowercase())
+		.collect()
+}
+
+/// Whether a header (named in lowercase) only describes a single connection.
+#[cfg(feature = "http")]
+fn is_hop_by_hop(name: &str, listed: &[String]) -> bool {
+	HOP_BY_HOP.contains(&name) || listed.iter().any(|listed| listed == name)
+}
@@ -0,0 +1,92 @@
+//! Streaming a CGI's output as it's written.
+
+use std::{
+	fs::File,
+	io::{self, Read},
+	path::PathBuf,
+};
+
+use super::{kill_process_group, RunningCgi};
+
+/// The stdout of a streaming CGI binary, which keeps the binary accounted for until it's dropped.
+pub(super) struct CgiOutput {
+	stdout: File,
+	cgi: RunningCgi,
+	path: PathBuf,
+	/// How many more bytes the binary may write, if limited.
+	remaining: Option<usize>,
+	finished: bool,
+}
+
+impl CgiOutput {
+	pub(super) fn new(
+		stdout: File,
+		cgi: RunningCgi,
+		path: PathBuf,
+		limit: Option<usize>,
+	) -> CgiOutput {
+		CgiOutput {
+			stdout,
+			cgi,
+			path,
+			remaining: limit,
+			finished: false,
+		}
+	}
+
+	/// Waits for the binary once its output has ended, logging how it went if that wasn't well.
+	fn finish(&mut self) {
+		self.finished = true;
+		let exit_status = self.cgi.p.wait();
+		if self.cgi.timed_out() {
+			return;
+		}
+		match exit_status {
+			Ok(status) if status.success() => {}
+			Ok(status) => tracing::error!(
+				"{} exited with {status:?} after its response started streaming",
+				self.path.display()
+			),
+			Err(e) => tracing::error!("Failed to wait for {}: {e}", self.path.display()),
+		}
+	}
+}
+
+impl Read for CgiOutput {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = self.stdout.read(buf)?;
+		if n == 0 {
+			if !self.finished {
+				self.finish();
+			}
+			return Ok(0);
+		}
+		if let Some(remaining) = &mut self.remaining {
+			*remaining = remaining.checked_sub(n).ok_or_else(|| {
+				io::Error::other(format!(
+					"{} wrote more than the response size limit",
+					self.path.display()
+				))
+			})?;
+		}
+		Ok(n)
+	}
+}
+
+impl Drop for CgiOutput {
+	fn drop(&mut self) {
+		// Nobody is left to read the rest, so don't let the binary carry on writing it.
+		if !self.finished {
+			if let Some(pid) = self
+				.cgi
+				.p
+				.poll()
+				.is_none()
+				.then(|| self.cgi.p.pid())
+				.flatten()
+			{
+				kill_process_group(pid);
+			}
+		}
+	}
+}
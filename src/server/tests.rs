@@ -0,0 +1,225 @@
+//! Unit tests for the parts of resolving that don't need a site on disk.
+
+use std::{collections::HashMap, ffi::OsString};
+
+use super::*;
+
+/// Looks up a variable in a CGI environment, going by its last value, which is the one the process sees.
+fn var<'a>(env: &'a [(OsString, OsString)], name: &str) -> Option<&'a str> {
+	env.iter()
+		.rev()
+		.find(|(k, _)| k == name)
+		.and_then(|(_, v)| v.to_str())
+}
+
+#[test]
+fn cgi_env_describes_the_request() {
+	let mut request = Request {
+		verb: "POST".to_string(),
+		..Default::default()
+	};
+	request.url.set_path("/form.cgi");
+	request.url.set_query(Some("step=2"));
+	request
+		.headers
+		.insert("User_Agent".to_string(), "curl".to_string());
+	let env = build_cgi_env(&request, &WWebS::default(), &request.query(), vec![]);
+	assert_eq!(var(&env, "VERB"), Some("POST"));
+	assert_eq!(var(&env, "REQUESTED"), Some("/form.cgi"));
+	assert_eq!(var(&env, "HEADER_USER_AGENT"), Some("curl"));
+	assert_eq!(var(&env, "QUERY_STEP"), Some("2"));
+	assert_eq!(var(&env, "QUERY_STRING"), Some("step=2"));
+}
+
+#[test]
+fn cgi_env_lets_the_directory_and_extras_override_the_request() {
+	let request = Request {
+		verb: "GET".to_string(),
+		..Default::default()
+	};
+	let config = WWebS {
+		env: Some(HashMap::from([
+			("VERB".to_string(), "configured".to_string()),
+			("SITE".to_string(), "configured".to_string()),
+		])),
+		..Default::default()
+	};
+	let extras = vec![("SITE".into(), "extra".into())];
+	let env = build_cgi_env(&request, &config, &HashMap::new(), extras);
+	assert_eq!(var(&env, "VERB"), Some("configured"));
+	assert_eq!(var(&env, "SITE"), Some("extra"));
+}
+
+#[test]
+fn cgi_env_lists_every_query_value_in_order() {
+	let mut request = Request::default();
+	request.url.set_query(Some("a=1&a=2&b=3"));
+	let env = build_cgi_env(&request, &WWebS::default(), &request.query(), vec![]);
+	assert_eq!(var(&env, "QUERY_COUNT"), Some("3"));
+	let pairs: Vec<_> = (0..3)
+		.map(|i| {
+			(
+				var(&env, &format!("QUERY_{i}_KEY")).unwrap(),
+				var(&env, &format!("QUERY_{i}_VALUE")).unwrap(),
+			)
+		})
+		.collect();
+	assert_eq!(pairs, [("a", "1"), ("a", "2"), ("b", "3")]);
+	// The single-value variables keep the last value.
+	assert_eq!(var(&env, "QUERY_A"), Some("2"));
+	assert_eq!(var(&env, "QUERY_B"), Some("3"));
+}
+
+#[test]
+fn cgi_env_names_are_sanitized() {
+	assert_eq!(env_name("HEADER_", "X-My-Header"), "HEADER_X_MY_HEADER");
+	assert_eq!(env_name("QUERY_", "page.size"), "QUERY_PAGE_SIZE");
+	assert_eq!(env_name("HEADER_", "a=b\nc d"), "HEADER_A_B_C_D");
+	assert_eq!(env_name("QUERY_", "caf\u{e9}"), "QUERY_CAF_");
+
+	let mut request = Request::default();
+	request.url.set_query(Some("PATH%3Dx=1"));
+	request
+		.headers
+		.insert("Evil=Header".to_string(), "value".to_string());
+	let env = build_cgi_env(&request, &WWebS::default(), &request.query(), vec![]);
+	// Nothing in the request can put an `=` in a name, or set a variable without a prefix.
+	assert!(env.iter().all(|(k, _)| !k.to_string_lossy().contains('=')));
+	assert_eq!(var(&env, "HEADER_EVIL_HEADER"), Some("value"));
+	assert_eq!(var(&env, "QUERY_PATH_X"), Some("1"));
+	assert_eq!(var(&env, "PATH"), None);
+}
+
+#[test]
+fn paths_are_normalized() {
+	assert_eq!(normalize_path("/a//b"), "/a/b");
+	assert_eq!(normalize_path("//"), "/");
+	assert_eq!(normalize_path("/a/./b/"), "/a/b/");
+	assert_eq!(normalize_path("/a/../b"), "/b");
+	// `..` never climbs above the root.
+	assert_eq!(normalize_path("/../../etc/passwd"), "/etc/passwd");
+	assert_eq!(normalize_path("/a/b/.."), "/a");
+}
+
+#[test]
+fn compressed_variants_are_only_compressed_once() {
+	let cache = CompressedCache::default();
+	let runs = AtomicUsize::new(0);
+	let compress = |body: &[u8]| {
+		runs.fetch_add(1, atomic::Ordering::SeqCst);
+		Ok(body.to_vec())
+	};
+	let modified = UNIX_EPOCH + Duration::from_secs(1);
+	let key = |modified, encoding| (PathBuf::from("/site/page.html"), modified, encoding);
+	for _ in 0..3 {
+		let body = cache.get_or_compress(key(modified, "gzip"), || compress(b"first"));
+		assert_eq!(body.unwrap(), b"first");
+	}
+	assert_eq!(runs.load(atomic::Ordering::SeqCst), 1);
+	// Another encoding, or the file being modified, is another variant.
+	cache
+		.get_or_compress(key(modified, "deflate"), || compress(b"first"))
+		.unwrap();
+	let body = cache.get_or_compress(key(modified + Duration::from_secs(1), "gzip"), || {
+		compress(b"second")
+	});
+	assert_eq!(body.unwrap(), b"second");
+	assert_eq!(runs.load(atomic::Ordering::SeqCst), 3);
+}
+
+#[test]
+fn children_are_told_apart_even_if_a_pid_is_reused() {
+	let children = Children::default();
+	let first = ChildGuard::new(&children, 4242, "/first.cgi");
+	let stale = first.token;
+	drop(first);
+	let second = ChildGuard::new(&children, 4242, "/second.cgi");
+	// Whatever still holds the first run's token, like its request's cancellation, can't reach the second.
+	assert!(!children.lock().unwrap().contains_key(&stale));
+	assert_eq!(children.lock().unwrap()[&second.token].path, "/second.cgi");
+	drop(second);
+	assert!(children.lock().unwrap().is_empty());
+}
+
+#[test]
+fn conditional_requests_together_share_a_look_at_the_file() {
+	let cache = ValidatorCache::default();
+	let stats = AtomicUsize::new(0);
+	let modified = UNIX_EPOCH + Duration::from_secs(1);
+	let stat = |len| {
+		stats.fetch_add(1, atomic::Ordering::SeqCst);
+		Ok((len, Some(modified)))
+	};
+	let path = Path::new("/site/style.css");
+	let barrier = std::sync::Barrier::new(8);
+	let etags: Vec<String> = std::thread::scope(|scope| {
+		let lookups: Vec<_> = (0..8)
+			.map(|_| {
+				scope.spawn(|| {
+					barrier.wait();
+					cache.get(path, true, || stat(100)).unwrap().headers[0]
+						.1
+						.clone()
+				})
+			})
+			.collect();
+		lookups
+			.into_iter()
+			.map(|lookup| lookup.join().unwrap())
+			.collect()
+	});
+	assert_eq!(stats.load(atomic::Ordering::SeqCst), 1);
+	assert!(etags.iter().all(|etag| *etag == etags[0]));
+
+	// Requests that read the file always look again, and see it change.
+	let validated = cache.get(path, false, || stat(200)).unwrap();
+	assert_eq!(stats.load(atomic::Ordering::SeqCst), 2);
+	assert_eq!(validated.len, 200);
+	assert_ne!(validated.headers[0].1, etags[0]);
+}
+
+#[test]
+fn a_slow_look_at_one_file_does_not_hold_up_others() {
+	let cache = ValidatorCache::default();
+	let (looking, looked) = std::sync::mpsc::channel();
+	let (finish, finished) = std::sync::mpsc::channel::<()>();
+	std::thread::scope(|scope| {
+		let cache = &cache;
+		let slow = scope.spawn(move || {
+			cache.get(Path::new("/site/slow.bin"), false, || {
+				looking.send(()).unwrap();
+				finished.recv().unwrap();
+				Ok((1, None))
+			})
+		});
+		looked.recv().unwrap();
+		// The slow look is still going, and would never finish if this had to wait for it.
+		let validated = cache
+			.get(Path::new("/site/style.css"), false, || Ok((100, None)))
+			.unwrap();
+		assert_eq!(validated.len, 100);
+		finish.send(()).unwrap();
+		assert_eq!(slow.join().unwrap().unwrap().len, 1);
+	});
+}
+
+#[test]
+fn header_commands_keep_the_value_verbatim() {
+	let mut response = Response::default();
+	let stderr = b"header Content-Type text/html; charset=utf-8\n\
+		header X-Spaced   three   spaces  \n\
+		header X-Colon a: b: c\n\
+		header Bad:Name value\n\
+		header NoValue\n";
+	parse_output_commands(
+		stderr,
+		&mut response,
+		&Request::default(),
+		Path::new("/a.cgi"),
+	);
+	assert_eq!(response.headers["Content-Type"], "text/html; charset=utf-8");
+	// Only the space after the name separates it from the value.
+	assert_eq!(response.headers["X-Spaced"], "  three   spaces  ");
+	assert_eq!(response.headers["X-Colon"], "a: b: c");
+	assert_eq!(response.headers.len(), 3);
+}
@@ -1,5 +1,14 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	str::FromStr,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+};
 
+use cookie::Cookie;
 use url::Url;
 
 /// An HTTP-like request structure.
@@ -14,10 +23,22 @@ pub struct Request {
 	pub verb: String,
 	/// The URL of the request.
 	pub url: url::Url,
-	/// The headers passed in the request.
+	/// The headers passed in the request, named as they were sent. See `header` to look one up.
+	/// Headers sent more than once are joined with `, ` into a single value, as HTTP allows.
 	pub headers: HashMap<String, String>,
 	/// The body of the request, if applicable.
 	pub body: Vec<u8>,
+	/// Whether the request was abandoned, shared by every clone of it.
+	pub cancellation: Cancellation,
+	/// The address of the client's end of the connection, if known.
+	/// Behind a proxy, this is the proxy's; see `Server::client_ip` for the client's own.
+	pub remote_addr: Option<SocketAddr>,
+	/// The address of the server's end of the connection, if known.
+	pub local_addr: Option<SocketAddr>,
+	/// The protocol version the client spoke, like `HTTP/1.1`, if known.
+	pub version: Option<String>,
+	/// What the secure connection the request came over negotiated, if it came over one.
+	pub tls: Option<TlsInfo>,
 }
 
 impl Default for Request {
@@ -28,6 +49,231 @@ impl Default for Request {
 			url: Url::from_str("http://localhost/").unwrap(),
 			headers: HashMap::default(),
 			body: Vec::default(),
+			cancellation: Cancellation::default(),
+			remote_addr: None,
+			local_addr: None,
+			version: None,
+			tls: None,
 		}
 	}
 }
+
+impl Request {
+	/// The query as a map. If a key is repeated, only its last value is kept.
+	///
+	/// ```
+	/// use wwebs::structures::Request;
+	///
+	/// let mut request = Request::default();
+	/// request.url.set_query(Some("page=2&sort=new%20first"));
+	/// let query = request.query();
+	/// assert_eq!(query["page"], "2");
+	/// assert_eq!(query["sort"], "new first");
+	/// ```
+	#[must_use]
+	pub fn query(&self) -> HashMap<String, String> {
+		self.url
+			.query_pairs()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect()
+	}
+
+	/// Looks up a header, ignoring case and treating `-` and `_` as equal.
+	///
+	/// ```
+	/// use wwebs::structures::Request;
+	///
+	/// let mut request = Request::default();
+	/// request.headers.insert("user_agent".to_string(), "curl".to_string());
+	/// assert_eq!(request.header("User-Agent"), Some("curl"));
+	/// assert_eq!(request.header("Accept"), None);
+	/// ```
+	#[must_use]
+	pub fn header(&self, name: &str) -> Option<&str> {
+		let normalize = |s: &str| s.to_ascii_lowercase().replace('-', "_");
+		let name = normalize(name);
+		self.headers
+			.iter()
+			.find(|(k, _)| normalize(k) == name)
+			.map(|(_, v)| v.as_str())
+	}
+
+	/// Looks up a cookie the client sent, from the header `cookie_headers` keeps it in.
+	///
+	/// ```
+	/// use wwebs::structures::Request;
+	///
+	/// let mut request = Request::default();
+	/// request.headers.extend(Request::cookie_headers("session=abc; theme=dark"));
+	/// assert_eq!(request.cookie("session"), Some("abc"));
+	/// assert_eq!(request.cookie("missing"), None);
+	/// ```
+	#[must_use]
+	pub fn cookie(&self, name: &str) -> Option<&str> {
+		self.header(&format!("Cookie_{name}"))
+	}
+
+	/// Splits the value of a `Cookie` header into a header per cookie, named `Cookie_` and the cookie's name,
+	/// which is how protocols pass cookies on to handlers. Anything that doesn't parse as a cookie is left out.
+	#[must_use]
+	pub fn cookie_headers(value: &str) -> Vec<(String, String)> {
+		value
+			.split(';')
+			.map(str::trim)
+			.flat_map(Cookie::parse)
+			.map(|cookie| {
+				(
+					format!("Cookie_{}", cookie.name()).replace('-', "_"),
+					cookie.value().to_string(),
+				)
+			})
+			.collect()
+	}
+}
+
+/// What a secure connection negotiated, as far as the protocol serving it can tell.
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct TlsInfo {
+	/// The protocol version, like `TLSv1.3`.
+	pub protocol: Option<String>,
+	/// The name of the cipher suite, like `TLS_AES_256_GCM_SHA384`.
+	pub cipher: Option<String>,
+	/// The certificate the client sent, in PEM.
+	pub client_cert: Option<String>,
+	/// The subject of the client's certificate, written as in RFC 2253, like `CN=alice,O=Example`.
+	pub client_subject: Option<String>,
+}
+
+#[cfg(feature = "openssl")]
+impl TlsInfo {
+	/// Describes an OpenSSL session, including the client's certificate if it sent one.
+	#[cfg(feature = "http")]
+	pub(crate) fn from_ssl(ssl: &openssl::ssl::SslRef) -> Self {
+		let mut info = Self {
+			protocol: Some(ssl.version_str().to_string()),
+			cipher: ssl.current_cipher().map(|cipher| cipher.name().to_string()),
+			..Self::default()
+		};
+		if let Some(cert) = ssl.peer_certificate() {
+			info.set_client_cert(&cert);
+		}
+		info
+	}
+
+	/// Sets the client's certificate.
+	pub(crate) fn set_client_cert(&mut self, cert: &openssl::x509::X509Ref) {
+		self.client_cert = cert
+			.to_pem()
+			.ok()
+			.and_then(|pem| String::from_utf8(pem).ok());
+		let mut subject: Vec<String> = cert
+			.subject_name()
+			.entries()
+			.map(|entry| {
+				let key = entry.object().nid().short_name().unwrap_or("UNDEF");
+				let value = String::from_utf8_lossy(entry.data().as_slice());
+				let mut escaped = String::new();
+				for c in value.chars() {
+					if ",+\"<>;\\=".contains(c) {
+						escaped.push('\\');
+					}
+					escaped.push(c);
+				}
+				format!("{key}={escaped}")
+			})
+			.collect();
+		// RFC 2253 lists the most specific part first, which OpenSSL keeps last.
+		subject.reverse();
+		self.client_subject = Some(subject.join(","));
+	}
+}
+
+/// A flag for a request that nobody is waiting on anymore, like when its client disconnected.
+/// Cancelling it stops any CGI binaries it's running, and keeps it from starting more.
+#[derive(Clone, Default)]
+pub struct Cancellation(Arc<CancellationState>);
+
+#[derive(Default)]
+struct CancellationState {
+	cancelled: AtomicBool,
+	on_cancel: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+}
+
+impl Cancellation {
+	/// Cancels the request. Cancelling it again does nothing.
+	/// # Panics
+	/// Panics if the callback lock was poisoned.
+	pub fn cancel(&self) {
+		self.0.cancelled.store(true, Ordering::SeqCst);
+		let callbacks = std::mem::take(&mut *self.0.on_cancel.lock().unwrap());
+		for callback in callbacks {
+			callback();
+		}
+	}
+
+	/// Returns whether the request was cancelled.
+	#[must_use]
+	pub fn is_cancelled(&self) -> bool {
+		self.0.cancelled.load(Ordering::SeqCst)
+	}
+
+	/// Runs `callback` when the request is cancelled, or right away if it already was.
+	pub(crate) fn on_cancel(&self, callback: impl FnOnce() + Send + 'static) {
+		let mut callbacks = self.0.on_cancel.lock().unwrap();
+		// Checked under the lock, so a concurrent `cancel` either sees this callback or was already seen here.
+		if self.is_cancelled() {
+			drop(callbacks);
+			callback();
+		} else {
+			callbacks.push(Box::new(callback));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Request;
+
+	#[test]
+	fn the_query_is_decoded_and_keeps_the_last_value() {
+		let mut request = Request::default();
+		assert!(request.query().is_empty());
+		request
+			.url
+			.set_query(Some("q=a+b%26c&empty=&page=1&page=2&flag"));
+		let query = request.query();
+		assert_eq!(query["q"], "a b&c");
+		assert_eq!(query["empty"], "");
+		assert_eq!(query["page"], "2");
+		assert_eq!(query["flag"], "");
+		assert_eq!(query.len(), 4);
+	}
+
+	#[test]
+	fn headers_are_found_whatever_their_spelling() {
+		let mut request = Request::default();
+		request
+			.headers
+			.insert("X_Forwarded_For".to_string(), "203.0.113.7".to_string());
+		for name in ["X-Forwarded-For", "x-forwarded-for", "X_FORWARDED_FOR"] {
+			assert_eq!(request.header(name), Some("203.0.113.7"), "{name}");
+		}
+		assert_eq!(request.header("X-Forwarded"), None);
+	}
+
+	#[test]
+	fn cookies_are_split_into_headers_of_their_own() {
+		let mut request = Request::default();
+		request.headers.extend(Request::cookie_headers(
+			"session=abc; theme-name=dark ;malformed; empty=",
+		));
+		assert_eq!(request.cookie("session"), Some("abc"));
+		// Names are matched like headers, so dashes and underscores are the same.
+		assert_eq!(request.cookie("theme-name"), Some("dark"));
+		assert_eq!(request.cookie("theme_name"), Some("dark"));
+		assert_eq!(request.cookie("empty"), Some(""));
+		assert_eq!(request.cookie("malformed"), None);
+		assert_eq!(request.headers.len(), 3);
+	}
+}
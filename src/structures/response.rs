@@ -1,4 +1,15 @@
-use std::collections::HashMap;
+use std::{
+	collections::HashMap,
+	fmt,
+	io::{self, Read, Write},
+	path::PathBuf,
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime},
+};
+
+use cookie::Cookie;
+
+use crate::files::wwebs::WWebS;
 
 /// An HTTP-like representation of the server's response.
 #[derive(Default, Clone, Debug)]
@@ -10,9 +21,78 @@ pub struct Response {
 	pub status: u16,
 	/// The headers of the response.
 	/// Their meanings should be as close to HTTP as possible.
+	/// A header with several values (like `Set-Cookie`) holds them separated by newlines, which can't appear in a value,
+	/// and each is sent as a header line of its own.
 	pub headers: HashMap<String, String>,
 	/// The body of the response.
 	pub body: Vec<u8>,
+	/// The rest of the body, read after `body` as the response is sent, if it's streamed.
+	pub stream: Option<BodyStream>,
+	/// How long the response may be reused for identical requests, if a CGI binary marked it cacheable.
+	pub cache_for: Option<Duration>,
+	/// The configuration of the directory that answered, once the request is resolved.
+	/// Settings that apply to the finished response, like `compression` and `access_log`, are read from here.
+	pub directory: Option<Box<WWebS>>,
+	/// Where the client's side of the connection goes once it's upgraded, if the response switches protocols.
+	/// The other side comes from `stream`.
+	pub upgrade: Option<UpgradeSink>,
+	/// The static file the body was read from whole, and when it was modified, so compressing it can be cached.
+	/// Anything that changes the body has to clear this.
+	pub(crate) file: Option<Box<(PathBuf, SystemTime)>>,
+	/// Whether a CGI binary or an upstream wrote the response rather than wwebs, so its body is theirs even if it's empty.
+	pub(crate) external: bool,
+}
+
+/// A body that's read as it's sent, rather than held in memory.
+/// It can only be read once, so clones share it and whichever takes it first gets all of it.
+#[derive(Clone)]
+pub struct BodyStream(Arc<Mutex<Option<Box<dyn Read + Send>>>>);
+
+impl BodyStream {
+	/// Wraps a reader as a body.
+	pub fn new(reader: impl Read + Send + 'static) -> BodyStream {
+		BodyStream(Arc::new(Mutex::new(Some(Box::new(reader)))))
+	}
+
+	/// Takes the reader, unless it has already been taken.
+	/// # Panics
+	/// Panics if a thread panicked while taking it.
+	#[must_use]
+	pub fn take(&self) -> Option<Box<dyn Read + Send>> {
+		self.0.lock().unwrap().take()
+	}
+}
+
+impl fmt::Debug for BodyStream {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("BodyStream")
+	}
+}
+
+/// Where data from the client goes on an upgraded connection, like a WebSocket handler's stdin.
+/// Like a `BodyStream`, clones share it and whichever takes it first gets it.
+#[derive(Clone)]
+pub struct UpgradeSink(Arc<Mutex<Option<Box<dyn Write + Send>>>>);
+
+impl UpgradeSink {
+	/// Wraps a writer as a sink.
+	pub fn new(writer: impl Write + Send + 'static) -> UpgradeSink {
+		UpgradeSink(Arc::new(Mutex::new(Some(Box::new(writer)))))
+	}
+
+	/// Takes the writer, unless it has already been taken.
+	/// # Panics
+	/// Panics if a thread panicked while taking it.
+	#[must_use]
+	pub fn take(&self) -> Option<Box<dyn Write + Send>> {
+		self.0.lock().unwrap().take()
+	}
+}
+
+impl fmt::Debug for UpgradeSink {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("UpgradeSink")
+	}
 }
 
 impl Response {
@@ -22,6 +102,127 @@ impl Response {
 		self.status == 0 || (200..300).contains(&self.status)
 	}
 
+	/// Reads the rest of a streamed body into `body`, for whatever needs the whole of it at once.
+	/// # Errors
+	/// Errors if reading the stream fails, in which case `body` holds whatever was read.
+	pub fn buffer_stream(&mut self) -> io::Result<()> {
+		match self.stream.take().and_then(|stream| stream.take()) {
+			Some(mut reader) => reader.read_to_end(&mut self.body).map(|_| ()),
+			None => Ok(()),
+		}
+	}
+
+	/// Returns whether the response has a body, streamed or not.
+	#[must_use]
+	pub fn has_body(&self) -> bool {
+		!self.body.is_empty() || self.stream.is_some()
+	}
+
+	/// Adds another value for a header, keeping any it already has.
+	pub fn append_header(&mut self, name: &str, value: &str) {
+		let existing = self
+			.headers
+			.keys()
+			.find(|k| k.eq_ignore_ascii_case(name))
+			.cloned();
+		match existing.and_then(|k| self.headers.get_mut(&k)) {
+			Some(values) => {
+				values.push('\n');
+				values.push_str(value);
+			}
+			None => {
+				self.headers.insert(name.to_string(), value.to_string());
+			}
+		}
+	}
+
+	/// Sets a cookie on the client, serializing it with its attributes
+	/// (`Path`, `HttpOnly`, `Secure`, `SameSite`, `Max-Age`, `Expires`...) as a `Set-Cookie` header.
+	/// Cookies set earlier are kept, each in a `Set-Cookie` header of its own.
+	pub fn set_cookie(&mut self, cookie: &Cookie) {
+		self.append_header("Set-Cookie", &cookie.to_string());
+	}
+
+	/// Sets the status, for building a response by chaining.
+	///
+	/// ```
+	/// use wwebs::structures::Response;
+	///
+	/// let response = Response::default()
+	///     .with_status(302)
+	///     .with_header("Location", "/elsewhere")
+	///     .with_body("Moved");
+	/// assert_eq!(response.status, 302);
+	/// assert_eq!(response.headers["Location"], "/elsewhere");
+	/// assert_eq!(response.body, b"Moved");
+	/// assert!(!response.is_ok());
+	/// ```
+	#[must_use]
+	pub fn with_status(mut self, status: u16) -> Response {
+		self.status = status;
+		self
+	}
+
+	/// Adds a value for a header, keeping any it already has like `append_header`, for building a response by chaining.
+	///
+	/// ```
+	/// use wwebs::structures::Response;
+	///
+	/// let response = Response::default()
+	///     .with_header("Set-Cookie", "a=1")
+	///     .with_header("Set-Cookie", "b=2");
+	/// assert_eq!(response.headers["Set-Cookie"], "a=1\nb=2");
+	/// ```
+	#[must_use]
+	pub fn with_header(mut self, name: &str, value: &str) -> Response {
+		self.append_header(name, value);
+		self
+	}
+
+	/// Sets the body, for building a response by chaining.
+	#[must_use]
+	pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+		self.body = body.into();
+		self
+	}
+
+	/// Streams the body from a reader as the response is sent, after anything already in `body`,
+	/// for building a response by chaining.
+	///
+	/// ```
+	/// use wwebs::structures::Response;
+	///
+	/// let mut response = Response::default().with_stream(&b"streamed"[..]);
+	/// response.buffer_stream().unwrap();
+	/// assert_eq!(response.body, b"streamed");
+	/// ```
+	#[must_use]
+	pub fn with_stream(mut self, reader: impl Read + Send + 'static) -> Response {
+		self.stream = Some(BodyStream::new(reader));
+		self
+	}
+
+	/// The status of a response from dynamic content that exited with `code`, unless it sent a `status` command.
+	/// 0 is success, and codes that are HTTP statuses are used as they are,
+	/// but any other code is just a failure, so it's a 502 rather than an invalid status.
+	///
+	/// ```
+	/// use wwebs::structures::Response;
+	///
+	/// assert_eq!(Response::status_for_exit_code(0), 200);
+	/// assert_eq!(Response::status_for_exit_code(1), 502);
+	/// assert_eq!(Response::status_for_exit_code(42), 502);
+	/// assert_eq!(Response::status_for_exit_code(404), 404);
+	/// ```
+	#[must_use]
+	pub fn status_for_exit_code(code: u32) -> u16 {
+		match code {
+			0 => 200,
+			100..=599 => u16::try_from(code).unwrap_or(502),
+			_ => 502,
+		}
+	}
+
 	/// Helper to generate an HTTP 500 response.
 	#[must_use]
 	pub fn internal_server_error() -> Response {
@@ -32,3 +233,36 @@ impl Response {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use cookie::{
+		time::{Duration, OffsetDateTime},
+		Cookie, SameSite,
+	};
+
+	use super::Response;
+
+	#[test]
+	fn set_cookie_serializes_attributes() {
+		let mut response = Response::default();
+		let session = Cookie::build("session", "abc")
+			.path("/")
+			.secure(true)
+			.http_only(true)
+			.same_site(SameSite::Strict)
+			.expires(OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap())
+			.finish();
+		let theme = Cookie::build("theme", "dark")
+			.same_site(SameSite::Lax)
+			.max_age(Duration::hours(1))
+			.finish();
+		response.set_cookie(&session);
+		response.set_cookie(&theme);
+		assert_eq!(
+			response.headers["Set-Cookie"],
+			"session=abc; HttpOnly; SameSite=Strict; Secure; Path=/; Expires=Tue, 14 Nov 2023 22:13:20 GMT\n\
+			theme=dark; SameSite=Lax; Max-Age=3600"
+		);
+	}
+}
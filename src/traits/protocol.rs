@@ -14,5 +14,7 @@ pub trait Protocol {
 	type Config: Default;
 
 	/// Starts the protocol.
+	/// Once `Server::shutting_down` resolves, it should stop accepting connections,
+	/// and return once it has answered the requests it was already working on.
 	async fn run(self, config: Self::Config, server: Server) -> anyhow::Result<()>;
 }
@@ -0,0 +1,640 @@
+//! Dynamic content: what it's given, and how what it writes becomes the response.
+
+mod common;
+
+use std::{
+	collections::HashMap,
+	io::{Read, Write},
+	net::{Ipv4Addr, TcpListener, TcpStream},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+
+use common::{get, request, send, Logs, Site};
+use wwebs::{files::server::ServerConfig, structures::Request};
+
+#[test]
+fn cgi_etag_matching_if_none_match_answers_304() {
+	let site = Site::new();
+	site.script("page.cgi", "echo 'header ETag \"v1\"' >&2\necho body");
+	let server = site.server();
+	let conditional = |verb: &str, etag: &str| {
+		let mut request = request(verb, "/page.cgi");
+		request
+			.headers
+			.insert("If-None-Match".to_string(), etag.to_string());
+		send(&server, &mut request)
+	};
+
+	let response = conditional("GET", "\"v1\"");
+	assert_eq!(response.status, 304);
+	assert!(response.body.is_empty());
+	assert_eq!(conditional("GET", "W/\"v1\", \"v0\"").status, 304);
+
+	let response = conditional("GET", "\"v2\"");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"body\n");
+
+	// Only GET and HEAD can be answered with a 304.
+	let response = conditional("POST", "\"v1\"");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"body\n");
+}
+
+#[test]
+fn identical_concurrent_requests_share_a_run() {
+	let site = Site::new();
+	let runs = site.root.join("runs");
+	site.file(".wwebs.toml", "single_flight = true").script(
+		"slow.cgi",
+		&format!("echo run >> {}\nsleep 0.5\necho done", runs.display()),
+	);
+	let server = site.server();
+	let responses: Vec<_> = std::thread::scope(|scope| {
+		let requests: Vec<_> = (0..8)
+			.map(|_| scope.spawn(|| send(&server, &mut request("GET", "/slow.cgi"))))
+			.collect();
+		requests.into_iter().map(|r| r.join().unwrap()).collect()
+	});
+	assert!(responses
+		.iter()
+		.all(|r| r.status == 200 && r.body == b"done\n"));
+	assert_eq!(std::fs::read_to_string(runs).unwrap(), "run\n");
+}
+
+#[test]
+fn requests_from_different_clients_never_share_a_run() {
+	let site = Site::new();
+	let runs = site.root.join("runs");
+	site.file(".wwebs.toml", "single_flight = true").script(
+		"me.cgi",
+		&format!(
+			"echo \"$HEADER_COOKIE_SESSION\" >> {}\nsleep 0.5\necho \"add-header Set-Cookie seen=$HEADER_COOKIE_SESSION\" >&2\necho \"$HEADER_COOKIE_SESSION\"",
+			runs.display()
+		),
+	);
+	let server = site.server();
+	let as_session = |verb: &str, session: &str| {
+		let mut request = request(verb, "/me.cgi");
+		request
+			.headers
+			.extend(Request::cookie_headers(&format!("session={session}")));
+		send(&server, &mut request)
+	};
+	let responses: Vec<_> = std::thread::scope(|scope| {
+		let requests: Vec<_> = [
+			("GET", "alice"),
+			("GET", "bob"),
+			("GET", "alice"),
+			("POST", "alice"),
+		]
+		.into_iter()
+		.map(|(verb, session)| scope.spawn(move || (session, as_session(verb, session))))
+		.collect();
+		requests.into_iter().map(|r| r.join().unwrap()).collect()
+	});
+
+	for (session, response) in &responses {
+		assert_eq!(response.body, format!("{session}\n").as_bytes());
+		assert_eq!(response.headers["Set-Cookie"], format!("seen={session}"));
+	}
+	// The two GETs as alice share a run, while bob and the POST get their own.
+	let mut runs: Vec<String> = std::fs::read_to_string(runs)
+		.unwrap()
+		.lines()
+		.map(str::to_string)
+		.collect();
+	runs.sort();
+	assert_eq!(runs, ["alice", "alice", "bob"]);
+}
+
+#[test]
+fn scripts_that_never_read_stdin_still_answer() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "stdin_timeout = 0.2")
+		.script("ignore.cgi", "sleep 0.5\necho ignored");
+	let mut request = request("POST", "/ignore.cgi");
+	// Far more than a pipe holds, so writing it would block for as long as the script runs.
+	request.body = vec![b'x'; 4 << 20];
+	let start = std::time::Instant::now();
+	let response = send(&site.server(), &mut request);
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"ignored\n");
+	assert!(start.elapsed() < std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn legacy_text_output_is_transcoded_to_utf8() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "transcode_from = \"latin1\"")
+		.script(
+			"legacy.cgi",
+			"echo 'header Content-Type text/plain' >&2\nprintf 'caf\\351'",
+		)
+		.script(
+			"image.cgi",
+			"echo 'header Content-Type image/png' >&2\nprintf 'caf\\351'",
+		);
+	let server = site.server();
+	let response = get(&server, "/legacy.cgi");
+	assert_eq!(response.body, "café".as_bytes());
+	assert_eq!(
+		response.headers["Content-Type"],
+		"text/plain; charset=utf-8"
+	);
+	// Only text is transcoded.
+	assert_eq!(get(&server, "/image.cgi").body, b"caf\xe9");
+}
+
+#[test]
+fn output_past_the_response_size_limit_is_refused() {
+	let site = Site::new();
+	site.script("small.cgi", "head -c 100 /dev/zero")
+		.script("large.cgi", "head -c 5000 /dev/zero")
+		.script("streamed/large.cgi", "echo >&2\nhead -c 5000 /dev/zero")
+		.file("streamed/.wwebs.toml", "streaming = true");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.limits.max_response_size = Some(1000);
+	server.set_config(config);
+
+	assert_eq!(get(&server, "/small.cgi").body.len(), 100);
+	let response = get(&server, "/large.cgi");
+	assert_eq!(response.status, 500);
+	assert!(response.body.len() <= 1000);
+	// A streamed body has already started by the time it's too long, so it's cut off instead.
+	let mut response = get(&server, "/streamed/large.cgi");
+	assert!(response.buffer_stream().is_err());
+	assert!(response.body.len() <= 1000);
+}
+
+#[tokio::test]
+async fn slow_cgis_are_killed_once_the_shutdown_grace_runs_out() {
+	let site = Site::new();
+	site.script("slow.cgi", "touch \"$0.started\"\nsleep 30\necho done");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.shutdown_grace = Some(Duration::from_millis(200));
+	server.set_config(config);
+	let slow = std::thread::spawn({
+		let server = server.clone();
+		move || get(&server, "/slow.cgi")
+	});
+	while !site.root.join("slow.cgi.started").exists() {
+		std::thread::sleep(Duration::from_millis(10));
+	}
+
+	let (logs, _guard) = Logs::capture();
+	let begun = Instant::now();
+	server.finish_cgi().await;
+	let response = slow.join().unwrap();
+	assert!(begun.elapsed() < Duration::from_secs(10));
+	assert!(!response.is_ok());
+	assert_ne!(response.body, b"done\n");
+	assert!(logs.contents().contains("Killing the CGI for /slow.cgi"));
+}
+
+#[test]
+fn the_redirect_command_sets_the_status_and_location() {
+	let site = Site::new();
+	site.script("moved.cgi", "echo 'redirect /new-home' >&2")
+		.script("gone.cgi", "echo 'redirect https://example.com/ 301' >&2");
+	let server = site.server();
+
+	let response = get(&server, "/moved.cgi");
+	assert_eq!(response.status, 302);
+	assert_eq!(response.headers["Location"], "/new-home");
+	let response = get(&server, "/gone.cgi");
+	assert_eq!(response.status, 301);
+	assert_eq!(response.headers["Location"], "https://example.com/");
+}
+
+#[test]
+fn cancelling_a_request_kills_its_cgi() {
+	let site = Site::new();
+	site.script("slow.cgi", "touch \"$0.started\"\nsleep 30\necho done");
+	let server = site.server();
+	let mut slow = request("GET", "/slow.cgi");
+	let cancellation = slow.cancellation.clone();
+	let begun = Instant::now();
+	let running = std::thread::spawn(move || send(&server, &mut slow));
+	while !site.root.join("slow.cgi.started").exists() {
+		std::thread::sleep(Duration::from_millis(10));
+	}
+
+	// Like a client disconnecting.
+	cancellation.cancel();
+	let response = running.join().unwrap();
+	assert!(begun.elapsed() < Duration::from_secs(10));
+	assert_ne!(response.body, b"done\n");
+}
+
+#[test]
+fn scripts_killed_by_a_signal_are_logged_with_it() {
+	let site = Site::new();
+	site.script("crash.cgi", "echo partial\nkill -SEGV $$");
+	let server = site.server();
+	let (logs, _guard) = Logs::capture();
+	assert_eq!(get(&server, "/crash.cgi").status, 500);
+	assert!(logs
+		.contents()
+		.contains("crash.cgi was killed by signal 11 (SIGSEGV, a crash)"));
+
+	let mut config = ServerConfig::default();
+	config.signal_status = Some(502);
+	server.set_config(config);
+	assert_eq!(get(&server, "/crash.cgi").status, 502);
+}
+
+#[test]
+fn cgis_past_their_timeout_are_killed() {
+	let site = Site::new();
+	site.script("slow.cgi", "sleep 30\necho done")
+		.script("quick.cgi", "echo done")
+		.file(".wwebs.toml", "timeout = 0.5");
+	let server = site.server();
+	let (logs, _guard) = Logs::capture();
+
+	assert_eq!(get(&server, "/quick.cgi").body, b"done\n");
+	let begun = Instant::now();
+	let response = get(&server, "/slow.cgi");
+	assert!(begun.elapsed() < Duration::from_secs(10));
+	assert_eq!(response.status, 504);
+	assert!(logs
+		.contents()
+		.contains("slow.cgi, it ran for longer than its timeout"));
+}
+
+#[test]
+fn target_only_env_is_withheld_from_loggers() {
+	let site = Site::new();
+	site.script("page.cgi", "echo \"$API_KEY\"")
+		.script(".logger", "echo \"[$API_KEY] $SITE $STATUS\" > \"$0.out\"")
+		.file(
+			".wwebs.toml",
+			"target_only_env = [\"API_KEY\"]\nenv = { API_KEY = \"secret\", SITE = \"blog\" }",
+		);
+	let server = site.server();
+
+	assert_eq!(get(&server, "/page.cgi").body, b"secret\n");
+	let logged = std::fs::read_to_string(site.root.join(".logger.out")).unwrap();
+	assert_eq!(logged, "[] blog 200\n");
+}
+
+#[test]
+fn handlers_see_the_verb_of_the_request() {
+	let site = Site::new();
+	site.script(".gatekeeper", "echo \"$VERB\" > \"${0%/*}/gatekeeper\"")
+		.script(
+			".req_transformer",
+			"echo \"$VERB\" > \"${0%/*}/req_transformer\"\ncat",
+		)
+		.script(".res_transformer", "cat\necho \"transformed for $VERB\"")
+		.script("form.cgi", "echo \"$VERB\"\ncat");
+	let server = site.server();
+	let mut post = request("POST", "/form.cgi");
+	post.body = b"name=wwebs\n".to_vec();
+
+	let response = send(&server, &mut post);
+	assert_eq!(
+		String::from_utf8(response.body).unwrap(),
+		"POST\nname=wwebs\ntransformed for POST\n"
+	);
+	let verb = |handler: &str| std::fs::read_to_string(site.root.join(handler)).unwrap();
+	assert_eq!(verb("gatekeeper"), "POST\n");
+	assert_eq!(verb("req_transformer"), "POST\n");
+}
+
+#[test]
+fn cacheable_cgis_only_run_once_within_their_ttl() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "[cache]\nmax_entries = 2")
+		.script(
+			"cached.cgi",
+			"echo run >> \"$0.runs\"\necho 'cache 60' >&2\necho \"$QUERY_STRING\"",
+		)
+		.script("fresh.cgi", "echo run >> \"$0.runs\"");
+	let server = site.server();
+	let runs = |script: &str| {
+		std::fs::read_to_string(site.root.join(format!("{script}.runs")))
+			.unwrap()
+			.lines()
+			.count()
+	};
+
+	for _ in 0..3 {
+		assert_eq!(get(&server, "/cached.cgi?a").body, b"a\n");
+		get(&server, "/fresh.cgi");
+	}
+	assert_eq!(runs("cached.cgi"), 1);
+	// Only responses the CGI marks cacheable are reused.
+	assert_eq!(runs("fresh.cgi"), 3);
+
+	// Another query is another response, and past `max_entries` the least recently used is evicted.
+	get(&server, "/cached.cgi?b");
+	get(&server, "/cached.cgi?a");
+	get(&server, "/cached.cgi?c");
+	assert_eq!(runs("cached.cgi"), 3);
+	get(&server, "/cached.cgi?b");
+	assert_eq!(runs("cached.cgi"), 4);
+}
+
+/// Reads a `FastCGI` record, returning its type and content, or `None` once the connection is closed.
+fn read_record(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+	let mut header = [0; 8];
+	stream.read_exact(&mut header).ok()?;
+	let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+	let mut content = vec![0; len + usize::from(header[6])];
+	stream.read_exact(&mut content).ok()?;
+	content.truncate(len);
+	Some((header[1], content))
+}
+
+/// Writes a `FastCGI` record for request 1.
+fn write_record(stream: &mut TcpStream, kind: u8, content: &[u8]) {
+	let [len_hi, len_lo] = u16::try_from(content.len()).unwrap().to_be_bytes();
+	stream
+		.write_all(&[1, kind, 0, 1, len_hi, len_lo, 0, 0])
+		.unwrap();
+	stream.write_all(content).unwrap();
+}
+
+/// Decodes `FastCGI` params, which are all short enough for one-byte lengths in these tests.
+fn decode_params(mut bytes: &[u8]) -> HashMap<String, String> {
+	let mut params = HashMap::new();
+	while let [name_len, value_len, rest @ ..] = bytes {
+		let (name, rest) = rest.split_at(usize::from(*name_len));
+		let (value, rest) = rest.split_at(usize::from(*value_len));
+		params.insert(
+			String::from_utf8_lossy(name).to_string(),
+			String::from_utf8_lossy(value).to_string(),
+		);
+		bytes = rest;
+	}
+	params
+}
+
+/// Starts a `FastCGI` responder that answers with the verb, script and body it got,
+/// returning its address and how many connections it has accepted.
+fn fastcgi_responder() -> (String, Arc<AtomicUsize>) {
+	let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+	let address = listener.local_addr().unwrap().to_string();
+	let accepted = Arc::new(AtomicUsize::new(0));
+	std::thread::spawn({
+		let accepted = accepted.clone();
+		move || {
+			for mut stream in listener.incoming().map(Result::unwrap) {
+				accepted.fetch_add(1, Ordering::SeqCst);
+				std::thread::spawn(move || {
+					// Connections are kept open, so they can carry a request after another.
+					loop {
+						let (mut params, mut stdin) = (vec![], vec![]);
+						loop {
+							match read_record(&mut stream) {
+								None => return,
+								Some((4, content)) => params.extend(content),
+								Some((5, content)) if content.is_empty() => break,
+								Some((5, content)) => stdin.extend(content),
+								Some(_) => {}
+							}
+						}
+						let params = decode_params(&params);
+						let stdout = format!(
+							"{} {}\n{}",
+							params["VERB"],
+							params["SCRIPT_FILENAME"].rsplit('/').next().unwrap(),
+							String::from_utf8_lossy(&stdin)
+						);
+						write_record(&mut stream, 6, stdout.as_bytes());
+						write_record(&mut stream, 6, &[]);
+						write_record(&mut stream, 7, b"header X-Upstream fastcgi\n");
+						write_record(&mut stream, 7, &[]);
+						write_record(&mut stream, 3, &[0, 0, 0, 0, 0, 0, 0, 0]);
+					}
+				});
+			}
+		}
+	});
+	(address, accepted)
+}
+
+#[test]
+fn directories_can_hand_dynamic_content_to_fastcgi() {
+	let (address, accepted) = fastcgi_responder();
+	let site = Site::new();
+	site.file(".wwebs.toml", format!("fastcgi = \"{address}\""))
+		.script("app.php", "echo spawned");
+	let server = site.server();
+
+	for body in ["first", "second"] {
+		let mut post = request("POST", "/app.php");
+		post.body = body.as_bytes().to_vec();
+		let response = send(&server, &mut post);
+		assert_eq!(response.status, 200);
+		assert_eq!(response.headers["X-Upstream"], "fastcgi");
+		assert_eq!(
+			String::from_utf8(response.body).unwrap(),
+			format!("POST app.php\n{body}")
+		);
+	}
+	// The connection was kept for the second request.
+	assert_eq!(accepted.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn output_that_does_not_fit_in_memory_is_refused() {
+	let (address, _) = fastcgi_responder();
+	let site = Site::new();
+	site.script("echo.cgi", "cat")
+		.file("upstream/.wwebs.toml", format!("fastcgi = \"{address}\""))
+		.script("upstream/app.php", "echo spawned");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.limits.max_buffered_bytes = Some(100);
+	server.set_config(config);
+
+	for path in ["/echo.cgi", "/upstream/app.php"] {
+		let mut post = request("POST", path);
+		post.body = b"small".to_vec();
+		assert_eq!(send(&server, &mut post).status, 200, "{path}");
+		post.body = vec![b'a'; 200];
+		assert_eq!(send(&server, &mut post).status, 503, "{path}");
+		assert_eq!(server.buffered_bytes(), 0);
+	}
+}
+
+#[test]
+fn the_path_after_the_script_is_path_info() {
+	let site = Site::new();
+	site.script("app/script.cgi", "echo \"$SCRIPT_NAME|$PATH_INFO|$1\"")
+		.script(
+			"app/.gatekeeper2",
+			"echo \"$SCRIPT_NAME|$PATH_INFO\" > \"${0%/*}/seen\"",
+		);
+	let server = site.server();
+
+	let response = get(&server, "/app/script.cgi/extra/path");
+	assert_eq!(response.body, b"/app/script.cgi|/extra/path|extra/path\n");
+	// Handlers get the rest of the path after their directory.
+	let seen = std::fs::read_to_string(site.root.join("app/seen")).unwrap();
+	assert_eq!(seen, "/app|/script.cgi/extra/path\n");
+	assert_eq!(get(&server, "/app/script.cgi").body, b"/app/script.cgi||\n");
+}
+
+#[test]
+fn response_transformers_see_the_request_and_the_response() {
+	let site = Site::new();
+	site.script(
+		".res_transformer",
+		"cat\necho \"$VERB $REQUEST_HEADER_X_SIGNATURE $HEADER_X_FROM\"\ncat \"$REQUEST_BODY_FILE\"",
+	)
+	.script(
+		"form.cgi",
+		"echo 'header X-From form' >&2\necho response",
+	);
+	let server = site.server();
+	let mut post = request("POST", "/form.cgi");
+	post.headers
+		.insert("X-Signature".to_string(), "abc123".to_string());
+	post.body = b"request body".to_vec();
+
+	// Response headers are `HEADER_*`, like the target's output is stdin, and the request's are kept apart.
+	let response = send(&server, &mut post);
+	assert_eq!(
+		String::from_utf8(response.body).unwrap(),
+		"response\nPOST abc123 form\nrequest body"
+	);
+}
+
+#[test]
+fn the_first_gatekeeper_to_deny_answers() {
+	let site = Site::new();
+	site.script(
+		".gatekeeper",
+		"echo 'redirect /login' >&2\necho 'header X-Denied-By first' >&2\necho 'Log in first'\nexit 1",
+	)
+	.script(".gatekeeper2", "touch \"${0%/*}/second-ran\"\nexit 1")
+	.script(".req_transformer", "touch \"${0%/*}/transformed\"\ncat")
+	.script("page.cgi", "touch \"${0%/*}/target-ran\"");
+	let server = site.server();
+
+	let response = get(&server, "/page.cgi");
+	assert_eq!(response.status, 302);
+	assert_eq!(response.headers["Location"], "/login");
+	assert_eq!(response.headers["X-Denied-By"], "first");
+	assert_eq!(response.body, b"Log in first\n");
+	for skipped in ["second-ran", "transformed", "target-ran"] {
+		assert!(!site.root.join(skipped).exists(), "{skipped}");
+	}
+}
+
+#[test]
+fn a_childs_env_overrides_its_parents() {
+	let site = Site::new();
+	site.file(
+		".wwebs.toml",
+		"env = { SITE = \"parent\", THEME = \"dark\" }",
+	)
+	.file("blog/.wwebs.toml", "env = { SITE = \"child\" }")
+	.script("blog/posts/show.cgi", "echo \"$SITE $THEME\"")
+	.script("show.cgi", "echo \"$SITE $THEME\"");
+	let server = site.server();
+
+	// The nearest directory wins, even below it, and what it doesn't set is inherited.
+	assert_eq!(get(&server, "/blog/posts/show.cgi").body, b"child dark\n");
+	assert_eq!(get(&server, "/show.cgi").body, b"parent dark\n");
+}
+
+#[test]
+fn files_without_the_execute_bit_run_through_their_interpreter() {
+	let tools = Site::new();
+	tools.script("fake-python", "echo \"python ran ${1##*/}\"");
+	let site = Site::new();
+	site.file(
+		".wwebs.toml",
+		format!(
+			"[interpreters]\npy = \"{}\"",
+			tools.root.join("fake-python").display()
+		),
+	)
+	.file("hello.py", "print('hello')")
+	.script("itself.py", "echo 'ran itself'")
+	.file("notes.txt", "print('hello')");
+	let server = site.server();
+
+	assert_eq!(get(&server, "/hello.py").body, b"python ran hello.py\n");
+	// Executable files still run on their own, and other extensions are static.
+	assert_eq!(get(&server, "/itself.py").body, b"ran itself\n");
+	assert_eq!(get(&server, "/notes.txt").body, b"print('hello')");
+}
+
+#[test]
+fn exit_codes_only_become_statuses_when_they_are_ones() {
+	let site = Site::new();
+	for code in [0, 1, 42, 204] {
+		site.script(
+			&format!("exit{code}.cgi"),
+			&format!("echo out\nexit {code}"),
+		);
+	}
+	site.script("told.cgi", "echo 'status 404' >&2\nexit 1");
+	let server = site.server();
+	let status = |path: &str| get(&server, path).status;
+
+	assert_eq!(status("/exit0.cgi"), 200);
+	assert_eq!(status("/exit1.cgi"), 502);
+	assert_eq!(status("/exit42.cgi"), 502);
+	assert_eq!(status("/exit204.cgi"), 204);
+	// A `status` command wins over the exit code.
+	assert_eq!(status("/told.cgi"), 404);
+}
+
+#[test]
+fn stderr_is_logged_with_the_script_and_request() {
+	let site = Site::new();
+	site.script(
+		"form.cgi",
+		"echo 'log saved the form' >&2\necho 'oops: no such file' >&2",
+	);
+	let server = site.server();
+	let (logs, _guard) = Logs::capture();
+
+	send(&server, &mut request("POST", "/form.cgi?step=2"));
+	let context = format!(
+		"{} (POST /form.cgi?step=2)",
+		site.root.join("form.cgi").display()
+	);
+	let logs = logs.contents();
+	let line = |needle: &str| {
+		logs.lines()
+			.find(|line| line.contains(needle))
+			.unwrap_or_else(|| panic!("{needle} isn't in {logs}"))
+	};
+	assert!(line(&format!("{context}: saved the form")).contains("INFO"));
+	// Lines that aren't commands are marked as the script's errors.
+	assert!(line(&format!("{context} (stderr): oops: no such file")).contains("WARN"));
+}
+
+#[test]
+fn what_a_request_logs_is_in_its_span() {
+	let site = Site::new();
+	site.script("hello.cgi", "echo 'log said hello' >&2\necho hello");
+	let server = site.server();
+	let (logs, _guard) = Logs::capture();
+
+	get(&server, "/hello.cgi?to=you");
+	let path = site.root.join("hello.cgi");
+	let span = format!(
+		"request{{verb=GET url=http://localhost/hello.cgi?to=you path={}}}",
+		path.display()
+	);
+	let logs = logs.contents();
+	assert!(
+		logs.lines()
+			.any(|line| line.contains(&span) && line.contains("said hello")),
+		"{span} isn't in {logs}"
+	);
+}
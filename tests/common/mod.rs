@@ -0,0 +1,351 @@
+//! Helpers shared by the integration tests: sites in temporary directories, and requests to them.
+// Each test binary only uses some of these.
+#![allow(dead_code)]
+
+use std::{
+	collections::HashMap,
+	fs, io,
+	os::unix::fs::PermissionsExt,
+	path::PathBuf,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
+};
+
+use tracing::subscriber::DefaultGuard;
+use tracing_subscriber::fmt::MakeWriter;
+
+use wwebs::{
+	files::wwebs::WWebS,
+	server::Server,
+	structures::{Request, Response},
+};
+
+/// A web directory in a temporary directory of its own, removed once it's dropped.
+pub struct Site {
+	/// Where the site is.
+	pub root: PathBuf,
+}
+
+impl Site {
+	/// Creates an empty site.
+	pub fn new() -> Site {
+		static SITES: AtomicUsize = AtomicUsize::new(0);
+		let root = std::env::temp_dir().join(format!(
+			"wwebs-test-{}-{}",
+			std::process::id(),
+			SITES.fetch_add(1, Ordering::SeqCst)
+		));
+		let _ = fs::remove_dir_all(&root);
+		fs::create_dir_all(&root).unwrap();
+		Site { root }
+	}
+
+	/// Writes a file (readable by everyone), creating the directories it's in.
+	pub fn file(&self, path: &str, contents: impl AsRef<[u8]>) -> &Site {
+		let path = self.root.join(path);
+		fs::create_dir_all(path.parent().unwrap()).unwrap();
+		fs::write(&path, contents).unwrap();
+		fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+		self
+	}
+
+	/// Writes a shell script, executable by everyone, so it's dynamic content.
+	pub fn script(&self, path: &str, script: &str) -> &Site {
+		self.file(path, format!("#!/bin/sh\n{script}\n"));
+		fs::set_permissions(self.root.join(path), fs::Permissions::from_mode(0o755)).unwrap();
+		self
+	}
+
+	/// A server for the site, with the default configuration.
+	pub fn server(&self) -> Server {
+		Server::new(self.root.clone())
+	}
+}
+
+impl Drop for Site {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.root);
+	}
+}
+
+/// A request for a path (which may have a query), with the given verb.
+pub fn request(verb: &str, path: &str) -> Request {
+	let mut request = Request::default();
+	request.verb = verb.to_string();
+	request.url = request.url.join(path).unwrap();
+	request
+}
+
+/// Answers a request the way the protocols do.
+pub fn send(server: &Server, request: &mut Request) -> Response {
+	server.exec(request, 0, &mut WWebS::default())
+}
+
+/// Answers a `GET` for a path.
+pub fn get(server: &Server, path: &str) -> Response {
+	send(server, &mut request("GET", path))
+}
+
+/// What's logged on the current thread while capturing.
+#[derive(Clone, Default)]
+pub struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+	/// Captures everything logged on the current thread, until the guard is dropped.
+	/// Async tests need a single-threaded runtime (the default for `tokio::test`) for their tasks' logs to be captured.
+	pub fn capture() -> (Logs, DefaultGuard) {
+		let logs = Logs::default();
+		let subscriber = tracing_subscriber::fmt()
+			.with_writer(logs.clone())
+			.with_ansi(false)
+			.with_max_level(tracing::Level::DEBUG)
+			.finish();
+		(logs, tracing::subscriber::set_default(subscriber))
+	}
+
+	/// Everything logged so far.
+	pub fn contents(&self) -> String {
+		String::from_utf8_lossy(&self.0.lock().unwrap()).to_string()
+	}
+}
+
+impl io::Write for Logs {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl MakeWriter<'_> for Logs {
+	type Writer = Logs;
+
+	fn make_writer(&self) -> Logs {
+		self.clone()
+	}
+}
+
+/// A response as it came over the wire.
+#[derive(Debug)]
+pub struct Raw {
+	/// The status code.
+	pub status: u16,
+	/// The headers, with lowercase names. Headers sent more than once are joined with newlines.
+	pub headers: HashMap<String, String>,
+	/// The body, with any chunked encoding undone.
+	pub body: Vec<u8>,
+}
+
+impl Raw {
+	/// Parses a whole HTTP/1.1 response.
+	pub fn parse(bytes: &[u8]) -> Raw {
+		let end = bytes
+			.windows(4)
+			.position(|w| w == b"\r\n\r\n")
+			.expect("the response has no end of headers");
+		let head = String::from_utf8_lossy(&bytes[..end]);
+		let mut lines = head.split("\r\n");
+		let status = lines
+			.next()
+			.unwrap()
+			.split(' ')
+			.nth(1)
+			.unwrap()
+			.parse()
+			.unwrap();
+		let mut headers: HashMap<String, String> = HashMap::new();
+		for line in lines {
+			let (name, value) = line.split_once(':').unwrap();
+			headers
+				.entry(name.to_ascii_lowercase())
+				.and_modify(|values| {
+					values.push('\n');
+					values.push_str(value.trim());
+				})
+				.or_insert_with(|| value.trim().to_string());
+		}
+		let mut body = bytes[end + 4..].to_vec();
+		if headers.get("transfer-encoding").map(String::as_str) == Some("chunked") {
+			body = dechunk(&body);
+		}
+		Raw {
+			status,
+			headers,
+			body,
+		}
+	}
+
+	/// A header's value, by its lowercase name.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		self.headers.get(name).map(String::as_str)
+	}
+}
+
+/// Undoes the chunked transfer encoding.
+fn dechunk(mut bytes: &[u8]) -> Vec<u8> {
+	let mut body = vec![];
+	loop {
+		let line_end = bytes.windows(2).position(|w| w == b"\r\n").unwrap();
+		let size = std::str::from_utf8(&bytes[..line_end]).unwrap();
+		let size = usize::from_str_radix(size.split(';').next().unwrap().trim(), 16).unwrap();
+		if size == 0 {
+			return body;
+		}
+		let start = line_end + 2;
+		body.extend_from_slice(&bytes[start..start + size]);
+		bytes = &bytes[start + size + 2..];
+	}
+}
+
+/// Serving sites over HTTP, and talking to them with handwritten requests.
+#[cfg(feature = "http")]
+pub mod http {
+	use std::{
+		fs,
+		net::{Ipv4Addr, TcpListener},
+		os::unix::fs::PermissionsExt,
+	};
+
+	use openssl::{
+		asn1::Asn1Time,
+		bn::BigNum,
+		hash::MessageDigest,
+		pkey::PKey,
+		rsa::Rsa,
+		x509::{X509NameBuilder, X509},
+	};
+	use tokio::{
+		io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+		net::TcpStream,
+	};
+	use tokio_native_tls::{native_tls, TlsConnector};
+	use wwebs::{
+		http::{Http, HttpConfig, TlsConfig},
+		server::Server,
+		traits::Protocol,
+	};
+
+	use super::{Raw, Site};
+
+	/// Serves a server over HTTP on a free port of the configured address (localhost by default),
+	/// returning the port once it's listening.
+	pub async fn serve(server: &Server, mut config: HttpConfig) -> u16 {
+		let ip = config.ip;
+		// The port is only free until something else takes it, but nothing else here listens.
+		let port = TcpListener::bind((ip, 0))
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port();
+		config.port = port;
+		tokio::spawn(Http.run(config, server.clone()));
+		// The server may already have other listeners, so this one is waited for by connecting to it.
+		while TcpStream::connect((ip, port)).await.is_err() {
+			tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+		}
+		port
+	}
+
+	/// Sends raw bytes over a new connection, and reads until the server closes it.
+	pub async fn exchange(port: u16, request: &[u8]) -> Vec<u8> {
+		let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+			.await
+			.unwrap();
+		exchange_over(stream, request).await
+	}
+
+	/// Sends raw bytes over a stream, and reads until the server closes it.
+	pub async fn exchange_over(
+		mut stream: impl AsyncRead + AsyncWrite + Unpin,
+		request: &[u8],
+	) -> Vec<u8> {
+		stream.write_all(request).await.unwrap();
+		let mut response = vec![];
+		stream.read_to_end(&mut response).await.unwrap();
+		response
+	}
+
+	/// Sends a request with the given method, target and extra header lines, closing the connection after it.
+	pub async fn send(port: u16, method: &str, target: &str, headers: &str) -> Raw {
+		let request = format!(
+			"{method} {target} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{headers}\r\n"
+		);
+		Raw::parse(&exchange(port, request.as_bytes()).await)
+	}
+
+	/// Writes a self-signed certificate for `localhost` and its key into a site, only readable by their owner so they aren't served,
+	/// and returns the configuration to serve HTTPS with them.
+	pub fn certificate(site: &Site) -> TlsConfig {
+		let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+		let mut name = X509NameBuilder::new().unwrap();
+		name.append_entry_by_text("CN", "localhost").unwrap();
+		let name = name.build();
+		let mut certificate = X509::builder().unwrap();
+		certificate.set_version(2).unwrap();
+		let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+		certificate.set_serial_number(&serial).unwrap();
+		certificate.set_subject_name(&name).unwrap();
+		certificate.set_issuer_name(&name).unwrap();
+		certificate.set_pubkey(&key).unwrap();
+		certificate
+			.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+			.unwrap();
+		certificate
+			.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+			.unwrap();
+		certificate.sign(&key, MessageDigest::sha256()).unwrap();
+		let config = TlsConfig {
+			public: site.root.join("cert.pem"),
+			private: site.root.join("key.pem"),
+			client_certs: false,
+		};
+		fs::write(&config.public, certificate.build().to_pem().unwrap()).unwrap();
+		fs::write(&config.private, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+		for path in [&config.public, &config.private] {
+			fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+		}
+		config
+	}
+
+	/// Like `send`, but over TLS, trusting whatever certificate the server has.
+	pub async fn send_tls(port: u16, method: &str, target: &str, headers: &str) -> Raw {
+		send_tls_as(None, port, method, target, headers).await
+	}
+
+	/// Like `send_tls`, presenting the certificate and key of a `TlsConfig` as the client's, if given.
+	pub async fn send_tls_as(
+		client: Option<&TlsConfig>,
+		port: u16,
+		method: &str,
+		target: &str,
+		headers: &str,
+	) -> Raw {
+		let mut connector = native_tls::TlsConnector::builder();
+		connector.danger_accept_invalid_certs(true);
+		if let Some(client) = client {
+			let identity = native_tls::Identity::from_pkcs8(
+				&fs::read(&client.public).unwrap(),
+				&fs::read(&client.private).unwrap(),
+			)
+			.unwrap();
+			connector.identity(identity);
+		}
+		let connector = connector.build().unwrap();
+		let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+			.await
+			.unwrap();
+		let stream = TlsConnector::from(connector)
+			.connect("localhost", stream)
+			.await
+			.unwrap();
+		let request = format!(
+			"{method} {target} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{headers}\r\n"
+		);
+		Raw::parse(&exchange_over(stream, request.as_bytes()).await)
+	}
+}
@@ -0,0 +1,401 @@
+//! Static files, and how they're served.
+
+mod common;
+
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::Read,
+	os::unix::fs::PermissionsExt,
+	time::{Duration, SystemTime},
+};
+
+use common::{get, request, send, Site};
+use flate2::read::GzDecoder;
+use wwebs::files::server::ServerConfig;
+
+#[test]
+fn static_conditionals_only_answer_304_to_get_and_head() {
+	let site = Site::new();
+	site.file("page.html", "hello");
+	let server = site.server();
+	let etag = get(&server, "/page.html").headers["ETag"].clone();
+	let conditional = |verb: &str| {
+		let mut request = request(verb, "/page.html");
+		request
+			.headers
+			.insert("If-None-Match".to_string(), etag.clone());
+		send(&server, &mut request)
+	};
+
+	assert_eq!(conditional("GET").status, 304);
+	assert_eq!(conditional("HEAD").status, 304);
+	let response = conditional("POST");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"hello");
+}
+
+#[test]
+fn preload_links_are_added_to_matching_html() {
+	let site = Site::new();
+	site.file(
+		".wwebs.toml",
+		r#"
+		[preload]
+		"/blog/*.html" = ["</style.css>; rel=preload; as=style", "</font.woff2>; rel=preload; as=font"]
+		"#,
+	)
+	.file("blog/post.html", "post")
+	.file("blog/post.txt", "post")
+	.file("about.html", "about");
+	let server = site.server();
+	assert_eq!(
+		get(&server, "/blog/post.html").headers["Link"],
+		"</style.css>; rel=preload; as=style, </font.woff2>; rel=preload; as=font"
+	);
+	assert!(!get(&server, "/blog/post.txt").headers.contains_key("Link"));
+	assert!(!get(&server, "/about.html").headers.contains_key("Link"));
+}
+
+#[test]
+fn head_reports_the_length_without_a_body() {
+	let site = Site::new();
+	site.file("page.html", "hello world")
+		.script("dynamic.cgi", "echo 'header X-Dynamic yes' >&2\necho hello");
+	let server = site.server();
+
+	let response = server.head(&mut request("GET", "/page.html"));
+	assert_eq!(response.status, 200);
+	assert_eq!(response.headers["Content-Length"], "11");
+	assert!(response.body.is_empty());
+
+	let response = server.head(&mut request("GET", "/dynamic.cgi"));
+	assert_eq!(response.headers["Content-Length"], "6");
+	assert_eq!(response.headers["X-Dynamic"], "yes");
+	assert!(response.body.is_empty());
+
+	assert_eq!(server.head(&mut request("GET", "/missing")).status, 404);
+}
+
+#[test]
+fn compressed_files_are_recompressed_once_modified() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "[compression]\nenabled = true\nmin_size = 1");
+	site.file("page.html", "first");
+	let server = site.server();
+	let fetch = || {
+		let mut request = request("GET", "/page.html");
+		request
+			.headers
+			.insert("Accept-Encoding".to_string(), "gzip".to_string());
+		let response = send(&server, &mut request);
+		assert_eq!(response.headers["Content-Encoding"], "gzip");
+		let mut body = String::new();
+		GzDecoder::new(&response.body[..])
+			.read_to_string(&mut body)
+			.unwrap();
+		body
+	};
+	assert_eq!(fetch(), "first");
+	assert_eq!(fetch(), "first");
+
+	site.file("page.html", "second");
+	// The file may be rewritten within the filesystem's timestamp resolution, so it's moved on explicitly.
+	let modified = SystemTime::now() + Duration::from_secs(10);
+	File::options()
+		.write(true)
+		.open(site.root.join("page.html"))
+		.unwrap()
+		.set_modified(modified)
+		.unwrap();
+	assert_eq!(fetch(), "second");
+}
+
+#[test]
+fn well_known_files_are_served_but_handlers_are_not() {
+	let site = Site::new();
+	site.file(
+		".well-known/security.txt",
+		"Contact: mailto:security@example.com",
+	)
+	.file(".wwebs.toml", "")
+	.script(".gatekeeper", "exit 0");
+	let server = site.server();
+	let response = get(&server, "/.well-known/security.txt");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"Contact: mailto:security@example.com");
+	assert_eq!(get(&server, "/.wwebs.toml").status, 404);
+	assert_eq!(get(&server, "/.gatekeeper").status, 404);
+}
+
+#[test]
+fn ranges_of_cached_files_are_sliced_from_the_cache() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "[cache]\nttl = 60")
+		.file("video.bin", "0123456789");
+	let server = site.server();
+	let ranged = |range: &str| {
+		let mut request = request("GET", "/video.bin");
+		request
+			.headers
+			.insert("Range".to_string(), range.to_string());
+		send(&server, &mut request)
+	};
+	assert_eq!(get(&server, "/video.bin").body, b"0123456789");
+
+	// The cached bytes are sliced, so changing the file doesn't show until the cache expires.
+	site.file("video.bin", "abcdefghij");
+	let response = ranged("bytes=2-5");
+	assert_eq!(response.status, 206);
+	assert_eq!(response.body, b"2345");
+	assert_eq!(response.headers["Content-Range"], "bytes 2-5/10");
+	let response = ranged("bytes=-3");
+	assert_eq!(response.body, b"789");
+	let response = ranged("bytes=20-");
+	assert_eq!(response.status, 416);
+	assert_eq!(response.headers["Content-Range"], "bytes */10");
+	assert_eq!(get(&server, "/video.bin").body, b"0123456789");
+}
+
+#[test]
+fn cache_control_depends_on_the_extension() {
+	let site = Site::new();
+	site.file("logo.png", "png")
+		.file("page.html", "html")
+		.file("bundle.min.js", "js")
+		.file("notes.txt", "txt");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.cache_control = Some(HashMap::from([
+		(
+			"png".to_string(),
+			"public, max-age=31536000, immutable".to_string(),
+		),
+		(".html".to_string(), "no-cache".to_string()),
+		("js".to_string(), "max-age=60".to_string()),
+		("min.js".to_string(), "max-age=3600".to_string()),
+	]));
+	server.set_config(config);
+	let cache_control = |path: &str| get(&server, path).headers.get("Cache-Control").cloned();
+
+	assert_eq!(
+		cache_control("/logo.png").as_deref(),
+		Some("public, max-age=31536000, immutable")
+	);
+	assert_eq!(cache_control("/page.html").as_deref(), Some("no-cache"));
+	// The longest matching extension wins.
+	assert_eq!(
+		cache_control("/bundle.min.js").as_deref(),
+		Some("max-age=3600")
+	);
+	assert_eq!(cache_control("/notes.txt"), None);
+}
+
+#[test]
+fn the_server_wide_error_page_is_the_fallback() {
+	let site = Site::new();
+	site.file("errors/404.html", "<h1>Lost?</h1>")
+		.file("docs/.wwebs.toml", "[errors]\n404 = \"missing.txt\"")
+		.file("docs/missing.txt", "No such doc")
+		.script("broken.cgi", "echo 'status 404' >&2\necho 'My own 404'");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.error_pages = Some(HashMap::from([(
+		"404".to_string(),
+		"errors/404.html".to_string(),
+	)]));
+	server.set_config(config);
+
+	let response = get(&server, "/nowhere");
+	assert_eq!(response.status, 404);
+	assert_eq!(response.body, b"<h1>Lost?</h1>");
+	assert_eq!(response.headers["Content-Type"], "text/html; charset=utf-8");
+	// A directory's own page takes precedence, and a CGI's error page is kept.
+	assert_eq!(get(&server, "/docs/nowhere").body, b"No such doc");
+	assert_eq!(get(&server, "/broken.cgi").body, b"My own 404\n");
+}
+
+#[test]
+fn downloads_name_their_file_even_if_it_is_not_ascii() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "download_extensions = [\"zip\"]")
+		.file("Résumé 2024.zip", "zip")
+		.file("plain.zip", "zip")
+		.file("page.html", "page");
+	let server = site.server();
+	let disposition = |path: &str| {
+		get(&server, path)
+			.headers
+			.get("Content-Disposition")
+			.cloned()
+	};
+
+	assert_eq!(
+		disposition("/R%C3%A9sum%C3%A9%202024.zip").as_deref(),
+		Some("attachment; filename=\"R_sum_ 2024.zip\"; filename*=UTF-8''R%C3%A9sum%C3%A9%202024.zip")
+	);
+	assert_eq!(
+		disposition("/plain.zip").as_deref(),
+		Some("attachment; filename=\"plain.zip\"")
+	);
+	assert_eq!(disposition("/page.html"), None);
+}
+
+#[test]
+fn directories_without_an_index_can_be_listed() {
+	let site = Site::new();
+	site.file("files/.wwebs.toml", "[resolution]\nautoindex = true")
+		.file("files/notes 10.txt", "")
+		.file("files/notes 9.txt", "")
+		.file("files/private.txt", "")
+		.file("files/sub/page.txt", "")
+		.script("files/.gatekeeper", "exit 0")
+		.file("closed/page.txt", "");
+	std::fs::set_permissions(
+		site.root.join("files/private.txt"),
+		std::fs::Permissions::from_mode(0o600),
+	)
+	.unwrap();
+	let server = site.server();
+
+	let response = get(&server, "/files/");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.headers["Content-Type"], "text/html; charset=utf-8");
+	let html = String::from_utf8(response.body).unwrap();
+	assert!(html.contains("<title>Index of /files/</title>"));
+	let links: Vec<&str> = html
+		.lines()
+		.filter(|line| line.starts_with("<li>"))
+		.collect();
+	assert_eq!(
+		links,
+		[
+			"<li><a href=\"/files/notes%209.txt\">notes 9.txt</a></li>",
+			"<li><a href=\"/files/notes%2010.txt\">notes 10.txt</a></li>",
+			"<li><a href=\"/files/sub/\">sub/</a></li>",
+		]
+	);
+
+	let mut over_gemini = request("GET", "/files");
+	over_gemini.proto = "Gemini";
+	let response = send(&server, &mut over_gemini);
+	assert_eq!(response.headers["Content-Type"], "text/gemini");
+	assert_eq!(
+		String::from_utf8(response.body).unwrap(),
+		"# Index of /files\n\n=> /files/notes%209.txt notes 9.txt\n=> /files/notes%2010.txt notes 10.txt\n=> /files/sub/ sub/\n"
+	);
+
+	// It's opt-in.
+	assert_eq!(get(&server, "/closed/").status, 404);
+}
+
+#[test]
+fn ranges_of_files_are_served_partially() {
+	let contents: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+	let site = Site::new();
+	site.file("video.bin", &contents);
+	let server = site.server();
+	let ranged = |range: &str| {
+		let mut request = request("GET", "/video.bin");
+		request
+			.headers
+			.insert("Range".to_string(), range.to_string());
+		send(&server, &mut request)
+	};
+
+	let response = ranged("bytes=100-");
+	assert_eq!(response.status, 206);
+	assert_eq!(response.body, &contents[100..]);
+	assert_eq!(response.headers["Content-Range"], "bytes 100-999/1000");
+	let response = ranged("bytes=-500");
+	assert_eq!(response.status, 206);
+	assert_eq!(response.body, &contents[500..]);
+	assert_eq!(response.headers["Content-Range"], "bytes 500-999/1000");
+	// Ranges running past the end are cut short.
+	let response = ranged("bytes=990-2000");
+	assert_eq!(response.body, &contents[990..]);
+	assert_eq!(response.headers["Content-Range"], "bytes 990-999/1000");
+
+	let response = ranged("bytes=1000-");
+	assert_eq!(response.status, 416);
+	assert_eq!(response.headers["Content-Range"], "bytes */1000");
+	assert!(response.body.is_empty());
+	// Ranges that can't be parsed are ignored, so the whole file is served.
+	let response = ranged("lines=1-2");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, contents);
+}
+
+#[test]
+fn if_none_match_answers_304_only_for_the_current_etag() {
+	let site = Site::new();
+	site.file("page.html", "hello");
+	let server = site.server();
+	let response = get(&server, "/page.html");
+	let etag = response.headers["ETag"].clone();
+	let last_modified = response.headers["Last-Modified"].clone();
+	let conditional = |header: &str, value: &str| {
+		let mut request = request("GET", "/page.html");
+		request
+			.headers
+			.insert(header.to_string(), value.to_string());
+		send(&server, &mut request)
+	};
+
+	let response = conditional("If-None-Match", &etag);
+	assert_eq!(response.status, 304);
+	assert!(response.body.is_empty());
+	assert_eq!(response.headers["ETag"], etag);
+	assert_eq!(
+		conditional("If-None-Match", &format!("W/{etag}")).status,
+		304
+	);
+	assert_eq!(
+		conditional("If-None-Match", &format!("\"other\", {etag}")).status,
+		304
+	);
+	assert_eq!(conditional("If-None-Match", "*").status, 304);
+	let response = conditional("If-None-Match", "\"other\"");
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"hello");
+	assert_eq!(conditional("If-Modified-Since", &last_modified).status, 304);
+
+	// Changing the file changes its ETag.
+	site.file("page.html", "hello again");
+	let response = get(&server, "/page.html");
+	assert_ne!(response.headers["ETag"], etag);
+	let response = conditional("If-None-Match", &etag);
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"hello again");
+}
+
+#[test]
+fn a_top_level_error_page_applies_to_every_directory() {
+	let site = Site::new();
+	site.file(
+		".wwebs.toml",
+		"[errors]\n404 = \"404.html\"\n500 = \"gone.html\"",
+	)
+	.file("404.html", "<h1>Not here</h1>")
+	.file("a/b/page.txt", "hello")
+	.file("a/b/.wwebs.toml", "[errors]\n500 = \"500.txt\"")
+	.file("a/b/500.txt", "Broken below")
+	.script("a/b/fail.cgi", "echo 'status 500' >&2");
+	let server = site.server();
+
+	// The page is found by walking up from the deepest directory, and the status is kept.
+	for path in ["/missing", "/a/missing", "/a/b/missing"] {
+		let response = get(&server, path);
+		assert_eq!(response.status, 404, "{path}");
+		assert_eq!(response.body, b"<h1>Not here</h1>", "{path}");
+		assert_eq!(response.headers["Content-Type"], "text/html; charset=utf-8");
+	}
+	let response = get(&server, "/a/b/fail.cgi");
+	assert_eq!(response.status, 500);
+	assert_eq!(response.body, b"Broken below");
+	// Without its own page, the top-level one is used, and one that doesn't exist leaves the body alone.
+	site.file("a/b/.wwebs.toml", "");
+	let response = get(&server, "/a/b/fail.cgi");
+	assert_eq!(response.status, 500);
+	assert!(response.body.is_empty());
+}
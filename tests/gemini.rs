@@ -0,0 +1,193 @@
+//! Serving sites over Gemini, going through the conversions the protocol makes.
+#![cfg(feature = "gemini")]
+
+mod common;
+
+use std::{fs, os::unix::fs::PermissionsExt};
+
+use common::{get, send, Site};
+use url::Url;
+use wwebs::{
+	gemini::{GRequest, GResponse},
+	server::Server,
+	structures::Request,
+};
+
+/// A request for a URL, like windmark hands one over.
+fn gemini_request(url: &str) -> GRequest {
+	GRequest {
+		url: Url::parse(url).unwrap(),
+		user_cert: None,
+		verb: None,
+		body: None,
+		remote_addr: None,
+		local_addr: None,
+	}
+}
+
+/// Answers a request, converting it and the response like the protocol does.
+fn answer(server: &Server, request: GRequest) -> GResponse {
+	let mut request: Request = request.into();
+	send(server, &mut request).into()
+}
+
+#[test]
+fn requests_can_carry_a_verb_and_body() {
+	let site = Site::new();
+	site.script("upload.cgi", "echo \"$VERB\"\ncat");
+	let request = GRequest {
+		verb: Some("PUT".to_string()),
+		body: Some(b"uploaded".to_vec()),
+		..gemini_request("titan://localhost/upload.cgi")
+	};
+	let response = answer(&site.server(), request);
+	assert_eq!(response.status, 20);
+	assert_eq!(response.body, b"PUT\nuploaded");
+
+	// Plain Gemini requests are GETs without a body.
+	let request: Request = gemini_request("gemini://localhost/").into();
+	assert_eq!(request.verb, "GET");
+	assert!(request.body.is_empty());
+}
+
+#[test]
+fn cgis_can_set_the_language_and_charset_of_the_meta() {
+	let site = Site::new();
+	site.script(
+		"hello.cgi",
+		"echo 'lang en' >&2\necho 'charset utf-8' >&2\necho '# Hello'",
+	);
+	let response = answer(
+		&site.server(),
+		gemini_request("gemini://localhost/hello.cgi"),
+	);
+	assert_eq!(response.status, 20);
+	let mut params: Vec<&str> = response.meta.split(';').map(str::trim).collect();
+	assert_eq!(params.remove(0), "text/gemini");
+	params.sort_unstable();
+	assert_eq!(params, ["charset=utf-8", "lang=en"]);
+	assert_eq!(response.body, b"# Hello\n");
+}
+
+#[test]
+fn only_allowed_client_certificates_get_in() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "allowed_client_certs = [\"trusted\"]")
+		.file("index.gmi", "# Members only");
+	let server = site.server();
+	let with_cert = |cert: Option<&str>| {
+		let request = GRequest {
+			user_cert: cert.map(str::to_string),
+			..gemini_request("gemini://localhost/index.gmi")
+		};
+		answer(&server, request)
+	};
+
+	let response = with_cert(Some("trusted"));
+	assert_eq!(response.status, 20);
+	assert_eq!(response.body, b"# Members only");
+	assert_eq!(with_cert(Some("stranger")).status, 61);
+	assert_eq!(with_cert(None).status, 60);
+}
+
+#[test]
+fn the_default_index_can_depend_on_the_protocol() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "[resolution]\nprotocol_index = true")
+		.file("index.html", "<h1>Web</h1>")
+		.file("index.gmi", "# Gemini")
+		.file("explicit/.wwebs.toml", "[resolution]\nindex = \"home.txt\"")
+		.file("explicit/index.gmi", "# Ignored")
+		.file("explicit/home.txt", "Home");
+	let server = site.server();
+	let over_gemini = |url: &str| answer(&server, gemini_request(url)).body;
+
+	assert_eq!(get(&server, "/").body, b"<h1>Web</h1>");
+	assert_eq!(over_gemini("gemini://localhost/"), b"# Gemini");
+	// An explicit index applies to both.
+	assert_eq!(get(&server, "/explicit/").body, b"Home");
+	assert_eq!(over_gemini("gemini://localhost/explicit/"), b"Home");
+
+	// It's opt-in, so otherwise both get the same index.
+	std::fs::remove_file(site.root.join(".wwebs.toml")).unwrap();
+	assert_eq!(over_gemini("gemini://localhost/"), b"<h1>Web</h1>");
+}
+
+#[test]
+fn index_candidates_are_tried_in_order_and_by_protocol() {
+	let site = Site::new();
+	site.file(
+		"list/.wwebs.toml",
+		"[resolution]\nindex = [\"missing.html\", \"private.html\", \"index.htm\", \"index.txt\"]",
+	)
+	.file("list/private.html", "Secret")
+	.file("list/index.htm", "Fallback")
+	.file("list/index.txt", "Too late")
+	.file(
+		"map/.wwebs.toml",
+		"[resolution.index]\nGemini = [\"index.gmi\", \"index.html\"]\ndefault = \"index.html\"",
+	)
+	.file("map/index.gmi", "# Capsule")
+	.file("map/index.html", "<h1>Site</h1>")
+	.file(
+		"fallback/.wwebs.toml",
+		"[resolution.index]\nGemini = [\"index.gmi\", \"index.html\"]",
+	)
+	.file("fallback/index.html", "<h1>Only</h1>");
+	fs::set_permissions(
+		site.root.join("list/private.html"),
+		fs::Permissions::from_mode(0o600),
+	)
+	.unwrap();
+	let server = site.server();
+	let over_gemini = |url: &str| answer(&server, gemini_request(url)).body;
+
+	// The first candidate that exists and can be served wins.
+	assert_eq!(get(&server, "/list/").body, b"Fallback");
+	assert_eq!(get(&server, "/map/").body, b"<h1>Site</h1>");
+	assert_eq!(over_gemini("gemini://localhost/map/"), b"# Capsule");
+	assert_eq!(
+		over_gemini("gemini://localhost/fallback/"),
+		b"<h1>Only</h1>"
+	);
+	// Protocols that aren't listed, without a default, get the usual index.
+	assert_eq!(get(&server, "/fallback/").body, b"<h1>Only</h1>");
+}
+
+#[test]
+fn input_prompts_are_answered_in_the_query() {
+	let site = Site::new();
+	site.script(
+		"ask.cgi",
+		"if [ -z \"$QUERY_STRING\" ]; then\n\techo 'status 600' >&2\n\tprintf 'Your name?'\nelse\n\techo \"# Hello, $QUERY_INPUT\"\nfi",
+	)
+	.script("secret.cgi", "echo 'status 601' >&2\nprintf 'Password?'");
+	let server = site.server();
+
+	let prompt = answer(&server, gemini_request("gemini://localhost/ask.cgi"));
+	assert_eq!((prompt.status, prompt.meta.as_str()), (10, "Your name?"));
+	// The client asks again with the answer as the whole query, percent-encoded.
+	let response = answer(
+		&server,
+		gemini_request("gemini://localhost/ask.cgi?Jane%20Doe%20%26%20co"),
+	);
+	assert_eq!(response.status, 20);
+	assert_eq!(response.body, b"# Hello, Jane Doe & co\n");
+	let prompt = answer(&server, gemini_request("gemini://localhost/secret.cgi"));
+	assert_eq!((prompt.status, prompt.meta.as_str()), (11, "Password?"));
+}
+
+#[test]
+fn binary_bodies_are_passed_on_untouched() {
+	// A PNG's signature and the start of its header, which aren't valid UTF-8.
+	let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\xff\xfe";
+	let site = Site::new();
+	site.file("pixel.png", png);
+	let response = answer(
+		&site.server(),
+		gemini_request("gemini://localhost/pixel.png"),
+	);
+	assert_eq!(response.status, 20);
+	assert_eq!(response.meta, "image/png");
+	assert_eq!(response.body, png);
+}
@@ -0,0 +1,719 @@
+//! Serving sites over HTTP, as clients see it.
+#![cfg(feature = "http")]
+
+mod common;
+
+use std::{
+	io::Read,
+	net::{Ipv4Addr, Ipv6Addr},
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use common::{http, Logs, Raw, Site};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use futures_util::{SinkExt, StreamExt};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+use tokio_tungstenite::tungstenite::Message;
+use wwebs::{
+	files::server::ServerConfig,
+	http::{Hsts, HttpConfig, TlsConfig},
+	structures::Response,
+};
+
+#[tokio::test]
+async fn options_star_allows_the_configured_methods() {
+	let site = Site::new();
+	let config = HttpConfig {
+		allowed_methods: vec!["GET".to_string(), "OPTIONS".to_string()],
+		..Default::default()
+	};
+	let port = http::serve(&site.server(), config).await;
+
+	let response = http::send(port, "OPTIONS", "*", "").await;
+	assert_eq!(response.status, 204);
+	assert_eq!(response.header("allow"), Some("GET, OPTIONS"));
+	assert!(response.body.is_empty());
+}
+
+#[tokio::test]
+async fn hsts_is_only_sent_over_tls() {
+	let site = Site::new();
+	site.file("index.html", "home");
+	let server = site.server();
+	let config = || HttpConfig {
+		hsts: Some(Hsts {
+			max_age: 31_536_000,
+			include_subdomains: true,
+			preload: false,
+		}),
+		alt_svc: Some("h3=\":443\"; ma=86400".to_string()),
+		..Default::default()
+	};
+	let plain = http::serve(&server, config()).await;
+	let secure = http::serve(
+		&server,
+		HttpConfig {
+			tls: Some(http::certificate(&site)),
+			..config()
+		},
+	)
+	.await;
+
+	let response = http::send(plain, "GET", "/", "").await;
+	assert_eq!(response.body, b"home");
+	assert_eq!(response.header("strict-transport-security"), None);
+	assert_eq!(response.header("alt-svc"), Some("h3=\":443\"; ma=86400"));
+
+	let response = http::send_tls(secure, "GET", "/", "").await;
+	assert_eq!(response.body, b"home");
+	assert_eq!(
+		response.header("strict-transport-security"),
+		Some("max-age=31536000; includeSubDomains")
+	);
+	assert_eq!(response.header("alt-svc"), Some("h3=\":443\"; ma=86400"));
+}
+
+#[tokio::test]
+async fn unsendable_responses_are_logged_and_get_the_fallback_body() {
+	let site = Site::new();
+	let server = site.server();
+	server.add_gatekeeper(Box::new(|_| {
+		Some(
+			Response::default()
+				.with_status(403)
+				.with_header("Bad Name", "value"),
+		)
+	}));
+	let mut config = ServerConfig::default();
+	config.fallback_body = Some("Something broke".to_string());
+	server.set_config(config);
+	let (logs, _guard) = Logs::capture();
+	let port = http::serve(&server, HttpConfig::default()).await;
+
+	let response = http::send(port, "GET", "/page", "").await;
+	assert_eq!(response.status, 500);
+	assert_eq!(response.body, b"Something broke");
+	let logs = logs.contents();
+	assert!(
+		logs.contains("ERROR") && logs.contains("\"Bad Name\" is not a valid header name"),
+		"{logs}"
+	);
+}
+
+#[tokio::test]
+async fn connections_close_after_their_request_limit() {
+	let site = Site::new();
+	site.file("page.txt", "page");
+	let config = HttpConfig {
+		max_requests_per_connection: Some(2),
+		read_buffer_size: Some(16384),
+		..Default::default()
+	};
+	let port = http::serve(&site.server(), config).await;
+
+	let pipelined = "GET /page.txt HTTP/1.1\r\nHost: localhost\r\n\r\n".repeat(3);
+	let responses = String::from_utf8(http::exchange(port, pipelined.as_bytes()).await).unwrap();
+	// The server closes the connection, so reading it ends without the third response.
+	let answered: Vec<&str> = responses.split("HTTP/1.1 ").skip(1).collect();
+	assert_eq!(answered.len(), 2);
+	assert!(answered.iter().all(|response| response.starts_with("200")));
+	assert!(!answered[0]
+		.to_ascii_lowercase()
+		.contains("connection: close"));
+	assert!(answered[1]
+		.to_ascii_lowercase()
+		.contains("connection: close"));
+}
+
+#[tokio::test]
+async fn connections_past_the_limit_are_turned_away() {
+	let site = Site::new();
+	site.file("page.txt", "page");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.limits.max_connections = Some(1);
+	server.set_config(config);
+	let port = http::serve(&server, HttpConfig::default()).await;
+	// Waits until the server has accepted so many connections, and so many are open.
+	let settle = |accepted: usize, open: usize| {
+		let server = server.clone();
+		async move {
+			while server.accepted_connections() != accepted || server.active_connections() != open {
+				tokio::time::sleep(Duration::from_millis(10)).await;
+			}
+		}
+	};
+	// Connecting to see whether the server is listening counted as a connection too.
+	settle(1, 0).await;
+
+	let held = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.unwrap();
+	settle(2, 1).await;
+	let response = http::send(port, "GET", "/page.txt", "").await;
+	assert_eq!(response.status, 503);
+	assert_eq!(response.header("connection"), Some("close"));
+
+	drop(held);
+	// The connection turned away wasn't accepted.
+	settle(2, 0).await;
+	assert_eq!(http::send(port, "GET", "/page.txt", "").await.body, b"page");
+	assert_eq!(server.accepted_connections(), 3);
+}
+
+#[tokio::test]
+async fn bodies_are_refused_once_they_do_not_fit() {
+	let site = Site::new();
+	// Only the length is echoed, so the output takes next to nothing of the limit.
+	site.script("echo.cgi", "wc -c");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.limits.max_buffered_bytes = Some(1000);
+	server.set_config(config);
+	let config = HttpConfig {
+		max_body_size: Some(900),
+		..Default::default()
+	};
+	let port = http::serve(&server, config).await;
+	let post = |headers: &str, body: &str| {
+		let request = format!(
+			"POST /echo.cgi HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{headers}\r\n{body}"
+		);
+		async move { Raw::parse(&http::exchange(port, request.as_bytes()).await) }
+	};
+	let chunked = |body: &str| {
+		let chunks: String = body
+			.as_bytes()
+			.chunks(100)
+			.map(|chunk| {
+				format!(
+					"{:x}\r\n{}\r\n",
+					chunk.len(),
+					String::from_utf8_lossy(chunk)
+				)
+			})
+			.collect();
+		format!("{chunks}0\r\n\r\n")
+	};
+	let sized = |len: usize| format!("Content-Length: {len}\r\n");
+	let streamed = "Transfer-Encoding: chunked\r\n";
+
+	let body = "a".repeat(600);
+	assert_eq!(post(&sized(600), &body).await.body, b"600\n");
+	// Past max_body_size, whether the body says so up front or not.
+	assert_eq!(post(&sized(6000), "").await.status, 413);
+	assert_eq!(post(streamed, &chunked(&"a".repeat(950))).await.status, 413);
+	assert_eq!(server.buffered_bytes(), 0);
+
+	// A body is reserved for before it's read, so one still arriving keeps others from fitting.
+	let mut slow = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.unwrap();
+	let head = format!(
+		"POST /echo.cgi HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+		sized(800)
+	);
+	slow.write_all(head.as_bytes()).await.unwrap();
+	while server.buffered_bytes() != 800 {
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+	let body = "b".repeat(300);
+	assert_eq!(post(&sized(300), &body).await.status, 503);
+	assert_eq!(post(streamed, &chunked(&body)).await.status, 503);
+	let response = Raw::parse(&http::exchange_over(slow, "a".repeat(800).as_bytes()).await);
+	assert_eq!(response.body, b"800\n");
+	while server.buffered_bytes() != 0 {
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+	assert_eq!(post(streamed, &chunked(&body)).await.body, b"300\n");
+}
+
+#[tokio::test]
+async fn responses_stay_reserved_for_until_they_are_sent() {
+	let site = Site::new();
+	// Far more than the connection's buffers can take, so most of it waits on the client.
+	site.script("large.cgi", "head -c 33554432 /dev/zero");
+	site.script("small.cgi", "head -c 10485760 /dev/zero");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.limits.max_buffered_bytes = Some(40 << 20);
+	server.set_config(config);
+	let port = http::serve(&server, HttpConfig::default()).await;
+
+	let mut unread = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.unwrap();
+	unread
+		.write_all(b"GET /large.cgi HTTP/1.1\r\nHost: localhost\r\n\r\n")
+		.await
+		.unwrap();
+	while server.buffered_bytes() < 32 << 20 {
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+	// The large response is still waiting to be sent, so there's no room for the output of another.
+	tokio::time::sleep(Duration::from_millis(100)).await;
+	assert_eq!(http::send(port, "GET", "/small.cgi", "").await.status, 503);
+
+	// Going away gives the room back.
+	drop(unread);
+	while server.buffered_bytes() != 0 {
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+	let response = http::send(port, "GET", "/small.cgi", "").await;
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body.len(), 10 << 20);
+}
+
+#[tokio::test]
+async fn cgis_get_the_query_over_http() {
+	let site = Site::new();
+	site.script(
+		"search.cgi",
+		"echo \"$QUERY_STRING\"\necho \"$QUERY_Q\"\necho \"$QUERY_PAGE\"",
+	);
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+
+	let response = http::send(port, "GET", "/search.cgi?q=hello%20world&page=2", "").await;
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"q=hello%20world&page=2\nhello world\n2\n");
+}
+
+#[tokio::test]
+async fn servers_can_listen_on_ipv6() {
+	let site = Site::new();
+	site.file("page.txt", "page");
+	let config = HttpConfig {
+		ip: Ipv6Addr::LOCALHOST.into(),
+		..Default::default()
+	};
+	let port = http::serve(&site.server(), config).await;
+
+	let stream = TcpStream::connect((Ipv6Addr::LOCALHOST, port))
+		.await
+		.unwrap();
+	let request = b"GET /page.txt HTTP/1.1\r\nHost: [::1]\r\nConnection: close\r\n\r\n";
+	let response = Raw::parse(&http::exchange_over(stream, request).await);
+	assert_eq!(response.body, b"page");
+	// It's only listening on IPv6.
+	assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.is_err());
+}
+
+#[tokio::test]
+async fn https_is_served_when_tls_is_configured() {
+	let site = Site::new();
+	site.file("page.txt", "page");
+	let config = HttpConfig {
+		tls: Some(http::certificate(&site)),
+		..Default::default()
+	};
+	let port = http::serve(&site.server(), config).await;
+
+	let response = http::send_tls(port, "GET", "/page.txt", "").await;
+	assert_eq!(response.status, 200);
+	assert_eq!(response.body, b"page");
+	// The certificate and key aren't readable by others, so they aren't served.
+	assert_eq!(
+		http::send_tls(port, "GET", "/key.pem", "").await.status,
+		404
+	);
+	// Plaintext HTTP on the same port gets no answer, if the connection isn't just reset.
+	let mut plain = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.unwrap();
+	plain
+		.write_all(b"GET /page.txt HTTP/1.1\r\nHost: localhost\r\n\r\n")
+		.await
+		.unwrap();
+	let mut answer = vec![];
+	let _ = plain.read_to_end(&mut answer).await;
+	assert!(!answer.starts_with(b"HTTP/"));
+}
+
+#[tokio::test]
+async fn proxying_back_to_the_same_server_is_broken_off() {
+	let site = Site::new();
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.limits.max_depth = Some(3);
+	server.set_config(config);
+	let let_through = Arc::new(AtomicUsize::new(0));
+	server.add_gatekeeper(Box::new({
+		let let_through = let_through.clone();
+		move |_| {
+			let_through.fetch_add(1, Ordering::SeqCst);
+			None
+		}
+	}));
+	let port = http::serve(&server, HttpConfig::default()).await;
+	site.file(
+		"loop/.wwebs.toml",
+		format!("proxy_pass = \"http://127.0.0.1:{port}/loop\""),
+	);
+
+	let response = http::send(port, "GET", "/loop/page", "").await;
+	assert_eq!(response.status, 508);
+	// The client's request and three nested ones got in, and the fourth nested one was refused.
+	assert_eq!(let_through.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn repeated_headers_reach_cgis_joined() {
+	let site = Site::new();
+	site.script(
+		"headers.cgi",
+		"echo \"$HEADER_X_TAG\"\necho \"$HEADER_COOKIE_A $HEADER_COOKIE_B\"",
+	);
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+
+	let response = http::send(
+		port,
+		"GET",
+		"/headers.cgi",
+		"X-Tag: first\r\nX-Tag: second\r\nCookie: a=1\r\nCookie: b=2\r\n",
+	)
+	.await;
+	assert_eq!(response.body, b"first, second\n1 2\n");
+}
+
+#[tokio::test]
+async fn cgis_can_set_several_cookies() {
+	let site = Site::new();
+	site.script(
+		"login.cgi",
+		"echo 'add-header Set-Cookie session=abc; HttpOnly' >&2\necho 'add-header Set-Cookie theme=dark' >&2",
+	);
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+
+	let response = http::send(port, "GET", "/login.cgi", "").await;
+	// Each cookie is a header line of its own.
+	assert_eq!(
+		response.header("set-cookie"),
+		Some("session=abc; HttpOnly\ntheme=dark")
+	);
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_refused_before_they_are_read() {
+	let site = Site::new();
+	site.script("upload.cgi", "touch \"$0.ran\"\ncat");
+	let config = HttpConfig {
+		max_body_size: Some(1024),
+		..Default::default()
+	};
+	let port = http::serve(&site.server(), config).await;
+
+	// The body never comes, so the answer can only be from its length.
+	let response = http::send(port, "POST", "/upload.cgi", "Content-Length: 1048576\r\n").await;
+	assert_eq!(response.status, 413);
+	assert!(!site.root.join("upload.cgi.ran").exists());
+
+	// A body that breaks off partway is a bad request.
+	let broken = "POST /upload.cgi HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd\r\nzz\r\n";
+	let response = Raw::parse(&http::exchange(port, broken.as_bytes()).await);
+	assert_eq!(response.status, 400);
+	assert!(!site.root.join("upload.cgi.ran").exists());
+}
+
+#[tokio::test]
+async fn cgis_get_the_address_of_the_client() {
+	let site = Site::new();
+	site.script("ip.cgi", "echo \"$REMOTE_ADDR\"");
+	let server = site.server();
+	let port = http::serve(&server, HttpConfig::default()).await;
+	let remote_addr = |forwarded_for: &str| {
+		let headers = format!("X-Forwarded-For: {forwarded_for}\r\n");
+		async move {
+			let response = http::send(port, "GET", "/ip.cgi", &headers).await;
+			String::from_utf8(response.body).unwrap()
+		}
+	};
+
+	// Anyone can send X-Forwarded-For, so it's ignored from clients that aren't trusted proxies.
+	assert_eq!(remote_addr("203.0.113.7").await, "127.0.0.1\n");
+
+	let mut config = ServerConfig::default();
+	config.trusted_proxies = Some(vec![Ipv4Addr::LOCALHOST.into()]);
+	server.set_config(config);
+	assert_eq!(remote_addr("203.0.113.7").await, "203.0.113.7\n");
+	// Going back through the proxies, the first address that isn't one of them is the client's.
+	assert_eq!(
+		remote_addr("198.51.100.1, 203.0.113.7, 127.0.0.1").await,
+		"203.0.113.7\n"
+	);
+}
+
+#[tokio::test]
+async fn head_responses_have_the_length_but_not_the_body() {
+	let site = Site::new();
+	site.file("page.txt", "a static page")
+		.script("page.cgi", "touch \"$0.ran\"\necho 'a dynamic page'");
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+
+	let response = http::send(port, "HEAD", "/page.txt", "").await;
+	assert_eq!(response.status, 200);
+	assert_eq!(response.header("content-length"), Some("13"));
+	assert!(response.body.is_empty());
+
+	// CGIs still run, so the headers are what a GET would get.
+	let response = http::send(port, "HEAD", "/page.cgi", "").await;
+	assert_eq!(response.status, 200);
+	assert_eq!(response.header("content-length"), Some("15"));
+	assert!(response.body.is_empty());
+	assert!(site.root.join("page.cgi.ran").exists());
+}
+
+#[tokio::test]
+async fn compressed_responses_decompress_to_the_original() {
+	let text = "All work and no play makes Jack a dull boy.\n".repeat(100);
+	let site = Site::new();
+	site.file("api/.wwebs.toml", "[compression]\nenabled = true")
+		.file("api/page.txt", &text)
+		.file("api/small.txt", "tiny")
+		.file("api/logo.png", &text)
+		.file("page.txt", &text);
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+	let fetch = |path: &'static str, encoding: &'static str| async move {
+		let headers = format!("Accept-Encoding: {encoding}\r\n");
+		http::send(port, "GET", path, &headers).await
+	};
+
+	let response = fetch("/api/page.txt", "gzip").await;
+	assert_eq!(response.header("content-encoding"), Some("gzip"));
+	assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+	assert_eq!(
+		response.header("content-length"),
+		Some(response.body.len().to_string().as_str())
+	);
+	assert!(response.body.len() < text.len());
+	let mut body = String::new();
+	GzDecoder::new(&response.body[..])
+		.read_to_string(&mut body)
+		.unwrap();
+	assert_eq!(body, text);
+
+	let response = fetch("/api/page.txt", "deflate").await;
+	assert_eq!(response.header("content-encoding"), Some("deflate"));
+	let mut body = String::new();
+	ZlibDecoder::new(&response.body[..])
+		.read_to_string(&mut body)
+		.unwrap();
+	assert_eq!(body, text);
+
+	// Small bodies, types that are already compressed, clients that don't ask and directories that don't opt in are left alone.
+	for (path, encoding) in [
+		("/api/small.txt", "gzip"),
+		("/api/logo.png", "gzip"),
+		("/api/page.txt", "identity"),
+		("/page.txt", "gzip"),
+	] {
+		let response = fetch(path, encoding).await;
+		assert_eq!(response.header("content-encoding"), None, "{path}");
+	}
+}
+
+#[tokio::test]
+async fn websockets_are_relayed_to_their_cgi() {
+	let site = Site::new();
+	site.file("chat/.wwebs.toml", "websocket = true").script(
+		"chat/echo.cgi",
+		"while read -r line; do echo \"echo: $line\"; done",
+	);
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+
+	let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.unwrap();
+	let (mut socket, response) =
+		tokio_tungstenite::client_async(format!("ws://localhost:{port}/chat/echo.cgi"), stream)
+			.await
+			.unwrap();
+	assert_eq!(response.status(), 101);
+	for message in ["hello", "world"] {
+		socket.send(Message::text(message)).await.unwrap();
+		let reply = socket.next().await.unwrap().unwrap();
+		assert_eq!(reply, Message::text(format!("echo: {message}")));
+	}
+	// Leaving closes the CGI's stdin, so it exits and the connection closes.
+	socket.close(None).await.unwrap();
+	while let Some(Ok(message)) = socket.next().await {
+		assert!(message.is_close());
+	}
+}
+
+#[tokio::test]
+async fn access_logs_get_a_line_per_request() {
+	let site = Site::new();
+	let log = site.root.join("access.log");
+	site.file(
+		"blog/.wwebs.toml",
+		format!("access_log = \"{}\"", log.display()),
+	)
+	.file("blog/post.html", "post");
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+
+	http::send(
+		port,
+		"GET",
+		"/blog/post.html?page=2",
+		"Referer: https://example.com/\r\nUser-Agent: curl/8.0 \"quoted\"\r\n",
+	)
+	.await;
+	http::send(port, "GET", "/blog/missing", "").await;
+	// Requests outside the directory aren't its to log.
+	http::send(port, "GET", "/other", "").await;
+
+	let log = std::fs::read_to_string(log).unwrap();
+	let lines: Vec<&str> = log.lines().collect();
+	assert_eq!(lines.len(), 2);
+	let (start, rest) = lines[0].split_once(" [").unwrap();
+	assert_eq!(start, "127.0.0.1 - -");
+	let (date, rest) = rest.split_once("] ").unwrap();
+	// Like `16/Oct/2026:14:30:03 +0000`.
+	assert_eq!(date.len(), 26);
+	assert!(date.ends_with(" +0000"));
+	assert_eq!(
+		rest,
+		r#""GET /blog/post.html?page=2 HTTP/1.1" 200 4 "https://example.com/" "curl/8.0 \"quoted\"""#
+	);
+	assert!(lines[1].ends_with(r#""GET /blog/missing HTTP/1.1" 404 - "-" "-""#));
+}
+
+#[tokio::test]
+async fn cgis_are_told_about_the_server_end() {
+	let site = Site::new();
+	site.script(
+		"env.cgi",
+		"echo \"$SERVER_NAME $SERVER_PORT $SERVER_PROTOCOL ${HTTPS:-off}\"",
+	);
+	let server = site.server();
+	let plain = http::serve(&server, HttpConfig::default()).await;
+	let config = HttpConfig {
+		tls: Some(http::certificate(&site)),
+		..Default::default()
+	};
+	let secure = http::serve(&server, config).await;
+
+	let response = http::send(plain, "GET", "/env.cgi", "").await;
+	assert_eq!(
+		String::from_utf8_lossy(&response.body),
+		format!("localhost {plain} HTTP/1.1 off\n")
+	);
+	let response = http::send_tls(secure, "GET", "/env.cgi", "").await;
+	assert_eq!(
+		String::from_utf8_lossy(&response.body),
+		format!("localhost {secure} HTTP/1.1 on\n")
+	);
+}
+
+#[tokio::test]
+async fn cgis_are_told_what_tls_negotiated() {
+	let site = Site::new();
+	site.script(
+		"tls.cgi",
+		"echo \"${SSL_PROTOCOL-unset}|${SSL_CIPHER-unset}|${SSL_CLIENT_S_DN-unset}\"",
+	);
+	let server = site.server();
+	let plain = http::serve(&server, HttpConfig::default()).await;
+	let config = HttpConfig {
+		tls: Some(TlsConfig {
+			client_certs: true,
+			..http::certificate(&site)
+		}),
+		..Default::default()
+	};
+	let secure = http::serve(&server, config).await;
+
+	let response = http::send(plain, "GET", "/tls.cgi", "").await;
+	assert_eq!(response.body, b"unset|unset|unset\n");
+
+	let response = http::send_tls(secure, "GET", "/tls.cgi", "").await;
+	let body = String::from_utf8(response.body).unwrap();
+	let [protocol, cipher, subject] = body.trim_end().split('|').collect::<Vec<_>>()[..] else {
+		panic!("unexpected output {body:?}");
+	};
+	assert!(protocol.starts_with("TLSv1."), "{protocol}");
+	assert!(!cipher.is_empty() && cipher != "unset", "{cipher}");
+	assert_eq!(subject, "unset");
+
+	let client = Site::new();
+	let response = http::send_tls_as(
+		Some(&http::certificate(&client)),
+		secure,
+		"GET",
+		"/tls.cgi",
+		"",
+	)
+	.await;
+	let body = String::from_utf8(response.body).unwrap();
+	assert!(body.ends_with("|CN=localhost\n"), "{body}");
+}
+
+#[tokio::test]
+async fn directories_can_forward_to_an_upstream() {
+	let upstream = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+		.await
+		.unwrap();
+	let upstream_port = upstream.local_addr().unwrap().port();
+	// A single-request upstream that answers once it has the whole body, and hands over what it got.
+	let received = tokio::spawn(async move {
+		let (mut stream, _) = upstream.accept().await.unwrap();
+		let mut request = vec![];
+		let mut buffer = [0; 4096];
+		while !request.ends_with(b"\r\n\r\nhello") {
+			let read = stream.read(&mut buffer).await.unwrap();
+			assert_ne!(read, 0, "the request ended early");
+			request.extend_from_slice(&buffer[..read]);
+		}
+		stream
+			.write_all(b"HTTP/1.1 201 Created\r\nX-Upstream: yes\r\nKeep-Alive: timeout=5\r\nContent-Length: 5\r\n\r\nmade!")
+			.await
+			.unwrap();
+		String::from_utf8(request).unwrap()
+	});
+	let site = Site::new();
+	site.file(
+		"api/.wwebs.toml",
+		format!("proxy_pass = \"http://127.0.0.1:{upstream_port}/v1\""),
+	);
+	let port = http::serve(&site.server(), HttpConfig::default()).await;
+
+	let response = http::exchange(
+		port,
+		b"POST /api/items?sort=new HTTP/1.1\r\nHost: example.com\r\nConnection: close, X-Secret\r\nX-Secret: 1\r\nX_Tenant: acme\r\nX-Tag: a\r\nContent-Length: 5\r\n\r\nhello",
+	)
+	.await;
+	let response = Raw::parse(&response);
+	assert_eq!(response.status, 201);
+	assert_eq!(response.body, b"made!");
+	assert_eq!(response.header("x-upstream"), Some("yes"));
+	assert_eq!(response.header("keep-alive"), None);
+
+	let request = received.await.unwrap().to_ascii_lowercase();
+	let (head, body) = request.split_once("\r\n\r\n").unwrap();
+	let mut lines = head.split("\r\n");
+	assert_eq!(lines.next(), Some("post /v1/items?sort=new http/1.1"));
+	let headers: Vec<&str> = lines.collect();
+	for expected in [
+		"x_tenant: acme",
+		"x-tag: a",
+		"x-forwarded-for: 127.0.0.1",
+		"x-forwarded-proto: http",
+		"x-forwarded-host: example.com",
+		"wwebs-depth: 1",
+	] {
+		assert!(headers.contains(&expected), "{expected} in {headers:?}");
+	}
+	// Headers for the client's connection alone stay behind.
+	assert!(!headers.iter().any(|header| header.starts_with("x-secret")));
+	assert!(!headers.iter().any(|header| header.starts_with("x-tenant")));
+	assert_eq!(body, "hello");
+}
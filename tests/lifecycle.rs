@@ -0,0 +1,107 @@
+//! Running the wwebs binary, from starting up to shutting down.
+#![cfg(feature = "http")]
+
+mod common;
+
+use std::{
+	io::{Read, Write},
+	net::{Ipv4Addr, TcpListener, TcpStream},
+	process::{Child, Command},
+	time::{Duration, Instant},
+};
+
+use common::Site;
+
+/// Starts wwebs serving a site over HTTP on a free port, returning it and the port.
+fn start(site: &Site, envs: &[(&str, &str)]) -> (Child, u16) {
+	let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+		.unwrap()
+		.local_addr()
+		.unwrap()
+		.port();
+	let child = Command::new(env!("CARGO_BIN_EXE_wwebs"))
+		.arg("--http-port")
+		.arg(port.to_string())
+		.current_dir(&site.root)
+		.envs(envs.iter().copied())
+		.spawn()
+		.unwrap();
+	(child, port)
+}
+
+/// Sends a signal to a child.
+fn signal(child: &Child, signal: i32) {
+	// SAFETY: `kill` has no memory safety requirements.
+	assert_eq!(
+		unsafe { libc::kill(child.id().try_into().unwrap(), signal) },
+		0
+	);
+}
+
+/// Waits for a child to exit, killing it if it takes too long.
+fn wait(child: &mut Child) -> std::process::ExitStatus {
+	let deadline = Instant::now() + Duration::from_secs(10);
+	while Instant::now() < deadline {
+		if let Some(status) = child.try_wait().unwrap() {
+			return status;
+		}
+		std::thread::sleep(Duration::from_millis(20));
+	}
+	child.kill().unwrap();
+	panic!("wwebs didn't exit");
+}
+
+#[cfg(feature = "systemd")]
+#[test]
+fn systemd_is_notified_when_ready_and_stopping() {
+	use std::os::unix::net::UnixDatagram;
+
+	let site = Site::new();
+	let socket_path = site.root.join("notify.sock");
+	let socket = UnixDatagram::bind(&socket_path).unwrap();
+	socket
+		.set_read_timeout(Some(Duration::from_secs(10)))
+		.unwrap();
+	let (mut child, port) = start(&site, &[("NOTIFY_SOCKET", socket_path.to_str().unwrap())]);
+	let mut message = [0; 64];
+
+	let len = socket.recv(&mut message).unwrap();
+	assert_eq!(String::from_utf8_lossy(&message[..len]).trim(), "READY=1");
+	// Ready means listening.
+	TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+
+	signal(&child, libc::SIGTERM);
+	let len = socket.recv(&mut message).unwrap();
+	assert_eq!(
+		String::from_utf8_lossy(&message[..len]).trim(),
+		"STOPPING=1"
+	);
+	assert!(wait(&mut child).success());
+}
+
+#[test]
+fn sigterm_lets_requests_finish_before_exiting() {
+	let site = Site::new();
+	site.script("slow.cgi", "sleep 1\necho done");
+	let (mut child, port) = start(&site, &[]);
+	let deadline = Instant::now() + Duration::from_secs(10);
+	let mut stream = loop {
+		match TcpStream::connect((Ipv4Addr::LOCALHOST, port)) {
+			Ok(stream) => break stream,
+			Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(20)),
+			Err(e) => panic!("wwebs never listened: {e}"),
+		}
+	};
+	stream
+		.write_all(b"GET /slow.cgi HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+		.unwrap();
+	// Long enough for the CGI to be running.
+	std::thread::sleep(Duration::from_millis(300));
+
+	signal(&child, libc::SIGTERM);
+	let mut response = String::new();
+	stream.read_to_string(&mut response).unwrap();
+	assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+	assert!(response.ends_with("done\n"), "{response}");
+	assert!(wait(&mut child).success());
+}
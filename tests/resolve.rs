@@ -0,0 +1,509 @@
+//! Walking a request's path through the site, and what answers it.
+
+mod common;
+
+use std::{collections::HashMap, os::unix::fs::symlink, path::Path};
+
+use common::{get, request, send, Logs, Site};
+use wwebs::{files::server::ServerConfig, server::Handlers, structures::Response};
+
+#[test]
+fn special_files_are_not_found() {
+	let site = Site::new();
+	let fifo = std::ffi::CString::new(site.root.join("pipe").into_os_string().into_encoded_bytes())
+		.unwrap();
+	// SAFETY: The path is a valid C string.
+	assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+	assert_eq!(get(&site.server(), "/pipe").status, 404);
+}
+
+#[test]
+fn symlinks_resolve_to_what_they_point_at() {
+	let site = Site::new();
+	site.file("docs/index.html", "docs")
+		.file("page.html", "page");
+	symlink(site.root.join("docs"), site.root.join("latest")).unwrap();
+	symlink(site.root.join("page.html"), site.root.join("alias.html")).unwrap();
+	let server = site.server();
+	assert_eq!(get(&server, "/latest/").body, b"docs");
+	assert_eq!(get(&server, "/alias.html").body, b"page");
+}
+
+#[test]
+fn non_canonical_paths_are_served_or_redirected() {
+	let site = Site::new();
+	site.file("a/b.html", "b");
+	let server = site.server();
+	let mut doubled = request("GET", "/");
+	doubled.url.set_path("/a//b.html");
+	assert_eq!(send(&server, &mut doubled.clone()).body, b"b");
+
+	let mut config = ServerConfig::default();
+	config.redirect_to_canonical = true;
+	server.set_config(config);
+	let response = send(&server, &mut doubled);
+	assert_eq!(response.status, 301);
+	assert_eq!(response.headers["Location"], "http://localhost/a/b.html");
+}
+
+#[test]
+fn user_agent_rules_pick_an_index_or_redirect() {
+	let site = Site::new();
+	site.file(
+		".wwebs.toml",
+		r#"
+		[[user_agent_rules]]
+		pattern = "(?i)bot"
+		index = "crawler.html"
+
+		[[user_agent_rules]]
+		pattern = "^OldBrowser"
+		redirect = "/legacy/"
+		"#,
+	)
+	.file("index.html", "home")
+	.file("crawler.html", "for crawlers");
+	let server = site.server();
+	let from = |agent: &str| {
+		let mut request = request("GET", "/");
+		request
+			.headers
+			.insert("User-Agent".to_string(), agent.to_string());
+		send(&server, &mut request)
+	};
+
+	assert_eq!(from("Googlebot/2.1").body, b"for crawlers");
+	let response = from("OldBrowser/1.0");
+	assert_eq!(response.status, 302);
+	assert_eq!(response.headers["Location"], "/legacy/");
+	assert_eq!(from("Mozilla/5.0").body, b"home");
+	assert_eq!(get(&server, "/").body, b"home");
+}
+
+#[test]
+fn aliases_serve_their_directory_without_escaping_it() {
+	let site = Site::new();
+	let assets = Site::new();
+	site.file("page.txt", "page");
+	assets
+		.file("public/logo.txt", "logo")
+		.file("private.txt", "private");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.aliases = Some(HashMap::from([(
+		"/static/".to_string(),
+		assets.root.join("public").to_string_lossy().to_string(),
+	)]));
+	server.set_config(config);
+
+	assert_eq!(get(&server, "/static/logo.txt").body, b"logo");
+	assert_eq!(get(&server, "/page.txt").body, b"page");
+	// URLs resolve `..` (even encoded as `%2e%2e`) before anything else, so that leaves the alias altogether.
+	assert_eq!(get(&server, "/static/%2e%2e/private.txt").status, 404);
+	// An encoded slash isn't a separator, so it can't climb out of the alias either.
+	let response = get(&server, "/static/..%2fprivate.txt");
+	assert!(response.status >= 400);
+	assert_ne!(response.body, b"private");
+}
+
+#[test]
+fn redirects_use_their_configured_status() {
+	let site = Site::new();
+	site.file(
+		".wwebs.toml",
+		r#"
+		[[user_agent_rules]]
+		pattern = "^OldBrowser"
+		redirect = "/legacy/"
+		redirect_status = 307
+
+		[[user_agent_rules]]
+		pattern = "^Typo"
+		redirect = "/legacy/"
+		redirect_status = 200
+		"#,
+	)
+	.file("a/b.html", "b");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.redirect_to_canonical = true;
+	config.canonical_redirect_status = Some(308);
+	server.set_config(config);
+
+	let mut doubled = request("POST", "/");
+	doubled.url.set_path("/a//b.html");
+	let response = send(&server, &mut doubled);
+	assert_eq!(response.status, 308);
+	assert_eq!(response.headers["Location"], "http://localhost/a/b.html");
+
+	let from = |agent: &str| {
+		let mut request = request("GET", "/");
+		request
+			.headers
+			.insert("User-Agent".to_string(), agent.to_string());
+		send(&server, &mut request).status
+	};
+	assert_eq!(from("OldBrowser/1.0"), 307);
+	// Statuses that aren't redirects fall back to a 302.
+	assert_eq!(from("Typo/1.0"), 302);
+}
+
+#[test]
+fn in_process_gatekeepers_can_reject_requests() {
+	let site = Site::new();
+	site.file("public.txt", "public")
+		.file("private/secret.txt", "secret")
+		.script("private/.gatekeeper", "touch \"$0.ran\"");
+	let server = site.server();
+	server.add_gatekeeper(Box::new(|request| {
+		request
+			.url
+			.path()
+			.starts_with("/private/")
+			.then(|| Response::default().with_status(403).with_body("Keep out"))
+	}));
+
+	assert_eq!(get(&server, "/public.txt").body, b"public");
+	let response = get(&server, "/private/secret.txt");
+	assert_eq!(response.status, 403);
+	assert_eq!(response.body, b"Keep out");
+	// They run before gatekeeper files, which a rejected request never reaches.
+	assert!(!site.root.join("private/.gatekeeper.ran").exists());
+}
+
+#[test]
+fn paths_with_control_characters_are_refused() {
+	let site = Site::new();
+	site.file("page.txt", "page");
+	let server = site.server();
+	let (logs, _guard) = Logs::capture();
+	for path in ["/page.txt%00.html", "/page%0a.txt", "/%7f"] {
+		assert_eq!(get(&server, path).status, 400, "for {path}");
+	}
+	assert!(logs.contents().contains("its path has control characters"));
+	assert_eq!(get(&server, "/page.txt").status, 200);
+}
+
+#[test]
+fn handlers_are_listed_in_the_order_they_run() {
+	let site = Site::new();
+	for handler in [
+		".gatekeeper10",
+		".gatekeeper2",
+		".gatekeeper",
+		".req_transformer1",
+		".res_transformer_b",
+		".res_transformer_a",
+		".logger",
+	] {
+		site.script(&format!("blog/{handler}"), "exit 0");
+	}
+	site.file("blog/index.html", "posts")
+		.file("blog/.wwebs.toml", "");
+	let handlers = site.server().handlers_at(Path::new("blog"));
+	assert_eq!(
+		handlers.gatekeepers,
+		[".gatekeeper", ".gatekeeper2", ".gatekeeper10"]
+	);
+	assert_eq!(handlers.req_transformers, [".req_transformer1"]);
+	assert_eq!(
+		handlers.res_transformers,
+		[".res_transformer_a", ".res_transformer_b"]
+	);
+	assert_eq!(handlers.loggers, [".logger"]);
+	assert_eq!(
+		site.server().handlers_at(Path::new("")),
+		Handlers::default()
+	);
+}
+
+#[test]
+fn extensions_can_be_answered_with_a_fixed_status() {
+	let site = Site::new();
+	site.file(
+		".wwebs.toml",
+		"[extension_status.php]\nstatus = 410\nbody = \"We don't do PHP anymore\"",
+	)
+	.file("page.html", "page");
+	let server = site.server();
+
+	// The file doesn't have to exist.
+	let response = get(&server, "/old/index.php?id=3");
+	assert_eq!(response.status, 410);
+	assert_eq!(response.body, b"We don't do PHP anymore");
+	assert_eq!(get(&server, "/page.html").status, 200);
+}
+
+#[test]
+fn percent_encoded_paths_are_decoded_without_escaping() {
+	let site = Site::new();
+	site.file("my file.txt", "spaced")
+		.file("100%.txt", "percent")
+		.file("a/b.txt", "b")
+		.file("secret.txt", "secret");
+	let server = site.server();
+
+	assert_eq!(get(&server, "/my%20file.txt").body, b"spaced");
+	// A `%` that doesn't start an escape is taken literally.
+	assert_eq!(get(&server, "/100%.txt").body, b"percent");
+	assert_eq!(get(&server, "/100%25.txt").body, b"percent");
+	// An encoded `..` is a `..`, which URLs resolve before anything is looked up.
+	let mut request = request("GET", "/a/%2e%2e/secret.txt");
+	assert_eq!(request.url.path(), "/secret.txt");
+	assert_eq!(send(&server, &mut request).body, b"secret");
+	// An encoded `/` can't split a segment, so it can't climb either.
+	for path in ["/a/..%2fsecret.txt", "/a/%2e%2e%2fsecret.txt", "/a%2fb.txt"] {
+		let response = get(&server, path);
+		assert!(
+			response.status >= 400,
+			"{path} answered {}",
+			response.status
+		);
+	}
+}
+
+#[test]
+fn symlinked_directories_can_redirect_to_their_target() {
+	let site = Site::new();
+	let elsewhere = Site::new();
+	site.file("releases/v2/notes.txt", "v2 notes");
+	elsewhere.file("notes.txt", "outside notes");
+	symlink(site.root.join("releases/v2"), site.root.join("latest")).unwrap();
+	symlink(&elsewhere.root, site.root.join("outside")).unwrap();
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.redirect_symlinked_dirs = true;
+	server.set_config(config);
+
+	let response = get(&server, "/latest/notes.txt?lang=en");
+	assert_eq!(response.status, 301);
+	assert_eq!(
+		response.headers["Location"],
+		"http://localhost/releases/v2/notes.txt?lang=en"
+	);
+	// A link out of the root has no path to redirect to, so it's followed.
+	assert_eq!(get(&server, "/outside/notes.txt").body, b"outside notes");
+}
+
+#[test]
+fn api_errors_are_problems_unless_a_cgi_wrote_them() {
+	let site = Site::new();
+	site.file("api/.wwebs.toml", "problem_json = true")
+		.script("api/broken.cgi", "exit 1");
+	let server = site.server();
+
+	let response = get(&server, "/api/missing");
+	assert_eq!(response.status, 404);
+	assert_eq!(response.headers["Content-Type"], "application/problem+json");
+	let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+	assert_eq!(problem["status"], 404);
+	assert_eq!(problem["title"], "Not Found");
+	assert_eq!(problem["type"], "about:blank");
+	assert!(problem["detail"].as_str().unwrap().contains("/api/missing"));
+
+	// Browsers asking for HTML, and requests outside the API, get the usual errors.
+	let mut html = request("GET", "/api/missing");
+	html.headers
+		.insert("Accept".to_string(), "text/html, */*;q=0.8".to_string());
+	assert!(!send(&server, &mut html)
+		.headers
+		.contains_key("Content-Type"));
+	assert!(!get(&server, "/missing")
+		.headers
+		.contains_key("Content-Type"));
+
+	// The CGI's answer is its own, empty body and all.
+	let response = get(&server, "/api/broken.cgi");
+	assert_eq!(response.status, 502);
+	assert!(response.body.is_empty());
+	assert!(!response.headers.contains_key("Content-Type"));
+}
+
+#[test]
+fn methods_gate_files_listings_and_upstreams() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "methods = [\"GET\", \"POST\"]")
+		.script("form.cgi", "echo ran")
+		.file("files/.wwebs.toml", "[resolution]\nautoindex = true")
+		.file("files/notes.txt", "hi")
+		.file("api/.wwebs.toml", "proxy_pass = \"http://127.0.0.1:9/\"")
+		.file("open/.wwebs.toml", "methods = [\"GET\", \"DELETE\"]")
+		.script("open/item.cgi", "echo \"$VERB\"");
+	let server = site.server();
+	let verb = |verb: &str, path: &str| send(&server, &mut request(verb, path));
+
+	let response = verb("DELETE", "/form.cgi");
+	assert_eq!(response.status, 405);
+	assert_eq!(response.headers["Allow"], "GET, POST, HEAD, OPTIONS");
+	assert!(!response.body.starts_with(b"ran"));
+	let response = verb("OPTIONS", "/form.cgi");
+	assert_eq!(response.status, 204);
+	assert_eq!(response.headers["Allow"], "GET, POST, HEAD, OPTIONS");
+	assert!(response.body.is_empty());
+	assert_eq!(verb("POST", "/form.cgi").body, b"ran\n");
+	// Listings and upstreams are gated the same way, before anything is listed or forwarded.
+	assert_eq!(verb("GET", "/files/").status, 200);
+	assert_eq!(verb("DELETE", "/files/").status, 405);
+	assert_eq!(verb("OPTIONS", "/files/").status, 204);
+	assert_eq!(verb("PUT", "/api/things").status, 405);
+	assert_eq!(verb("OPTIONS", "/api/things").status, 204);
+	// A deeper directory can allow what the ones above it don't.
+	assert_eq!(verb("DELETE", "/open/item.cgi").body, b"DELETE\n");
+	assert_eq!(verb("POST", "/open/item.cgi").status, 405);
+}
+
+#[test]
+fn a_childs_index_overrides_its_parents() {
+	let site = Site::new();
+	site.file(".wwebs.toml", "[resolution]\nindex = \"home.html\"")
+		.file("home.html", "top")
+		.file("docs/home.html", "inherited")
+		.file("blog/.wwebs.toml", "[resolution]\nindex = \"main.html\"")
+		.file("blog/home.html", "parent's")
+		.file("blog/main.html", "child's");
+	let server = site.server();
+
+	assert_eq!(get(&server, "/").body, b"top");
+	assert_eq!(get(&server, "/docs/").body, b"inherited");
+	assert_eq!(get(&server, "/blog/").body, b"child's");
+}
+
+#[test]
+fn explaining_a_nested_path_lists_each_step() {
+	let site = Site::new();
+	site.file("blog/.wwebs.toml", "env = { SITE = \"blog\" }")
+		.script("blog/.gatekeeper", "true")
+		.script("blog/.logger", "true")
+		.script("blog/posts/show.cgi", "touch \"${0%/*}/ran\"");
+	let server = site.server();
+
+	let steps = server.explain(&mut request("GET", "/blog/posts/show.cgi"));
+	let root = site.root.display();
+	assert_eq!(
+		steps,
+		[
+			format!("Directory {root}/"),
+			format!("Directory {root}/blog"),
+			"  applies its .wwebs.toml".to_string(),
+			"  gatekeepers: .gatekeeper".to_string(),
+			"  loggers: .logger".to_string(),
+			format!("Directory {root}/blog/posts"),
+			format!("File {root}/blog/posts/show.cgi, run as CGI"),
+		]
+	);
+	// Nothing is run while explaining.
+	assert!(!site.root.join("blog/posts/ran").exists());
+}
+
+#[test]
+fn overlong_urls_and_deep_paths_are_too_long() {
+	let site = Site::new();
+	site.file("a/b/page.txt", "deep enough")
+		.file("a/b/c/page.txt", "too deep");
+	let server = site.server();
+	let deep = "/a".repeat(5000);
+
+	let long = format!("/a/b/page.txt?q={}", "x".repeat(9000));
+
+	assert_eq!(get(&server, &deep).status, 414);
+	assert_eq!(get(&server, &long).status, 414);
+	// Each limit applies on its own.
+	let mut config = ServerConfig::default();
+	config.limits.max_url_length = usize::MAX;
+	server.set_config(config);
+	assert_eq!(get(&server, &deep).status, 414);
+	assert_eq!(get(&server, &long).body, b"deep enough");
+	let mut config = ServerConfig::default();
+	config.limits.max_path_segments = 3;
+	server.set_config(config);
+	assert_eq!(get(&server, "/a/b/c/page.txt").status, 414);
+	assert_eq!(get(&server, "/a/b/page.txt").status, 200);
+}
+
+#[test]
+fn malformed_directory_configs_are_reported() {
+	let site = Site::new();
+	site.file("docs/.wwebs.toml", "timeout = [unclosed")
+		.file("docs/page.txt", "still here")
+		.file("fine/page.txt", "fine");
+	let server = site.server();
+	let (logs, _guard) = Logs::capture();
+
+	// It's left out, so the directory is served with its parent's configuration.
+	assert_eq!(get(&server, "/docs/page.txt").body, b"still here");
+	let config_path = site.root.join("docs/.wwebs.toml");
+	let logs = logs.contents();
+	let line = logs
+		.lines()
+		.find(|line| line.contains(&format!("Ignoring {}, it's invalid", config_path.display())))
+		.unwrap_or_else(|| panic!("the error isn't in {logs}"));
+	assert!(line.contains("ERROR"));
+	// In strict mode it's refused instead, while other directories are unaffected.
+	let mut config = ServerConfig::default();
+	config.strict_config = true;
+	server.set_config(config);
+	assert_eq!(get(&server, "/docs/page.txt").status, 500);
+	assert_eq!(get(&server, "/fine/page.txt").body, b"fine");
+}
+
+#[test]
+fn directories_without_a_trailing_slash_can_be_redirected() {
+	let site = Site::new();
+	site.file("blog/index.html", "posts")
+		.file("docs/index.html", "docs")
+		.file(
+			"docs/.wwebs.toml",
+			"[resolution]\nredirect_trailing_slash = true",
+		);
+	let server = site.server();
+
+	// By default the index is served in place.
+	assert_eq!(get(&server, "/blog").body, b"posts");
+	let response = get(&server, "/docs?page=2");
+	assert_eq!(response.status, 301);
+	assert_eq!(response.headers["Location"], "/docs/?page=2");
+	assert_eq!(get(&server, "/docs/").body, b"docs");
+	// The redirect follows the server's status for canonical redirects.
+	let mut config = ServerConfig::default();
+	config.canonical_redirect_status = Some(308);
+	server.set_config(config);
+	let response = send(&server, &mut request("POST", "/docs"));
+	assert_eq!(response.status, 308);
+	assert_eq!(response.headers["Location"], "/docs/");
+}
+
+#[test]
+fn names_that_are_both_a_file_and_an_alias_follow_prefer() {
+	let site = Site::new();
+	let manual = Site::new();
+	site.file("docs", "the docs file")
+		.file("old/notes", "the notes file");
+	manual.file("index.html", "manual").file("page.txt", "page");
+	let server = site.server();
+	let mut config = ServerConfig::default();
+	config.aliases = Some(HashMap::from([
+		(
+			"/docs/".to_string(),
+			manual.root.to_string_lossy().to_string(),
+		),
+		(
+			"/old/notes/".to_string(),
+			manual.root.to_string_lossy().to_string(),
+		),
+	]));
+	server.set_config(config);
+
+	// The alias' directory answers by default.
+	assert_eq!(get(&server, "/docs").body, b"manual");
+	assert_eq!(get(&server, "/old/notes").body, b"manual");
+	// Preferring files lets the file answer for its own name, in the directory and below it.
+	site.file(".wwebs.toml", "[resolution]\nprefer = \"file\"");
+	assert_eq!(get(&server, "/docs").body, b"the docs file");
+	assert_eq!(get(&server, "/old/notes").body, b"the notes file");
+	// With a trailing slash or a path under it, the name is a directory, so it's the alias.
+	assert_eq!(get(&server, "/docs/").body, b"manual");
+	assert_eq!(get(&server, "/docs/page.txt").body, b"page");
+	// A deeper directory can prefer directories again.
+	site.file("old/.wwebs.toml", "[resolution]\nprefer = \"directory\"");
+	assert_eq!(get(&server, "/old/notes").body, b"manual");
+}
@@ -0,0 +1,95 @@
+//! Serving sites over SCGI, behind a front-end web server.
+#![cfg(feature = "scgi")]
+
+mod common;
+
+use std::{
+	net::{Ipv4Addr, TcpListener},
+	time::Duration,
+};
+
+use common::{Raw, Site};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+};
+use wwebs::{
+	scgi::{Scgi, ScgiConfig},
+	server::Server,
+	traits::Protocol,
+};
+
+/// Serves a server over SCGI on a free port, returning the port once it's listening.
+async fn serve(server: &Server, max_body_size: Option<usize>) -> u16 {
+	let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+		.unwrap()
+		.local_addr()
+		.unwrap()
+		.port();
+	let config = ScgiConfig {
+		address: format!("127.0.0.1:{port}"),
+		max_body_size,
+	};
+	tokio::spawn(Scgi.run(config, server.clone()));
+	while TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.is_err()
+	{
+		tokio::time::sleep(Duration::from_millis(10)).await;
+	}
+	port
+}
+
+/// Sends a request like a front-end would, with its variables in a netstring and then its body,
+/// and reads the answer as an HTTP response.
+async fn send(port: u16, vars: &[(&str, &str)], body: &[u8]) -> Raw {
+	let headers: Vec<u8> = vars
+		.iter()
+		.flat_map(|(name, value)| [name.as_bytes(), b"\0", value.as_bytes(), b"\0"].concat())
+		.collect();
+	let mut request = format!("{}:", headers.len()).into_bytes();
+	request.extend(headers);
+	request.push(b',');
+	request.extend(body);
+	let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+		.await
+		.unwrap();
+	stream.write_all(&request).await.unwrap();
+	let mut response = vec![];
+	stream.read_to_end(&mut response).await.unwrap();
+	// SCGI answers like CGI, with a `Status` header instead of a status line.
+	let response = String::from_utf8_lossy(&response).replacen("Status: ", "HTTP/1.1 ", 1);
+	Raw::parse(response.as_bytes())
+}
+
+#[tokio::test]
+async fn handcrafted_requests_are_answered() {
+	let site = Site::new();
+	site.script(
+		"form.cgi",
+		"echo 'header X-Form yes' >&2\necho \"$VERB $QUERY_STEP $HEADER_USER_AGENT\"\ncat",
+	);
+	let port = serve(&site.server(), Some(1024)).await;
+	let request = |method: &'static str, length: &'static str| {
+		[
+			("CONTENT_LENGTH", length),
+			("SCGI", "1"),
+			("REQUEST_METHOD", method),
+			("REQUEST_URI", "/form.cgi?step=2"),
+			("HTTP_HOST", "example.com"),
+			("HTTP_USER_AGENT", "curl"),
+		]
+	};
+
+	let response = send(port, &request("POST", "5"), b"hello").await;
+	assert_eq!(response.status, 200);
+	assert_eq!(response.header("x-form"), Some("yes"));
+	assert_eq!(response.body, b"POST 2 curl\nhello");
+
+	// The body's length is checked before any of it is read.
+	let response = send(port, &request("POST", "4096"), b"").await;
+	assert_eq!(response.status, 413);
+	// Front-ends always send a length, even when there's no body.
+	let response = send(port, &[("SCGI", "1"), ("REQUEST_METHOD", "GET")], b"").await;
+	assert_eq!(response.status, 400);
+}
@@ -0,0 +1,94 @@
+//! The server-wide configuration, and what it changes about answering requests.
+
+mod common;
+
+use common::{get, request, send, Site};
+use wwebs::{files::server::ServerConfig, server::Server};
+
+#[test]
+fn reloading_toggles_maintenance_mode() {
+	let site = Site::new();
+	site.file("index.html", "home");
+	let config = site.root.join("server.toml");
+	let server = site.server();
+	assert_eq!(get(&server, "/").status, 200);
+
+	std::fs::write(&config, "maintenance = true").unwrap();
+	server.reload_config(&config).unwrap();
+	let response = get(&server, "/");
+	assert_eq!(response.status, 503);
+	assert_ne!(response.body, b"home");
+
+	std::fs::write(&config, "maintenance = false").unwrap();
+	server.reload_config(&config).unwrap();
+	assert_eq!(get(&server, "/").body, b"home");
+
+	// An invalid file keeps the configuration as it was.
+	std::fs::write(&config, "maintenance = ").unwrap();
+	assert!(server.reload_config(&config).is_err());
+	assert_eq!(get(&server, "/").status, 200);
+}
+
+#[tokio::test]
+async fn listeners_bound_waits_for_every_listener() {
+	let server = Site::new().server();
+	let bound = tokio::spawn({
+		let server = server.clone();
+		async move { server.listeners_bound(2).await }
+	});
+	server.listener_bound();
+	tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+	assert!(!bound.is_finished());
+	server.listener_bound();
+	tokio::time::timeout(std::time::Duration::from_secs(5), bound)
+		.await
+		.unwrap()
+		.unwrap();
+}
+
+#[test]
+fn server_timing_breaks_down_the_stages_when_enabled() {
+	let site = Site::new();
+	site.file("index.html", "home");
+	let server = site.server();
+	assert_eq!(get(&server, "/").headers.get("Server-Timing"), None);
+
+	let mut config = ServerConfig::default();
+	config.server_timing = true;
+	server.set_config(config);
+	let response = get(&server, "/");
+	let timing = response.headers.get("Server-Timing").unwrap();
+	let stages: Vec<&str> = timing
+		.split(", ")
+		.map(|stage| {
+			let (name, duration) = stage.split_once(";dur=").unwrap();
+			duration.parse::<f64>().unwrap();
+			name
+		})
+		.collect();
+	assert_eq!(
+		stages,
+		["gatekeeper", "req_transformer", "target", "res_transformer"]
+	);
+}
+
+#[test]
+fn servers_can_be_created_with_custom_limits() {
+	let site = Site::new();
+	site.file("a/b.txt", "deep enough");
+	let defaults = site.server().config().limits.clone();
+	assert_eq!(defaults.max_headers, 100);
+	assert_eq!(defaults.max_path_segments, 128);
+	assert_eq!(defaults.max_depth, None);
+
+	let mut config: ServerConfig = toml::from_str("[limits]\nmax_path_segments = 2").unwrap();
+	config.limits.max_depth = Some(1);
+	let server = Server::with_config(site.root.clone(), config);
+	assert_eq!(get(&server, "/a/b.txt").body, b"deep enough");
+	assert_eq!(get(&server, "/a/b/c.txt").status, 414);
+	let mut nested = request("GET", "/a/b.txt");
+	nested
+		.headers
+		.insert("WWebS-Depth".to_string(), "2".to_string());
+	assert_eq!(send(&server, &mut nested).status, 508);
+}